@@ -0,0 +1,119 @@
+//! Throughput of the hot paths of a download: framing peer messages, verifying pieces, and
+//! picking blocks. Run with `cargo bench`.
+
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use sha1::{Digest, Sha1};
+use torrant::{bitfield::Bitfield, peer::PeerMessage, picker::Picker};
+
+const BLOCK: usize = 16 * 1024;
+
+/// Encoding and decoding as `PeerCodec` does, which delegates to `PeerMessage`.
+fn codec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec");
+
+    let piece = PeerMessage::Piece(7, 0, vec![0xa5; BLOCK]);
+    let mut wire = BytesMut::new();
+    piece.clone().encode(&mut wire);
+    group.throughput(Throughput::Bytes(wire.len() as u64));
+    group.bench_function("encode piece", |b| {
+        let mut dst = BytesMut::with_capacity(wire.len());
+        b.iter(|| {
+            dst.clear();
+            black_box(piece.clone()).encode(&mut dst);
+        })
+    });
+    group.bench_function("decode piece", |b| {
+        b.iter_batched_ref(
+            || wire.clone(),
+            |src| PeerMessage::decode(src).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    // A burst of small control messages, as in a busy request pipeline.
+    let requests = (0..256)
+        .map(|i| PeerMessage::Request(i / 16, (i % 16) * BLOCK as u32, BLOCK as u32))
+        .collect::<Vec<_>>();
+    let mut wire = BytesMut::new();
+    requests
+        .iter()
+        .for_each(|request| request.clone().encode(&mut wire));
+    group.throughput(Throughput::Elements(requests.len() as u64));
+    group.bench_function("encode requests", |b| {
+        let mut dst = BytesMut::with_capacity(wire.len());
+        b.iter(|| {
+            dst.clear();
+            for request in &requests {
+                request.clone().encode(&mut dst);
+            }
+        })
+    });
+    group.bench_function("decode requests", |b| {
+        b.iter_batched_ref(
+            || wire.clone(),
+            |src| while PeerMessage::decode(src).unwrap().is_some() {},
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+/// Hashing a complete piece before it's written, at common piece sizes.
+fn verify(c: &mut Criterion) {
+    let mut group = c.benchmark_group("verify");
+
+    for piece_length in [256 * 1024, 1024 * 1024, 4 * 1024 * 1024] {
+        let data = vec![0x5a; piece_length];
+        let expected: [u8; 20] = Sha1::digest(&data).into();
+
+        group.throughput(Throughput::Bytes(piece_length as u64));
+        group.bench_function(format!("sha1 {} KiB", piece_length / 1024), |b| {
+            b.iter(|| <[u8; 20]>::from(Sha1::digest(black_box(&data))) == expected)
+        });
+    }
+
+    group.finish();
+}
+
+/// Picker decisions for a torrent with 4096 pieces of 16 blocks.
+fn picker(c: &mut Criterion) {
+    let mut group = c.benchmark_group("picker");
+    let piece_length = 16 * BLOCK;
+    let num_pieces = 4096;
+    let length = num_pieces * piece_length;
+    group.throughput(Throughput::Elements(1));
+
+    // A fresh download from a seed: every pick comes from the first piece with a missing block.
+    let available = Bitfield::full(num_pieces);
+    let verified = Bitfield::new(num_pieces);
+    group.bench_function("pick from seed", |b| {
+        b.iter_batched_ref(
+            || Picker::new(piece_length, length),
+            |picker| {
+                let block = picker.pick(&available, &verified).unwrap();
+                picker.received(block);
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    // Nearly done, from a peer with only the last piece: every pick scans past the rest.
+    let mut available = Bitfield::new(num_pieces);
+    available.set(num_pieces - 1);
+    let mut verified = Bitfield::full(num_pieces);
+    verified.clear(num_pieces - 1);
+    group.bench_function("pick last piece", |b| {
+        b.iter_batched_ref(
+            || Picker::new(piece_length, length),
+            |picker| picker.pick(&available, &verified).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, codec, verify, picker);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+//! Bencode from untrusted sources: `.torrent` files and tracker announce responses.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use torrant::{info::Torrent, tracker::TrackerResponse};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(torrent) = Torrent::from_bytes(data) {
+        torrent.trackers();
+        torrent.nodes();
+        torrent.info.name();
+        torrent.info.files();
+        let _ = torrent.info.calculate_info_hash();
+    }
+    let _ = TrackerResponse::from_bytes(data);
+});
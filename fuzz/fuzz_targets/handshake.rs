@@ -0,0 +1,17 @@
+//! Handshakes as `HandshakeCodec` sees them, including garbage from peers speaking something else.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use torrant::peer::Handshake;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    if let Ok(Some(handshake)) = Handshake::decode(&mut buf) {
+        let mut wire = BytesMut::new();
+        handshake.encode(&mut wire);
+        assert_eq!(wire[..], data[..Handshake::LEN]);
+        assert_eq!(buf[..], data[Handshake::LEN..]);
+    }
+});
@@ -0,0 +1,33 @@
+//! Peer messages arriving in arbitrary chunks, as `PeerCodec` sees them off a socket.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use torrant::peer::PeerMessage;
+
+fuzz_target!(|input: (Vec<u8>, Vec<u16>)| {
+    let (data, chunks) = input;
+
+    let mut buf = BytesMut::new();
+    let mut rest = &data[..];
+    for chunk in chunks.into_iter().map(usize::from).chain([usize::MAX]) {
+        let (chunk, tail) = rest.split_at(chunk.min(rest.len()));
+        rest = tail;
+        buf.extend_from_slice(chunk);
+
+        loop {
+            let before = buf.clone();
+            match PeerMessage::decode(&mut buf) {
+                Ok(Some(message)) => {
+                    // Whatever was accepted must encode back to the bytes it came from.
+                    let mut wire = BytesMut::new();
+                    message.encode(&mut wire);
+                    assert_eq!(wire[..], before[..wire.len()]);
+                }
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+    }
+});
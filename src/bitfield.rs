@@ -0,0 +1,84 @@
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Wraps a bitfield received from a peer, ignoring any spare bits past `len`.
+    /// A bitfield with every bit set.
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for index in 0..len {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        let n = bitfield.bytes.len().min(bytes.len());
+        bitfield.bytes[..n].copy_from_slice(&bytes[..n]);
+
+        if !len.is_multiple_of(8) {
+            if let Some(last) = bitfield.bytes.last_mut() {
+                *last &= 0xff << (8 - len % 8);
+            }
+        }
+
+        bitfield
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        if index >= self.len {
+            return false;
+        }
+
+        self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        assert!(
+            index < self.len,
+            "bit {index} out of range for {}",
+            self.len
+        );
+        self.bytes[index / 8] |= 0x80 >> (index % 8);
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        assert!(
+            index < self.len,
+            "bit {index} out of range for {}",
+            self.len
+        );
+        self.bytes[index / 8] &= !(0x80 >> (index % 8));
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
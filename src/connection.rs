@@ -0,0 +1,571 @@
+//! The peer wire protocol as a state machine that never touches sockets, disks, or clocks.
+//!
+//! [`PeerState`] consumes messages and timer ticks and answers with [`Action`]s for the caller to
+//! carry out, which keeps it usable from any event loop. [`run`] drives it on tokio.
+
+use std::time::{Duration, Instant};
+
+use rand::seq::index;
+
+use crate::{
+    bitfield::Bitfield,
+    peer::PeerMessage,
+    picker::{Block, Pipeline, PipelineStats, RequestTimeouts, MAX_REQUEST},
+};
+
+#[cfg(feature = "net")]
+pub use self::task::run;
+
+#[cfg(feature = "net")]
+mod task;
+
+/// Most pieces a lazy bitfield leaves out, to announce with `have` messages instead.
+const LAZY_WITHHELD: usize = 8;
+
+/// Pieces a peer may send without being asked before each further one counts as a
+/// [`Violation::UnrequestedPiece`].
+pub const MAX_UNSOLICITED: usize = 8;
+
+/// Something [`PeerState`] needs done.
+#[derive(Debug)]
+pub enum Action {
+    Send(PeerMessage),
+    /// Store a received block that was outstanding to this peer.
+    Store(Block, Vec<u8>),
+    /// Store a block the peer sent without being asked, if we still need it.
+    StoreUnsolicited(Block, Vec<u8>),
+    /// Read a block and send it to the peer as a piece, if it's one we have.
+    Serve(Block),
+    /// Return a block that won't arrive from this peer to the picker.
+    Release(Block),
+    /// Close the connection and refuse the peer for the given time, because it misbehaved too
+    /// often.
+    Ban(Duration),
+}
+
+/// A protocol rule the peer broke.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// A `have`, `request`, or `piece` for a piece the torrent doesn't have.
+    PieceOutOfRange,
+    /// A bitfield of the wrong length or with spare bits set.
+    InvalidBitfield,
+    /// A piece we never requested, or already gave up on, past the first [`MAX_UNSOLICITED`].
+    UnrequestedPiece,
+    /// A request while we're choking the peer.
+    RequestWhileChoked,
+    /// A request that's empty or longer than we serve.
+    InvalidRequestLength,
+}
+
+impl Violation {
+    /// How much the violation counts towards [`ViolationPolicy::threshold`].
+    ///
+    /// Anomalies that also happen to well-behaved peers because of races, like pieces arriving
+    /// after their request timed out, weigh little.
+    pub fn score(self) -> u32 {
+        match self {
+            Violation::PieceOutOfRange => 25,
+            Violation::InvalidBitfield => 50,
+            Violation::UnrequestedPiece => 1,
+            Violation::RequestWhileChoked => 5,
+            Violation::InvalidRequestLength => 10,
+        }
+    }
+}
+
+/// When a misbehaving peer gets disconnected and banned.
+#[derive(Debug, Clone, Copy)]
+pub struct ViolationPolicy {
+    /// Total [`Violation::score`] at which the peer is banned.
+    pub threshold: u32,
+    pub ban_duration: Duration,
+}
+
+impl Default for ViolationPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 100,
+            ban_duration: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Choke and interest state of a connection, in both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerFlags {
+    /// We're choking the peer.
+    pub am_choking: bool,
+    /// We told the peer we're interested.
+    pub am_interested: bool,
+    /// The peer is choking us.
+    pub peer_choking: bool,
+    /// The peer told us it's interested.
+    pub peer_interested: bool,
+    /// Both sides support the Fast extension.
+    pub fast: bool,
+}
+
+/// Protocol state of a single peer connection.
+#[derive(Debug)]
+pub struct PeerState {
+    pipeline: Pipeline,
+    timeouts: RequestTimeouts,
+    available: Bitfield,
+    /// Pieces the peer has been told we have.
+    announced: Bitfield,
+    /// Whether the peer is choking us.
+    choked: bool,
+    /// Whether we told the peer we're interested.
+    interested: bool,
+    /// Whether we're choking the peer.
+    choking: bool,
+    /// Whether the peer told us it's interested.
+    peer_interested: bool,
+    policy: ViolationPolicy,
+    score: u32,
+    /// Whether both sides support the Fast extension.
+    fast: bool,
+    lazy_bitfield: bool,
+    max_request: u32,
+    /// Pieces received that we never requested from the peer.
+    unsolicited: usize,
+}
+
+impl PeerState {
+    pub fn new(num_pieces: usize, max_depth: usize, timeouts: RequestTimeouts) -> Self {
+        Self {
+            pipeline: Pipeline::new(max_depth),
+            timeouts,
+            available: Bitfield::new(num_pieces),
+            announced: Bitfield::new(num_pieces),
+            choked: true,
+            interested: false,
+            choking: true,
+            peer_interested: false,
+            policy: ViolationPolicy::default(),
+            score: 0,
+            fast: false,
+            lazy_bitfield: false,
+            max_request: MAX_REQUEST as u32,
+            unsolicited: 0,
+        }
+    }
+
+    /// Enables the Fast extension's messages. Only pass `true` if both handshakes advertised it.
+    pub fn with_fast(mut self, fast: bool) -> Self {
+        self.fast = fast;
+        self
+    }
+
+    /// Leaves a few random pieces out of the initial bitfield and announces them with `have`
+    /// messages right after, so seeds don't look like seeds to traffic inspection.
+    pub fn with_lazy_bitfield(mut self, lazy: bool) -> Self {
+        self.lazy_bitfield = lazy;
+        self
+    }
+
+    /// Keeps the request pipeline at its maximum depth. See [`Pipeline::with_fixed_depth`].
+    pub fn with_fixed_depth(mut self, fixed: bool) -> Self {
+        let max_depth = self.pipeline.max_depth();
+        self.pipeline = Pipeline::new(max_depth).with_fixed_depth(fixed);
+        self
+    }
+
+    pub fn with_violation_policy(mut self, policy: ViolationPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Sets the longest request the peer may send, [`MAX_REQUEST`] by default.
+    pub fn with_max_request(mut self, length: usize) -> Self {
+        self.max_request = length.try_into().unwrap_or(u32::MAX);
+        self
+    }
+
+    /// Sum of the scores of the violations seen so far.
+    pub fn score(&self) -> u32 {
+        self.score
+    }
+
+    pub fn available(&self) -> &Bitfield {
+        &self.available
+    }
+
+    /// How many pieces the peer sent that we didn't request from it.
+    pub fn unsolicited(&self) -> usize {
+        self.unsolicited
+    }
+
+    pub fn pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+
+    pub fn pipeline_stats(&self) -> PipelineStats {
+        self.pipeline.stats(&self.timeouts)
+    }
+
+    /// Whether the peer is interested in our pieces.
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    pub fn is_choking(&self) -> bool {
+        self.choking
+    }
+
+    pub fn flags(&self) -> PeerFlags {
+        PeerFlags {
+            am_choking: self.choking,
+            am_interested: self.interested,
+            peer_choking: self.choked,
+            peer_interested: self.peer_interested,
+            fast: self.fast,
+        }
+    }
+
+    /// Lets the peer request blocks.
+    pub fn unchoke(&mut self) -> Vec<Action> {
+        if !self.choking {
+            return Vec::new();
+        }
+        self.choking = false;
+        vec![Action::Send(PeerMessage::Unchoke)]
+    }
+
+    /// Stops serving the peer's requests.
+    pub fn choke(&mut self) -> Vec<Action> {
+        if self.choking {
+            return Vec::new();
+        }
+        self.choking = true;
+        vec![Action::Send(PeerMessage::Choke)]
+    }
+
+    /// Messages to send right after the handshake.
+    pub fn start(&mut self, verified: &Bitfield) -> Vec<Action> {
+        self.announced = verified.clone();
+
+        let count = verified.count_ones();
+        if self.fast && count == 0 {
+            return vec![Action::Send(PeerMessage::HaveNone)];
+        }
+        if self.fast && verified.is_complete() && !self.lazy_bitfield {
+            return vec![Action::Send(PeerMessage::HaveAll)];
+        }
+        if count == 0 {
+            return Vec::new();
+        }
+
+        if !self.lazy_bitfield {
+            return vec![Action::Send(PeerMessage::Bitfield(
+                verified.as_bytes().to_vec(),
+            ))];
+        }
+
+        let have = (0..verified.len())
+            .filter(|&index| verified.get(index))
+            .collect::<Vec<_>>();
+        let mut rng = rand::thread_rng();
+        let withheld = index::sample(&mut rng, have.len(), LAZY_WITHHELD.min(have.len()))
+            .into_iter()
+            .map(|i| have[i])
+            .collect::<Vec<_>>();
+
+        let mut partial = verified.clone();
+        for &index in &withheld {
+            partial.clear(index);
+        }
+
+        // An empty bitfield may be left out entirely.
+        let mut actions = Vec::new();
+        if partial.count_ones() > 0 {
+            actions.push(Action::Send(PeerMessage::Bitfield(
+                partial.as_bytes().to_vec(),
+            )));
+        }
+        actions.extend(
+            withheld
+                .into_iter()
+                .map(|index| Action::Send(PeerMessage::Have(index as u32))),
+        );
+        actions
+    }
+
+    pub fn receive(&mut self, message: PeerMessage, now: Instant) -> Vec<Action> {
+        let num_pieces = self.available.len();
+
+        match message {
+            // With the Fast extension, a choke no longer drops our requests; the peer rejects the
+            // ones it won't answer.
+            PeerMessage::Choke if self.fast => {
+                self.choked = true;
+                Vec::new()
+            }
+            PeerMessage::Choke => {
+                self.choked = true;
+                self.pipeline
+                    .drain()
+                    .into_iter()
+                    .map(Action::Release)
+                    .collect()
+            }
+            PeerMessage::Unchoke => {
+                self.choked = false;
+                Vec::new()
+            }
+            PeerMessage::Interested => {
+                self.peer_interested = true;
+                Vec::new()
+            }
+            PeerMessage::NotInterested => {
+                self.peer_interested = false;
+                Vec::new()
+            }
+            PeerMessage::Have(index)
+            | PeerMessage::Request(index, ..)
+            | PeerMessage::Reject(index, ..)
+                if index as usize >= num_pieces =>
+            {
+                self.violation(Violation::PieceOutOfRange)
+            }
+            PeerMessage::Piece(index, ..) if index as usize >= num_pieces => {
+                self.violation(Violation::PieceOutOfRange)
+            }
+            PeerMessage::Have(index) => {
+                self.available.set(index as usize);
+                Vec::new()
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                let bitfield_ok = bitfield.len() == num_pieces.div_ceil(8)
+                    && Bitfield::from_bytes(&bitfield, num_pieces).as_bytes() == bitfield;
+                if !bitfield_ok {
+                    return self.violation(Violation::InvalidBitfield);
+                }
+                self.available = Bitfield::from_bytes(&bitfield, num_pieces);
+                Vec::new()
+            }
+            PeerMessage::HaveAll if self.fast => {
+                self.available = Bitfield::full(num_pieces);
+                Vec::new()
+            }
+            PeerMessage::HaveNone if self.fast => {
+                self.available = Bitfield::new(num_pieces);
+                Vec::new()
+            }
+            PeerMessage::Request(piece, begin, length) if self.choking => {
+                let mut actions = self.violation(Violation::RequestWhileChoked);
+                // Fast peers are told instead of being left waiting.
+                if self.fast && actions.is_empty() {
+                    actions.push(Action::Send(PeerMessage::Reject(piece, begin, length)));
+                }
+                actions
+            }
+            PeerMessage::Request(piece, begin, length)
+                if length == 0 || length > self.max_request =>
+            {
+                let mut actions = self.violation(Violation::InvalidRequestLength);
+                if self.fast && actions.is_empty() {
+                    actions.push(Action::Send(PeerMessage::Reject(piece, begin, length)));
+                }
+                actions
+            }
+            PeerMessage::Reject(piece, begin, length) if self.fast => {
+                let block = Block {
+                    piece,
+                    begin,
+                    length,
+                };
+                if self.pipeline.cancel(block) {
+                    vec![Action::Release(block)]
+                } else {
+                    Vec::new()
+                }
+            }
+            PeerMessage::Request(piece, begin, length) => {
+                vec![Action::Serve(Block {
+                    piece,
+                    begin,
+                    length,
+                })]
+            }
+            PeerMessage::Piece(piece, begin, data) => {
+                let block = Block {
+                    piece,
+                    begin,
+                    length: data.len() as u32,
+                };
+                if self.pipeline.complete(block, now) {
+                    return vec![Action::Store(block, data)];
+                }
+
+                // Some clients send data optimistically, and late answers to timed out requests
+                // may still be of use.
+                self.unsolicited += 1;
+                if self.unsolicited <= MAX_UNSOLICITED {
+                    vec![Action::StoreUnsolicited(block, data)]
+                } else {
+                    self.violation(Violation::UnrequestedPiece)
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Records a violation, returning a ban once the score reaches the policy's threshold.
+    fn violation(&mut self, violation: Violation) -> Vec<Action> {
+        self.score = self.score.saturating_add(violation.score());
+        if self.score < self.policy.threshold {
+            return Vec::new();
+        }
+
+        let mut actions = self.close();
+        actions.push(Action::Ban(self.policy.ban_duration));
+        actions
+    }
+
+    /// Times out requests the peer is taking too long to answer.
+    pub fn tick(&mut self, now: Instant) -> Vec<Action> {
+        self.pipeline
+            .expire(now, &self.timeouts)
+            .into_iter()
+            .flat_map(|block| {
+                [
+                    Action::Release(block),
+                    Action::Send(PeerMessage::Cancel(block.piece, block.begin, block.length)),
+                ]
+            })
+            .collect()
+    }
+
+    /// Tells the peer about pieces verified since it was last told.
+    pub fn verified(&mut self, verified: &Bitfield) -> Vec<Action> {
+        let actions = (0..verified.len())
+            .filter(|&index| verified.get(index) && !self.announced.get(index))
+            .map(|index| Action::Send(PeerMessage::Have(index as u32)))
+            .collect();
+        self.announced = verified.clone();
+        actions
+    }
+
+    /// Updates our interest and fills the request pipeline with blocks from `pick`.
+    pub fn request(
+        &mut self,
+        verified: &Bitfield,
+        mut pick: impl FnMut(&Bitfield) -> Option<Block>,
+        now: Instant,
+    ) -> Vec<Action> {
+        let mut actions = Vec::new();
+
+        let interesting = (0..self.available.len())
+            .any(|index| self.available.get(index) && !verified.get(index));
+        if interesting != self.interested {
+            self.interested = interesting;
+            actions.push(Action::Send(if interesting {
+                PeerMessage::Interested
+            } else {
+                PeerMessage::NotInterested
+            }));
+        }
+
+        if self.choked {
+            return actions;
+        }
+
+        while self.pipeline.has_room() {
+            let Some(block) = pick(&self.available) else {
+                break;
+            };
+
+            self.pipeline.push(block, now);
+            actions.push(Action::Send(PeerMessage::Request(
+                block.piece,
+                block.begin,
+                block.length,
+            )));
+        }
+
+        actions
+    }
+
+    /// Gives up every outstanding request, for when the connection closes.
+    pub fn close(&mut self) -> Vec<Action> {
+        self.pipeline
+            .drain()
+            .into_iter()
+            .map(Action::Release)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> PeerState {
+        PeerState::new(10, 16, RequestTimeouts::default())
+    }
+
+    #[test]
+    fn bans_once_violations_reach_threshold() {
+        let mut state = state();
+        let now = Instant::now();
+
+        // Each out of range `have` scores 25 of the default threshold of 100.
+        for _ in 0..3 {
+            assert!(state.receive(PeerMessage::Have(10), now).is_empty());
+        }
+        let actions = state.receive(PeerMessage::Have(10), now);
+        assert!(
+            matches!(actions[..], [Action::Ban(duration)] if duration == Duration::from_secs(3600))
+        );
+        assert_eq!(state.score(), 100);
+    }
+
+    #[test]
+    fn announces_have_all_with_fast() {
+        let actions = state().with_fast(true).start(&Bitfield::full(10));
+        assert!(matches!(actions[..], [Action::Send(PeerMessage::HaveAll)]));
+
+        let actions = state().start(&Bitfield::full(10));
+        assert!(matches!(
+            actions[..],
+            [Action::Send(PeerMessage::Bitfield(_))]
+        ));
+    }
+
+    #[test]
+    fn accepts_a_few_unsolicited_blocks() {
+        let mut state = state();
+        let now = Instant::now();
+        let block = Block {
+            piece: 0,
+            begin: 0,
+            length: 4,
+        };
+
+        state.receive(PeerMessage::Unchoke, now);
+        state.receive(PeerMessage::Have(0), now);
+        let mut picked = Some(block);
+        state.request(&Bitfield::new(10), |_| picked.take(), now);
+
+        // The request times out, so the block arriving afterwards is unsolicited.
+        let actions = state.tick(now + Duration::from_secs(61));
+        assert!(matches!(
+            actions[..],
+            [Action::Release(released), Action::Send(PeerMessage::Cancel(0, 0, 4))] if released == block
+        ));
+        for _ in 0..MAX_UNSOLICITED {
+            let actions = state.receive(PeerMessage::Piece(0, 0, vec![0; 4]), now);
+            assert!(
+                matches!(actions[..], [Action::StoreUnsolicited(stored, _)] if stored == block)
+            );
+        }
+
+        assert!(state
+            .receive(PeerMessage::Piece(0, 0, vec![0; 4]), now)
+            .is_empty());
+        assert_eq!(state.unsolicited(), MAX_UNSOLICITED + 1);
+        assert_eq!(state.score(), Violation::UnrequestedPiece.score());
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{future::OptionFuture, SinkExt, StreamExt};
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_util::codec::Framed;
+
+use crate::{
+    download::{ConnectionStatus, Download, Overhead, PeerAction},
+    error::{PeerError, Result},
+    peer::{Handshake, PeerCodec, PeerMessage},
+    slots::SlotPermit,
+};
+
+use super::{Action, PeerState};
+
+/// How long small messages may sit in the write buffer so they go out in one write.
+const FLUSH_DELAY: Duration = Duration::from_millis(5);
+
+/// Payload bytes exchanged over one connection.
+#[derive(Debug, Default)]
+struct Transferred {
+    downloaded: u64,
+    uploaded: u64,
+}
+
+fn status(state: &PeerState, transferred: &Transferred) -> ConnectionStatus {
+    ConnectionStatus {
+        flags: state.flags(),
+        pieces: state.available().count_ones(),
+        downloaded: transferred.downloaded,
+        uploaded: transferred.uploaded,
+        pipeline: state.pipeline_stats(),
+    }
+}
+
+/// Exchanges pieces with one peer until either side disconnects. `remote` is the handshake the
+/// peer sent.
+///
+/// Requests flow while the peer has pieces we lack. The peer is unchoked while it's interested in
+/// ours and holds one of the torrent's upload slots, unless [`Download::peer_action`] overrides
+/// that.
+pub async fn run(
+    download: Arc<Download>,
+    mut framed: Framed<TcpStream, PeerCodec>,
+    remote: Handshake,
+    addr: SocketAddr,
+) -> Result<()> {
+    download.set_peer_connected(addr, true);
+    download.record_overhead(Overhead {
+        uploaded: Handshake::LEN as u64,
+        downloaded: Handshake::LEN as u64,
+    });
+
+    // We always advertise the Fast extension, so it's in use whenever the peer supports it.
+    let pipeline = download.pipeline_config();
+    let mut state = PeerState::new(download.num_pieces(), pipeline.max_depth, pipeline.timeouts)
+        .with_fixed_depth(pipeline.fixed)
+        .with_violation_policy(download.violation_policy())
+        .with_fast(remote.supports_fast())
+        .with_lazy_bitfield(download.lazy_bitfield())
+        .with_max_request(download.max_request());
+    let mut transferred = Transferred::default();
+    let actions = download.connection_opened(addr, remote.peer_id(), status(&state, &transferred));
+    let result = drive(
+        &download,
+        &mut framed,
+        &mut state,
+        &mut transferred,
+        actions,
+        addr,
+    )
+    .await;
+    if result.is_ok() {
+        // Messages queued right before hanging up, like a last choke, still go out. The peer may
+        // have closed the connection already, so a failure is no error.
+        let _ = framed.flush().await;
+    }
+
+    for action in state.close() {
+        if let Action::Release(block) = action {
+            download.cancel_block(block);
+        }
+    }
+    download.connection_closed(addr);
+    download.set_peer_connected(addr, false);
+
+    result
+}
+
+async fn drive(
+    download: &Download,
+    framed: &mut Framed<TcpStream, PeerCodec>,
+    state: &mut PeerState,
+    transferred: &mut Transferred,
+    mut manual: mpsc::UnboundedReceiver<PeerAction>,
+    addr: SocketAddr,
+) -> Result<()> {
+    let mut verified = download.watch_verified();
+    let actions = state.start(&verified.borrow_and_update());
+    perform(download, framed, actions, transferred, addr).await?;
+
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let flush = tokio::time::sleep(FLUSH_DELAY);
+    tokio::pin!(flush);
+    let mut flush_pending = false;
+    let mut upload: Option<SlotPermit> = None;
+    // Kept across iterations while it's wanted, since dropping it wakes every other waiter.
+    let mut waiting: Option<Pin<Box<dyn Future<Output = SlotPermit> + Send + '_>>> = None;
+    // Whether the peer is choked (`true`) or unchoked regardless of upload slots.
+    let mut manual_choke: Option<bool> = None;
+
+    loop {
+        if manual_choke.is_none() && state.peer_interested() && state.is_choking() {
+            if waiting.is_none() {
+                waiting = Some(Box::pin(download.slots().upload()));
+            }
+        } else {
+            waiting = None;
+        }
+
+        if !flush_pending && !framed.write_buffer().is_empty() {
+            flush
+                .as_mut()
+                .reset(tokio::time::Instant::now() + FLUSH_DELAY);
+            flush_pending = true;
+        }
+
+        let actions = tokio::select! {
+            _ = &mut flush, if flush_pending => {
+                framed.flush().await.map_err(PeerError::from)?;
+                flush_pending = false;
+                continue;
+            }
+            _ = tick.tick() => {
+                download.update_connection(addr, status(state, transferred));
+                let mut actions = state.tick(Instant::now());
+                // Makes room for a torrent that hasn't got its share of the upload slots.
+                if upload.as_ref().is_some_and(SlotPermit::should_yield) {
+                    upload = None;
+                    actions.extend(state.choke());
+                }
+                actions
+            }
+            Some(permit) = OptionFuture::from(waiting.as_mut()), if waiting.is_some() => {
+                waiting = None;
+                upload = Some(permit);
+                state.unchoke()
+            }
+            Some(action) = manual.recv() => {
+                // Manual choking doesn't hold on to a slot.
+                upload = None;
+                match action {
+                    PeerAction::Choke => {
+                        manual_choke = Some(true);
+                        state.choke()
+                    }
+                    PeerAction::Unchoke => {
+                        manual_choke = Some(false);
+                        state.unchoke()
+                    }
+                    PeerAction::Automatic => {
+                        manual_choke = None;
+                        state.choke()
+                    }
+                    PeerAction::Disconnect => return Ok(()),
+                }
+            }
+            changed = verified.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+                let verified = verified.borrow_and_update().clone();
+                state.verified(&verified)
+            }
+            message = framed.next() => match message {
+                Some(message) => {
+                    let message = message.map_err(PeerError::from)?;
+                    download.record_overhead(Overhead {
+                        uploaded: 0,
+                        downloaded: overhead(&message),
+                    });
+                    state.receive(message, Instant::now())
+                }
+                None => return Ok(()),
+            },
+        };
+        perform(download, framed, actions, transferred, addr).await?;
+
+        if upload.is_some() && !state.peer_interested() {
+            upload = None;
+            perform(download, framed, state.choke(), transferred, addr).await?;
+        }
+
+        if download.is_seed_only() || download.is_paused() {
+            continue;
+        }
+        let actions = state.request(
+            &download.verified(),
+            |available| download.pick_block(available, addr.ip()),
+            Instant::now(),
+        );
+        perform(download, framed, actions, transferred, addr).await?;
+    }
+}
+
+async fn perform(
+    download: &Download,
+    framed: &mut Framed<TcpStream, PeerCodec>,
+    actions: Vec<Action>,
+    transferred: &mut Transferred,
+    addr: SocketAddr,
+) -> Result<()> {
+    for action in actions {
+        match action {
+            // Buffered until the flush timer fires, the buffer fills up, or a piece goes out.
+            Action::Send(message) => {
+                download.record_overhead(Overhead {
+                    uploaded: overhead(&message),
+                    downloaded: 0,
+                });
+                framed.feed(message).await.map_err(PeerError::from)?
+            }
+            Action::Store(block, data) => {
+                download.record_downloaded(data.len());
+                transferred.downloaded += data.len() as u64;
+                download.download_limiter().acquire(data.len()).await;
+                download.receive_block(block, &data, addr.ip()).await?;
+                // Receiving may have proven this peer sent bad data.
+                if download.is_banned(addr.ip()) {
+                    return Err(PeerError::Misbehaved.into());
+                }
+            }
+            Action::StoreUnsolicited(block, data) => {
+                download.record_downloaded(data.len());
+                transferred.downloaded += data.len() as u64;
+                download.download_limiter().acquire(data.len()).await;
+                download
+                    .receive_unsolicited(block, &data, addr.ip())
+                    .await?;
+                if download.is_banned(addr.ip()) {
+                    return Err(PeerError::Misbehaved.into());
+                }
+            }
+            Action::Serve(block) => {
+                if let Some(data) = download.read_block(block).await? {
+                    download.upload_limiter().acquire(data.len()).await;
+                    download.record_uploaded(data.len());
+                    transferred.uploaded += data.len() as u64;
+                    let message = PeerMessage::Piece(block.piece, block.begin, data);
+                    download.record_overhead(Overhead {
+                        uploaded: overhead(&message),
+                        downloaded: 0,
+                    });
+                    framed.send(message).await.map_err(PeerError::from)?;
+                }
+            }
+            Action::Release(block) => download.cancel_block(block),
+            Action::Ban(duration) => {
+                download.ban(addr.ip(), duration);
+                return Err(PeerError::Misbehaved.into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Bytes of `message` on the wire that aren't piece payload.
+fn overhead(message: &PeerMessage) -> u64 {
+    let payload = match message {
+        PeerMessage::Piece(_, _, block) => block.len(),
+        _ => 0,
+    };
+    (message.wire_len() - payload) as u64
+}
@@ -0,0 +1,1332 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    io, mem,
+    net::{IpAddr, SocketAddr},
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{broadcast, watch, Semaphore},
+};
+
+use crate::{
+    bitfield::Bitfield,
+    connection::ViolationPolicy,
+    error::{Error, Result, StorageError, UsageError},
+    info::{FileEntry, Info},
+    ip_filter::SharedIpFilter,
+    peer_list::{PeerCandidate, PeerList, PeerSource, MAX_PEERS},
+    picker::{Block, BlockCounts, Picker, PipelineConfig, MAX_REQUEST},
+    progress::HashProgress,
+    rate_limit::RateLimiter,
+    slots::{SlotPool, TorrentSlots},
+    storage::{FileStore, SyncPolicy},
+    tracker::TrackerResponse,
+};
+
+use self::discovery::DISCOVERED_CAPACITY;
+pub use self::{
+    peers::{ConnectionStatus, PeerAction, PeerInfo, Transport},
+    range::RangeReader,
+};
+
+mod discovery;
+mod peers;
+mod range;
+
+pub struct Download {
+    piece_length: usize,
+    length: usize,
+    hashes: Vec<[u8; 20]>,
+    files: Vec<FileEntry>,
+    /// Set by the `private` flag (BEP 27): peers only come from trackers and incoming connections.
+    private: bool,
+    store: FileStore,
+    verified: watch::Sender<Bitfield>,
+    state: watch::Sender<DownloadState>,
+    sync_policy: SyncPolicy,
+    check: CheckConfig,
+    durability: Mutex<Durability>,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    overhead_uploaded: AtomicU64,
+    overhead_downloaded: AtomicU64,
+    swarm: Mutex<Swarm>,
+    discovered: broadcast::Sender<PeerCandidate>,
+    connections: Mutex<HashMap<SocketAddr, peers::Connection>>,
+    /// `None` for seed-only downloads, which never request anything.
+    picker: Option<Mutex<Picker>>,
+    /// Blocks received so far for pieces that aren't complete yet.
+    partial: Mutex<HashMap<u32, PartialPiece>>,
+    download_limit: RateLimiter,
+    upload_limit: RateLimiter,
+    slots: TorrentSlots,
+    priority: Mutex<Priority>,
+    ip_filter: SharedIpFilter,
+    violation_policy: ViolationPolicy,
+    lazy_bitfield: bool,
+    max_request: usize,
+    pipeline: PipelineConfig,
+    /// Misbehaving peers and when their ban ends.
+    bans: Mutex<HashMap<IpAddr, Instant>>,
+    suspects: Mutex<Suspects>,
+}
+
+impl Download {
+    pub fn new(info: &Info, store: FileStore) -> Self {
+        let picker =
+            Picker::new(info.piece_length(), info.length()).with_locality(store.is_rotational());
+        Self::with_picker(info, store, Some(picker))
+    }
+
+    /// Creates a download that only uploads data already on disk.
+    ///
+    /// Peers are never sent `Interested` or requests, and no picker state is kept. Run
+    /// [`recheck`](Self::recheck) before serving so the existing pieces are known.
+    pub fn seed_only(info: &Info, store: FileStore) -> Self {
+        Self::with_picker(info, store, None)
+    }
+
+    fn with_picker(info: &Info, store: FileStore, picker: Option<Picker>) -> Self {
+        let hashes = (0..info.num_pieces())
+            .filter_map(|index| info.piece_hash(index))
+            .collect::<Vec<_>>();
+        let (verified, _) = watch::channel(Bitfield::new(hashes.len()));
+
+        Self {
+            piece_length: info.piece_length(),
+            length: info.length(),
+            hashes,
+            files: info.files(),
+            private: info.is_private(),
+            store,
+            verified,
+            state: watch::channel(DownloadState::Running).0,
+            sync_policy: SyncPolicy::default(),
+            check: CheckConfig::default(),
+            durability: Mutex::new(Durability {
+                synced: Bitfield::new(info.num_pieces()),
+                unsynced: Vec::new(),
+                last_sync: Instant::now(),
+            }),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            overhead_uploaded: AtomicU64::new(0),
+            overhead_downloaded: AtomicU64::new(0),
+            swarm: Mutex::new(Swarm {
+                trackers: HashMap::new(),
+                peers: PeerList::new().with_max_len(MAX_PEERS),
+            }),
+            discovered: broadcast::channel(DISCOVERED_CAPACITY).0,
+            connections: Mutex::default(),
+            picker: picker.map(Mutex::new),
+            partial: Mutex::default(),
+            download_limit: RateLimiter::default(),
+            upload_limit: RateLimiter::default(),
+            slots: TorrentSlots::default(),
+            priority: Mutex::default(),
+            ip_filter: SharedIpFilter::default(),
+            violation_policy: ViolationPolicy::default(),
+            lazy_bitfield: false,
+            max_request: MAX_REQUEST,
+            pipeline: PipelineConfig::default(),
+            bans: Mutex::default(),
+            suspects: Mutex::default(),
+        }
+    }
+
+    /// Makes the torrent's transfers also count against session-wide limits.
+    pub fn with_global_limits(mut self, download: &RateLimiter, upload: &RateLimiter) -> Self {
+        let weight = self.priority().weight();
+        self.download_limit = download.child();
+        self.download_limit.set_weight(weight);
+        self.upload_limit = upload.child();
+        self.upload_limit.set_weight(weight);
+        self
+    }
+
+    /// Takes connection and upload slots from `pool`, shared with the session's other torrents.
+    pub fn with_slots(mut self, pool: &SlotPool) -> Self {
+        self.slots = pool.register(self.priority().weight());
+        self
+    }
+
+    pub fn priority(&self) -> Priority {
+        *self.priority.lock().unwrap()
+    }
+
+    /// Weights the torrent's share of session-wide rate limits and slots, taking effect right
+    /// away.
+    pub fn set_priority(&self, priority: Priority) {
+        *self.priority.lock().unwrap() = priority;
+        let weight = priority.weight();
+        self.download_limit.set_weight(weight);
+        self.upload_limit.set_weight(weight);
+        self.slots.set_weight(weight);
+    }
+
+    /// Keeps peers in `filter` from being offered as connection candidates.
+    pub fn with_ip_filter(mut self, filter: SharedIpFilter) -> Self {
+        self.ip_filter = filter;
+        self
+    }
+
+    /// Sets when peers breaking the protocol are disconnected and banned.
+    pub fn with_violation_policy(mut self, policy: ViolationPolicy) -> Self {
+        self.violation_policy = policy;
+        self
+    }
+
+    pub fn violation_policy(&self) -> ViolationPolicy {
+        self.violation_policy
+    }
+
+    /// Sends peers lazy bitfields. See [`PeerState::with_lazy_bitfield`].
+    ///
+    /// [`PeerState::with_lazy_bitfield`]: crate::connection::PeerState::with_lazy_bitfield
+    pub fn with_lazy_bitfield(mut self, lazy: bool) -> Self {
+        self.lazy_bitfield = lazy;
+        self
+    }
+
+    pub fn lazy_bitfield(&self) -> bool {
+        self.lazy_bitfield
+    }
+
+    /// Requests blocks of `size` bytes. See [`Picker::with_block_size`].
+    pub fn with_block_size(mut self, size: usize) -> Self {
+        self.picker = self.picker.map(|picker| {
+            let picker = picker.into_inner().unwrap();
+            Mutex::new(picker.with_block_size(size))
+        });
+        self
+    }
+
+    /// Sets the longest request peers may send. See [`PeerState::with_max_request`].
+    ///
+    /// [`PeerState::with_max_request`]: crate::connection::PeerState::with_max_request
+    pub fn with_max_request(mut self, length: usize) -> Self {
+        self.max_request = length;
+        self
+    }
+
+    pub fn max_request(&self) -> usize {
+        self.max_request
+    }
+
+    /// Sizes the request pipelines of connections opened from now on.
+    pub fn with_pipeline_config(mut self, config: PipelineConfig) -> Self {
+        self.pipeline = config;
+        self
+    }
+
+    pub fn pipeline_config(&self) -> PipelineConfig {
+        self.pipeline
+    }
+
+    /// Keeps at most `max` known peers, [`MAX_PEERS`] by default. See [`PeerList::with_max_len`].
+    pub fn with_max_peers(self, max: usize) -> Self {
+        self.set_max_peers(Some(max));
+        self
+    }
+
+    /// Changes how many known peers are kept, forgetting unconnected ones over the new limit.
+    pub fn set_max_peers(&self, max: Option<usize>) {
+        self.swarm.lock().unwrap().peers.set_max_len(max);
+    }
+
+    /// Refuses connections to and from `ip` for `duration`.
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.bans
+            .lock()
+            .unwrap()
+            .insert(ip, Instant::now() + duration);
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        let now = Instant::now();
+        bans.retain(|_, until| *until > now);
+        bans.contains_key(&ip)
+    }
+
+    /// How many failed pieces `ip` contributed to without being proven innocent or guilty yet.
+    pub fn suspicion(&self, ip: IpAddr) -> u32 {
+        let suspects = self.suspects.lock().unwrap();
+        suspects.suspicion.get(&ip).copied().unwrap_or(0)
+    }
+
+    /// Caps this torrent's download rate in bytes per second, or lifts the cap with `None`.
+    pub fn set_download_limit(&self, rate: Option<u64>) {
+        self.download_limit.set_rate(rate);
+    }
+
+    /// Caps this torrent's upload rate in bytes per second, or lifts the cap with `None`.
+    pub fn set_upload_limit(&self, rate: Option<u64>) {
+        self.upload_limit.set_rate(rate);
+    }
+
+    /// Limiter peer connections must acquire from before reading payload.
+    pub fn download_limiter(&self) -> &RateLimiter {
+        &self.download_limit
+    }
+
+    /// Limiter peer connections must acquire from before sending payload.
+    pub fn upload_limiter(&self) -> &RateLimiter {
+        &self.upload_limit
+    }
+
+    /// Slots connections must hold to stay open and to unchoke their peer.
+    pub fn slots(&self) -> &TorrentSlots {
+        &self.slots
+    }
+
+    pub fn is_seed_only(&self) -> bool {
+        self.picker.is_none()
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Size of the piece at `index`, accounting for the truncated last piece.
+    pub fn piece_size(&self, index: usize) -> usize {
+        let start = index * self.piece_length;
+        self.piece_length.min(self.length.saturating_sub(start))
+    }
+
+    pub fn verified(&self) -> Bitfield {
+        self.verified.borrow().clone()
+    }
+
+    /// Counts payload bytes received from peers, whether or not they end up verifying.
+    pub fn record_downloaded(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts payload bytes sent to peers.
+    pub fn record_uploaded(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts bytes exchanged for the protocol rather than as piece payload: peer messages and
+    /// their framing, handshakes, and tracker traffic such as from [`TrackerTiers::take_traffic`].
+    ///
+    /// [`TrackerTiers::take_traffic`]: crate::tracker::TrackerTiers::take_traffic
+    pub fn record_overhead(&self, overhead: Overhead) {
+        self.overhead_uploaded
+            .fetch_add(overhead.uploaded, Ordering::Relaxed);
+        self.overhead_downloaded
+            .fetch_add(overhead.downloaded, Ordering::Relaxed);
+    }
+
+    /// Protocol bytes exchanged besides the payload counted by [`transfer`](Self::transfer), so
+    /// ratios can be reported either way.
+    pub fn overhead(&self) -> Overhead {
+        Overhead {
+            uploaded: self.overhead_uploaded.load(Ordering::Relaxed),
+            downloaded: self.overhead_downloaded.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Adds counters carried over from an earlier session, so totals survive restarts.
+    pub fn restore_transfer(&self, uploaded: u64, downloaded: u64) {
+        self.uploaded.fetch_add(uploaded, Ordering::Relaxed);
+        self.downloaded.fetch_add(downloaded, Ordering::Relaxed);
+    }
+
+    /// Bytes still needed to complete the download.
+    pub fn left(&self) -> u64 {
+        let verified = self.verified.borrow();
+        (0..self.num_pieces())
+            .filter(|&index| !verified.get(index))
+            .map(|index| self.piece_size(index) as u64)
+            .sum()
+    }
+
+    /// Counters to report in tracker announces.
+    pub fn transfer(&self) -> TransferStats {
+        TransferStats {
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left: self.left(),
+        }
+    }
+
+    /// Merges a tracker's view of the swarm into the torrent's swarm summary.
+    pub fn record_announce(&self, tracker: &str, response: &TrackerResponse) {
+        let mut swarm = self.swarm.lock().unwrap();
+        swarm.trackers.insert(
+            tracker.to_owned(),
+            (response.seeders(), response.leechers()),
+        );
+        let inserted = response
+            .peers()
+            .iter()
+            .map(|&peer| (peer, swarm.peers.insert(peer, PeerSource::Tracker)))
+            .collect::<Vec<_>>();
+        drop(swarm);
+
+        for (peer, insert) in inserted {
+            self.publish(peer, PeerSource::Tracker, insert);
+        }
+    }
+
+    /// Known peers we aren't connected to yet, one address per IP, minus blocked and banned ones.
+    pub fn candidates(&self) -> Vec<SocketAddr> {
+        let dialable = self
+            .swarm
+            .lock()
+            .unwrap()
+            .peers
+            .dialable()
+            .collect::<Vec<_>>();
+
+        dialable
+            .into_iter()
+            .filter(|&addr| self.is_dialable(addr))
+            .collect()
+    }
+
+    /// Whether the torrent is private (BEP 27), so peers from PEX, the DHT, or LSD are ignored.
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    /// Records a peer learned from any source, updating its port if it moved.
+    ///
+    /// Private torrents ignore peers from PEX, the DHT, and LSD, so clients that would leak the
+    /// swarm through them can't feed it peers either.
+    pub fn add_peer(&self, addr: SocketAddr, source: PeerSource) {
+        if self.private && matches!(source, PeerSource::Pex | PeerSource::Dht | PeerSource::Lsd) {
+            return;
+        }
+        let insert = self.swarm.lock().unwrap().peers.insert(addr, source);
+        self.publish(addr, source, insert);
+    }
+
+    /// Records a peer that connected in from `addr` through our local address `interface`.
+    ///
+    /// `addr` isn't where the peer listens, so it's never dialed and doesn't replace a port the
+    /// peer advertised.
+    pub fn add_incoming(&self, addr: SocketAddr, interface: SocketAddr) {
+        let mut swarm = self.swarm.lock().unwrap();
+        swarm.peers.insert(addr, PeerSource::Incoming);
+        swarm.peers.set_interface(addr, interface);
+    }
+
+    /// Tracks whether a connection to `addr` is open, so the peer isn't dialed twice.
+    pub fn set_peer_connected(&self, addr: SocketAddr, connected: bool) {
+        self.swarm
+            .lock()
+            .unwrap()
+            .peers
+            .set_connected(addr, connected);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let blocks = self.picker.as_ref().map(|picker| {
+            let verified = self.verified.borrow();
+            picker.lock().unwrap().block_counts(&verified)
+        });
+        let swarm = self.swarm.lock().unwrap();
+
+        Stats {
+            transfer: self.transfer(),
+            overhead: self.overhead(),
+            swarm: SwarmStats {
+                // Trackers see overlapping subsets of the same swarm, so summing would over-count.
+                seeders: swarm.trackers.values().filter_map(|&(s, _)| s).max(),
+                leechers: swarm.trackers.values().filter_map(|&(_, l)| l).max(),
+                peers: swarm.peers.len(),
+            },
+            blocks,
+        }
+    }
+
+    /// How much of each piece we have, from `0` to `1`, counting received blocks of pieces that
+    /// aren't verified yet. For drawing a piece bar with partial pieces.
+    pub fn piece_progress(&self) -> Vec<f64> {
+        let verified = self.verified.borrow();
+        let picker = self.picker.as_ref().map(|picker| picker.lock().unwrap());
+
+        (0..self.num_pieces())
+            .map(|index| match &picker {
+                _ if verified.get(index) => 1.0,
+                Some(picker) => picker.piece_progress(index as u32),
+                None => 0.0,
+            })
+            .collect()
+    }
+
+    /// Files in torrent order, along with how many of their bytes are verified on disk.
+    pub fn files(&self) -> Vec<FileProgress> {
+        let verified = self.verified.borrow();
+
+        self.files
+            .iter()
+            .map(|entry| {
+                let start = entry.offset();
+                let end = start + entry.length();
+
+                let downloaded = if entry.length() == 0 {
+                    0
+                } else {
+                    (start / self.piece_length..=(end - 1) / self.piece_length)
+                        .filter(|&index| verified.get(index))
+                        .map(|index| {
+                            let piece_start = index * self.piece_length;
+                            let piece_end = piece_start + self.piece_size(index);
+                            piece_end.min(end) - piece_start.max(start)
+                        })
+                        .sum()
+                };
+
+                FileProgress {
+                    entry: entry.clone(),
+                    downloaded,
+                }
+            })
+            .collect()
+    }
+
+    /// Subscribes to the verified pieces, e.g. to tell peers about new ones.
+    pub fn watch_verified(&self) -> watch::Receiver<Bitfield> {
+        self.verified.subscribe()
+    }
+
+    /// Sets when written pieces are flushed to the disk.
+    pub fn with_sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    /// Sets how data on disk is checked, see [`recheck`](Self::recheck).
+    pub fn with_check_config(mut self, check: CheckConfig) -> Self {
+        self.check = check;
+        self
+    }
+
+    pub fn check_config(&self) -> CheckConfig {
+        self.check
+    }
+
+    /// Flushes every written piece to the disk.
+    pub async fn sync(&self) -> Result<()> {
+        let pieces = mem::take(&mut self.durability.lock().unwrap().unsynced);
+
+        if let Err(e) = self.store.sync().await {
+            self.durability.lock().unwrap().unsynced.extend(pieces);
+            return Err(e.into());
+        }
+
+        let mut durability = self.durability.lock().unwrap();
+        for index in pieces {
+            durability.synced.set(index);
+        }
+        durability.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    /// Saves which pieces are complete to `path`, so a later [`load_progress`] can skip the
+    /// recheck.
+    ///
+    /// Pieces are synced first, and only those that made it to the disk are saved, so a crash
+    /// can't leave a file claiming pieces the disk never got.
+    ///
+    /// [`load_progress`]: Self::load_progress
+    pub async fn save_progress(&self, path: impl AsRef<Path>) -> Result<()> {
+        let bitfield = self.durable_progress().await?;
+
+        let path = path.as_ref();
+        let temporary = path.with_extension("tmp");
+        let write = async {
+            let mut file = tokio::fs::File::create(&temporary).await?;
+            file.write_all(bitfield.as_bytes()).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&temporary, path).await
+        };
+        write.await.map_err(|source| StorageError::Write {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        Ok(())
+    }
+
+    /// Marks the pieces saved by [`save_progress`](Self::save_progress) as verified without
+    /// hashing them.
+    ///
+    /// Returns the number of verified pieces.
+    pub async fn load_progress(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let path = path.as_ref();
+        let read_error = |source| StorageError::Read {
+            path: path.to_owned(),
+            source,
+        };
+
+        let bytes = tokio::fs::read(path).await.map_err(read_error)?;
+        let count = self.restore_progress(&bytes).map_err(read_error)?;
+        self.finalize_files(&self.verified()).await?;
+        Ok(count)
+    }
+
+    /// Syncs written pieces and returns the ones that made it to the disk, which is what
+    /// [`save_progress`](Self::save_progress) saves.
+    pub async fn durable_progress(&self) -> Result<Bitfield> {
+        self.sync().await?;
+        Ok(self.durability.lock().unwrap().synced.clone())
+    }
+
+    /// Marks the pieces of a bitfield from [`durable_progress`](Self::durable_progress) as
+    /// verified without hashing them.
+    ///
+    /// Returns the number of verified pieces, or fails if the bitfield is for another piece count.
+    pub fn restore_progress(&self, bitfield: &[u8]) -> io::Result<usize> {
+        if bitfield.len() != self.num_pieces().div_ceil(8) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bitfield length mismatch",
+            ));
+        }
+
+        let verified = Bitfield::from_bytes(bitfield, self.num_pieces());
+        let count = verified.count_ones();
+        self.durability.lock().unwrap().synced = verified.clone();
+        self.verified.send_replace(verified);
+
+        Ok(count)
+    }
+
+    pub fn state(&self) -> DownloadState {
+        self.state.borrow().clone()
+    }
+
+    pub fn watch_state(&self) -> watch::Receiver<DownloadState> {
+        self.state.subscribe()
+    }
+
+    /// Whether a storage failure paused the download. See [`DownloadState::Error`].
+    pub fn is_paused(&self) -> bool {
+        matches!(*self.state.borrow(), DownloadState::Error(_))
+    }
+
+    /// Resumes a download paused by a storage failure once every file can be opened again.
+    ///
+    /// Returns whether the download is running, with [`state`](Self::state) holding the new error
+    /// if it isn't.
+    pub async fn resume(&self) -> bool {
+        match self.store.check().await {
+            Ok(()) => {
+                self.state.send_replace(DownloadState::Running);
+                true
+            }
+            Err(e) => {
+                self.pause(e);
+                false
+            }
+        }
+    }
+
+    fn pause(&self, error: StorageError) {
+        self.state
+            .send_replace(DownloadState::Error(Arc::new(error)));
+    }
+
+    /// Hashes the data already on disk, marking every piece that matches as verified.
+    ///
+    /// Returns the number of verified pieces.
+    pub async fn recheck(&self) -> Result<usize> {
+        self.recheck_with_progress(|_| {}).await
+    }
+
+    /// Like [`recheck`](Self::recheck), calling `progress` after every piece.
+    pub async fn recheck_with_progress(
+        &self,
+        mut progress: impl FnMut(&HashProgress),
+    ) -> Result<usize> {
+        let start = Instant::now();
+        let mut bytes_hashed = 0;
+        let mut verified = Bitfield::new(self.num_pieces());
+        let hashing = Semaphore::new(self.check.threads.max(1));
+        // Reads run ahead of hashing, but results come out in piece order.
+        let mut checked = stream::iter(self.hashes.iter().enumerate())
+            .map(|(index, expected)| {
+                let hashing = &hashing;
+                async move {
+                    let offset = index * self.piece_length;
+                    let data = self.store.read(offset, self.piece_size(index)).await?;
+                    let _permit = hashing.acquire().await.expect("semaphore is never closed");
+                    let length = data.len();
+                    let digest = tokio::task::spawn_blocking(move || Sha1::digest(&data))
+                        .await
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+                    Ok::<_, Error>((index, digest.as_slice() == expected, length))
+                }
+            })
+            .buffered(self.check.read_ahead.max(1));
+
+        while let Some(result) = checked.next().await {
+            let (index, matches, length) = result?;
+            if matches {
+                verified.set(index);
+            }
+
+            bytes_hashed += length as u64;
+            let offset = index * self.piece_length;
+            let file = self
+                .files
+                .iter()
+                .find(|file| offset < file.offset() + file.length())
+                .map(|file| file.path().to_owned())
+                .unwrap_or_default();
+            progress(&HashProgress {
+                pieces_hashed: index + 1,
+                pieces: self.num_pieces(),
+                bytes_hashed,
+                bytes: self.length as u64,
+                file,
+                elapsed: start.elapsed(),
+            });
+        }
+
+        self.finalize_files(&verified).await?;
+        let count = verified.count_ones();
+        self.durability.lock().unwrap().synced = verified.clone();
+        self.verified.send_replace(verified);
+
+        Ok(count)
+    }
+
+    /// Renames files written under a part suffix whose pieces are all in `verified` to their real
+    /// names. See [`FileStore::create`].
+    pub(crate) async fn finalize_files(&self, verified: &Bitfield) -> Result<()> {
+        self.store
+            .finalize(|range| self.covers(verified, range))
+            .await?;
+        Ok(())
+    }
+
+    /// Whether `verified` has every piece overlapping `range` of the torrent's byte stream.
+    fn covers(&self, verified: &Bitfield, range: Range<usize>) -> bool {
+        (range.start / self.piece_length..range.end.div_ceil(self.piece_length))
+            .all(|index| verified.get(index))
+    }
+
+    /// Where the file starting at `offset` is on disk, if all of its pieces are verified.
+    #[cfg(feature = "net")]
+    pub(crate) fn complete_file(&self, offset: usize, length: usize) -> Option<std::path::PathBuf> {
+        if !self.covers(&self.verified.borrow(), offset..offset + length) {
+            return None;
+        }
+        self.store.file_path(offset)
+    }
+
+    #[cfg(feature = "net")]
+    pub(crate) fn store(&self) -> &FileStore {
+        &self.store
+    }
+
+    /// Downloads the pieces overlapping the file at `index` again, e.g. after it was corrupted
+    /// outside of this crate, without rechecking the whole torrent.
+    ///
+    /// The file is zeroed on disk and its pieces stop counting as verified. Bytes of neighbouring
+    /// files sharing those pieces are left alone. Returns how many pieces there were.
+    pub async fn redownload_file(&self, index: usize) -> Result<usize> {
+        let Some(picker) = &self.picker else {
+            return Err(UsageError::SeedOnly.into());
+        };
+        let Some(file) = self.files.get(index) else {
+            return Err(UsageError::FileOutOfRange(index).into());
+        };
+        if file.length() == 0 {
+            return Ok(0);
+        }
+
+        let first = file.offset() / self.piece_length;
+        let last = (file.offset() + file.length() - 1) / self.piece_length;
+
+        self.verified.send_modify(|verified| {
+            for piece in first..=last {
+                verified.clear(piece);
+            }
+        });
+        {
+            let mut durability = self.durability.lock().unwrap();
+            durability
+                .unsynced
+                .retain(|piece| !(first..=last).contains(piece));
+            for piece in first..=last {
+                durability.synced.clear(piece);
+            }
+        }
+
+        let end = file.offset() + file.length();
+        let zeroes = vec![0; self.piece_length.min(file.length())];
+        let mut offset = file.offset();
+        while offset < end {
+            let length = zeroes.len().min(end - offset);
+            self.store.write(offset, &zeroes[..length]).await?;
+            offset += length;
+        }
+
+        // Only now, so blocks requested while the zeroes were being written are dropped instead of
+        // completing a piece the zeroes could land on top of.
+        {
+            let mut picker = picker.lock().unwrap();
+            let mut partial = self.partial.lock().unwrap();
+            for piece in first..=last {
+                picker.reset_piece(piece as u32);
+                partial.remove(&(piece as u32));
+            }
+        }
+
+        Ok(last - first + 1)
+    }
+
+    /// Picks the next block to request from `peer`, which has `available`.
+    ///
+    /// Pieces that failed their hash check with data from `peer` are only picked once nothing
+    /// else is left, so they're downloaded again from someone else where possible.
+    pub fn pick_block(&self, available: &Bitfield, peer: IpAddr) -> Option<Block> {
+        let mut preferred = available.clone();
+        for (&piece, failed) in &self.suspects.lock().unwrap().failed {
+            if failed.contributors.iter().any(|(_, ip)| *ip == peer) {
+                preferred.clear(piece as usize);
+            }
+        }
+
+        let verified = self.verified.borrow();
+        let mut picker = self.picker.as_ref()?.lock().unwrap();
+        picker
+            .pick(&preferred, &verified)
+            .or_else(|| picker.pick(available, &verified))
+    }
+
+    /// Returns a block that won't be received to the pool, e.g. after a timeout or disconnect.
+    pub fn cancel_block(&self, block: Block) {
+        if let Some(picker) = &self.picker {
+            picker.lock().unwrap().cancel(block);
+        }
+    }
+
+    /// Stores a block received from `peer`, verifying and writing its piece once every block has
+    /// arrived.
+    ///
+    /// If the piece fails its hash check, a sole contributor is banned right away. Otherwise every
+    /// contributor becomes a suspect until the piece verifies, at which point the peers whose
+    /// blocks differ from the good data are banned.
+    pub async fn receive_block(
+        &self,
+        block: Block,
+        data: &[u8],
+        peer: IpAddr,
+    ) -> Result<BlockOutcome> {
+        if data.len() != block.length as usize {
+            return Ok(BlockOutcome::Unrequested);
+        }
+
+        let Some(picker) = &self.picker else {
+            return Ok(BlockOutcome::Unrequested);
+        };
+        if self.is_paused() {
+            picker.lock().unwrap().cancel(block);
+            return Ok(BlockOutcome::Paused);
+        }
+        let Some(piece_done) = picker.lock().unwrap().received(block) else {
+            return Ok(BlockOutcome::Unrequested);
+        };
+
+        self.add_block(block, data, peer, piece_done).await
+    }
+
+    /// Stores a block `peer` sent without us requesting it, if it fits the torrent's block
+    /// layout and its piece still needs it. Otherwise it's dropped as
+    /// [`Unrequested`](BlockOutcome::Unrequested).
+    pub async fn receive_unsolicited(
+        &self,
+        block: Block,
+        data: &[u8],
+        peer: IpAddr,
+    ) -> Result<BlockOutcome> {
+        if data.len() != block.length as usize {
+            return Ok(BlockOutcome::Unrequested);
+        }
+
+        let Some(picker) = &self.picker else {
+            return Ok(BlockOutcome::Unrequested);
+        };
+        if self.is_paused() {
+            return Ok(BlockOutcome::Paused);
+        }
+        let claimed = picker.lock().unwrap().claim(block, &self.verified.borrow());
+        let Some(piece_done) = claimed else {
+            return Ok(BlockOutcome::Unrequested);
+        };
+
+        self.add_block(block, data, peer, piece_done).await
+    }
+
+    /// Adds a block the picker accepted to its piece, finishing the piece if it was the last.
+    async fn add_block(
+        &self,
+        block: Block,
+        data: &[u8],
+        peer: IpAddr,
+        piece_done: bool,
+    ) -> Result<BlockOutcome> {
+        let Some(picker) = &self.picker else {
+            return Ok(BlockOutcome::Unrequested);
+        };
+        let piece = {
+            let mut partial = self.partial.lock().unwrap();
+            let added = partial
+                .entry(block.piece)
+                .or_insert_with(|| PartialPiece::new(self.piece_size(block.piece as usize)))
+                .add(block.begin as usize, data, peer);
+            // The picker only accepts blocks inside their piece, so this would be a bug, but a
+            // piece missing a block could never verify, so start it over.
+            if !added {
+                partial.remove(&block.piece);
+                drop(partial);
+                picker.lock().unwrap().reset_piece(block.piece);
+                return Ok(BlockOutcome::Unrequested);
+            }
+
+            if !piece_done {
+                return Ok(BlockOutcome::Stored);
+            }
+            partial.remove(&block.piece)
+        };
+        let Some(mut piece) = piece else {
+            return Ok(BlockOutcome::Unrequested);
+        };
+
+        picker.lock().unwrap().reset_piece(block.piece);
+
+        let contributors = mem::take(&mut piece.contributors);
+        let (data, digest) = piece.finish();
+        match self.store_piece(block.piece as usize, &data, digest).await {
+            Ok(true) => {
+                picker.lock().unwrap().written(block.piece);
+                self.convict(block.piece, &data);
+                Ok(BlockOutcome::Verified)
+            }
+            Ok(false) => {
+                self.suspect(block.piece, data, contributors);
+                Ok(BlockOutcome::Failed)
+            }
+            Err(Error::Storage(e)) => {
+                self.pause(e);
+                Ok(BlockOutcome::Paused)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records who contributed to a piece that failed its hash check.
+    fn suspect(&self, piece: u32, data: Vec<u8>, contributors: Vec<(Range<usize>, IpAddr)>) {
+        let mut peers = contributors.iter().map(|(_, ip)| *ip).collect::<Vec<_>>();
+        peers.sort();
+        peers.dedup();
+
+        if let [peer] = peers[..] {
+            self.ban(peer, self.violation_policy.ban_duration);
+            return;
+        }
+
+        let mut suspects = self.suspects.lock().unwrap();
+        for peer in peers {
+            *suspects.suspicion.entry(peer).or_default() += 1;
+        }
+        suspects
+            .failed
+            .insert(piece, FailedPiece { data, contributors });
+    }
+
+    /// Bans the peers whose blocks of an earlier failed attempt at `piece` differ from the now
+    /// verified `data`, and clears the others.
+    fn convict(&self, piece: u32, data: &[u8]) {
+        let mut guilty = Vec::new();
+        {
+            let mut suspects = self.suspects.lock().unwrap();
+            let Some(failed) = suspects.failed.remove(&piece) else {
+                return;
+            };
+
+            let mut peers = Vec::new();
+            for (range, peer) in failed.contributors {
+                if failed.data[range.clone()] != data[range] {
+                    guilty.push(peer);
+                }
+                peers.push(peer);
+            }
+            peers.sort();
+            peers.dedup();
+            for peer in peers {
+                if let Some(count) = suspects.suspicion.get_mut(&peer) {
+                    *count -= 1;
+                    if *count == 0 {
+                        suspects.suspicion.remove(&peer);
+                    }
+                }
+            }
+        }
+
+        for peer in guilty {
+            self.ban(peer, self.violation_policy.ban_duration);
+        }
+    }
+
+    /// Reads a block of a verified piece to upload. Returns `None` for blocks we can't serve,
+    /// and while paused.
+    pub async fn read_block(&self, block: Block) -> Result<Option<Vec<u8>>> {
+        let index = block.piece as usize;
+        let end = block.begin as usize + block.length as usize;
+        if self.is_paused()
+            || index >= self.num_pieces()
+            || end > self.piece_size(index)
+            || !self.verified.borrow().get(index)
+        {
+            return Ok(None);
+        }
+
+        let offset = index * self.piece_length + block.begin as usize;
+        match self.store.read(offset, block.length as usize).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) => {
+                self.pause(e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Checks a fully assembled piece against its hash and writes it to disk.
+    ///
+    /// Returns `false` if the data didn't match, in which case nothing is written.
+    pub async fn complete_piece(&self, index: usize, data: &[u8]) -> Result<bool> {
+        self.store_piece(index, data, None).await
+    }
+
+    /// Like [`complete_piece`](Self::complete_piece), but trusts `digest` if it was already
+    /// computed while the piece came in.
+    async fn store_piece(
+        &self,
+        index: usize,
+        data: &[u8],
+        digest: Option<[u8; 20]>,
+    ) -> Result<bool> {
+        let Some(expected) = self.hashes.get(index) else {
+            return Err(UsageError::PieceOutOfRange(index).into());
+        };
+
+        if data.len() != self.piece_size(index) {
+            return Ok(false);
+        }
+
+        let digest = digest.unwrap_or_else(|| Sha1::digest(data).into());
+        if &digest != expected {
+            return Ok(false);
+        }
+
+        self.store.write(index * self.piece_length, data).await?;
+        // Files are renamed into place before anyone is told the piece completed them.
+        let mut verified = self.verified();
+        verified.set(index);
+        self.finalize_files(&verified).await?;
+        self.verified.send_modify(|verified| verified.set(index));
+
+        let due = {
+            let mut durability = self.durability.lock().unwrap();
+            durability.unsynced.push(index);
+            match self.sync_policy {
+                SyncPolicy::Never => false,
+                SyncPolicy::OnPiece => true,
+                SyncPolicy::Interval(interval) => durability.last_sync.elapsed() >= interval,
+            }
+        };
+        if due {
+            self.sync().await?;
+        }
+
+        Ok(true)
+    }
+
+    /// Resolves once the piece at `index` has been verified and written to disk.
+    pub fn wait_piece(&self, index: usize) -> impl Future<Output = Result<()>> {
+        let in_range = index < self.num_pieces();
+        let waiter = self.wait_until(move |verified| verified.get(index));
+
+        async move {
+            if !in_range {
+                return Err(UsageError::PieceOutOfRange(index).into());
+            }
+            waiter.await
+        }
+    }
+
+    /// Resolves once every piece has been verified and written to disk.
+    pub fn wait_complete(&self) -> impl Future<Output = Result<()>> {
+        self.wait_until(Bitfield::is_complete)
+    }
+
+    fn wait_until(&self, done: impl Fn(&Bitfield) -> bool) -> impl Future<Output = Result<()>> {
+        let mut rx = self.verified.subscribe();
+
+        async move {
+            loop {
+                if done(&rx.borrow_and_update()) {
+                    return Ok(());
+                }
+
+                if rx.changed().await.is_err() {
+                    return Err(UsageError::Dropped.into());
+                }
+            }
+        }
+    }
+}
+
+/// Which verified pieces are known to be on the disk rather than only in the OS cache.
+#[derive(Debug)]
+struct Durability {
+    synced: Bitfield,
+    /// Pieces written since the last sync.
+    unsynced: Vec<usize>,
+    last_sync: Instant,
+}
+
+/// Who contributed to pieces that failed their hash check.
+#[derive(Debug, Default)]
+struct Suspects {
+    /// Failed pieces being downloaded again, by index.
+    failed: HashMap<u32, FailedPiece>,
+    /// Failed pieces each peer contributed to.
+    suspicion: HashMap<IpAddr, u32>,
+}
+
+#[derive(Debug)]
+struct FailedPiece {
+    data: Vec<u8>,
+    contributors: Vec<(Range<usize>, IpAddr)>,
+}
+
+/// A piece being assembled from blocks, hashed as soon as its data is contiguous so completing
+/// it doesn't mean hashing the whole piece at once.
+#[derive(Debug)]
+struct PartialPiece {
+    data: Vec<u8>,
+    hasher: Sha1,
+    /// Bytes from the start fed to `hasher`.
+    hashed: usize,
+    /// Received ranges past `hashed`, by start.
+    pending: BTreeMap<usize, usize>,
+    /// Who sent each block.
+    contributors: Vec<(Range<usize>, IpAddr)>,
+}
+
+impl PartialPiece {
+    fn new(length: usize) -> Self {
+        Self {
+            data: vec![0; length],
+            hasher: Sha1::new(),
+            hashed: 0,
+            pending: BTreeMap::new(),
+            contributors: Vec::new(),
+        }
+    }
+
+    /// Copies a block into the piece, unless it would reach past its end or into bytes already
+    /// received.
+    fn add(&mut self, begin: usize, data: &[u8], peer: IpAddr) -> bool {
+        let Some(end) = begin
+            .checked_add(data.len())
+            .filter(|&end| end <= self.data.len())
+        else {
+            return false;
+        };
+        let overlaps = self
+            .contributors
+            .iter()
+            .any(|(range, _)| range.start < end && begin < range.end);
+        if overlaps {
+            return false;
+        }
+
+        self.data[begin..end].copy_from_slice(data);
+        self.contributors.push((begin..end, peer));
+        if begin >= self.hashed {
+            self.pending.insert(begin, end);
+        }
+
+        while let Some(end) = self.pending.remove(&self.hashed) {
+            self.hasher.update(&self.data[self.hashed..end]);
+            self.hashed = end;
+        }
+
+        true
+    }
+
+    /// The piece's data, and its hash if every byte made it into the hasher.
+    fn finish(self) -> (Vec<u8>, Option<[u8; 20]>) {
+        let digest = (self.hashed == self.data.len()).then(|| self.hasher.finalize().into());
+        (self.data, digest)
+    }
+}
+
+/// How data already on disk is checked when a torrent is added or rechecked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckConfig {
+    /// Pieces hashed at once, each on a blocking thread.
+    pub threads: usize,
+    /// Pieces read from disk ahead of the one being reported, including those being hashed.
+    /// Bounds the checker's memory to this many pieces and should be at least `threads`.
+    pub read_ahead: usize,
+}
+
+impl Default for CheckConfig {
+    /// Half the available cores, leaving the rest and some disk bandwidth to running downloads,
+    /// and two pieces read ahead per thread.
+    fn default() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let threads = (cores / 2).max(1);
+        Self {
+            threads,
+            read_ahead: threads * 2,
+        }
+    }
+}
+
+/// What became of a block handed to [`Download::receive_block`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// The block wasn't outstanding, so it was dropped.
+    Unrequested,
+    /// The block was stored and its piece still misses others.
+    Stored,
+    /// The block completed its piece, which verified and was written to disk.
+    Verified,
+    /// The block completed its piece, which failed verification and will be downloaded again.
+    Failed,
+    /// The download is paused by a storage failure, so the block or its piece was dropped.
+    Paused,
+}
+
+/// How much of the session's bandwidth and slots a torrent gets next to the others.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// The torrent's weight when rates and slots are split between torrents.
+    pub fn weight(self) -> u32 {
+        match self {
+            Priority::Low => 1,
+            Priority::Normal => 2,
+            Priority::High => 4,
+        }
+    }
+}
+
+/// Whether a download is transferring data.
+#[derive(Debug, Clone)]
+pub enum DownloadState {
+    Running,
+    /// Paused after reading or writing its files failed, e.g. with [`StorageError::DiskFull`] once
+    /// the disk fills up. Peers stay connected, but nothing is requested, stored, or served until
+    /// [`Download::resume`] succeeds.
+    Error(Arc<StorageError>),
+}
+
+#[derive(Debug)]
+struct Swarm {
+    trackers: HashMap<String, (Option<u64>, Option<u64>)>,
+    peers: PeerList,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub transfer: TransferStats,
+    pub overhead: Overhead,
+    pub swarm: SwarmStats,
+    /// `None` for seed-only downloads, which have no blocks to fetch.
+    pub blocks: Option<BlockCounts>,
+}
+
+/// Swarm size as reported by trackers. Counts are `None` until a tracker reports them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SwarmStats {
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    /// Distinct peer IPs learned from all sources.
+    pub peers: usize,
+}
+
+/// Transfer counters in the form trackers expect them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+/// Bytes exchanged for the protocol itself rather than piece payload, see
+/// [`Download::overhead`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Overhead {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileProgress {
+    entry: FileEntry,
+    downloaded: usize,
+}
+
+impl FileProgress {
+    pub fn path(&self) -> &Path {
+        self.entry.path()
+    }
+
+    pub fn length(&self) -> usize {
+        self.entry.length()
+    }
+
+    /// Byte offset of the file within the torrent's concatenated data.
+    pub fn offset(&self) -> usize {
+        self.entry.offset()
+    }
+
+    /// Number of the file's bytes that belong to verified pieces.
+    pub fn downloaded(&self) -> usize {
+        self.downloaded
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.downloaded == self.length()
+    }
+}
@@ -0,0 +1,64 @@
+use std::net::SocketAddr;
+
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::peer_list::{Insert, PeerCandidate, PeerSource};
+
+use super::Download;
+
+/// Discoveries buffered per [`Download::discover`] stream before a slow consumer misses some.
+pub(super) const DISCOVERED_CAPACITY: usize = 1024;
+
+impl Download {
+    /// Peers worth dialing, as they're discovered.
+    ///
+    /// Starts with every known peer we aren't connected to, then yields each peer reported through
+    /// [`record_announce`](Self::record_announce) or [`add_peer`](Self::add_peer) that is new or
+    /// moved to another port, tagged with its source, so re-announces and any DHT, PEX or LSD
+    /// feeding the download are merged into one stream. Blocked and banned peers are left out.
+    ///
+    /// A consumer that falls more than a thousand peers behind skips the oldest ones; they're
+    /// still in [`candidates`](Self::candidates). The stream ends when the download is dropped.
+    pub fn discover(&self) -> impl Stream<Item = PeerCandidate> + Send + 'static {
+        let receiver = self.discovered.subscribe();
+        let mut known = self
+            .swarm
+            .lock()
+            .unwrap()
+            .peers
+            .iter()
+            .filter(|candidate| !candidate.is_connected() && candidate.is_listening())
+            .map(|candidate| PeerCandidate {
+                addr: candidate.addr(),
+                source: candidate.sources()[0],
+            })
+            .collect::<Vec<_>>();
+        known.retain(|candidate| self.is_dialable(candidate.addr));
+
+        stream::iter(known).chain(stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(candidate) => return Some((candidate, receiver)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+
+    /// Tells [`discover`](Self::discover) streams about a peer just added to the peer list.
+    pub(super) fn publish(&self, addr: SocketAddr, source: PeerSource, insert: Insert) {
+        let dialable = matches!(insert, Insert::New | Insert::PortChanged(_))
+            && source != PeerSource::Incoming
+            && self.is_dialable(addr);
+        if dialable {
+            // Fails only when nobody is listening.
+            let _ = self.discovered.send(PeerCandidate { addr, source });
+        }
+    }
+
+    pub(super) fn is_dialable(&self, addr: SocketAddr) -> bool {
+        !self.ip_filter.load().is_blocked(addr.ip()) && !self.is_banned(addr.ip())
+    }
+}
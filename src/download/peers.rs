@@ -0,0 +1,183 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{connection::PeerFlags, peer_id::PeerId, peer_list::PeerSource, picker::PipelineStats};
+
+use super::Download;
+
+/// A connected peer at one moment, see [`Download::peers`].
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub peer_id: PeerId,
+    /// Client name and version from the peer id, if it has the usual format.
+    pub client: Option<String>,
+    pub flags: PeerFlags,
+    /// `Some(true)` while the peer is choked by [`PeerAction::Choke`], `Some(false)` while it's
+    /// unchoked by [`PeerAction::Unchoke`], and `None` while the automatic choker decides.
+    pub manual_choke: Option<bool>,
+    pub transport: Transport,
+    /// Fraction of the torrent the peer has, from 0 to 1.
+    pub progress: f64,
+    /// Payload bytes per second received from the peer, averaged over the last update interval.
+    pub download_rate: u64,
+    /// Payload bytes per second sent to the peer, averaged over the last update interval.
+    pub upload_rate: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// How we learned about the peer.
+    pub sources: Vec<PeerSource>,
+    pub connected_for: Duration,
+    /// Our requests to the peer, as of the last update.
+    pub pipeline: PipelineStats,
+}
+
+/// An override of what a connection would do on its own, see [`Download::peer_action`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAction {
+    /// Chokes the peer until [`Automatic`](Self::Automatic), whatever upload slots are free.
+    Choke,
+    /// Unchokes the peer until [`Automatic`](Self::Automatic), without taking an upload slot.
+    Unchoke,
+    /// Hands the peer back to the automatic choker.
+    Automatic,
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+}
+
+/// What a connection reports about itself, see [`Download::update_connection`].
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionStatus {
+    pub flags: PeerFlags,
+    /// Pieces the peer has.
+    pub pieces: usize,
+    /// Payload bytes received since the connection opened.
+    pub downloaded: u64,
+    /// Payload bytes sent since the connection opened.
+    pub uploaded: u64,
+    pub pipeline: PipelineStats,
+}
+
+#[derive(Debug)]
+pub(super) struct Connection {
+    peer_id: PeerId,
+    since: Instant,
+    status: ConnectionStatus,
+    updated: Instant,
+    download_rate: u64,
+    upload_rate: u64,
+    manual_choke: Option<bool>,
+    actions: mpsc::UnboundedSender<PeerAction>,
+}
+
+impl Download {
+    /// Snapshots every open connection, e.g. for a client's peer list.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let swarm = self.swarm.lock().unwrap();
+        let connections = self.connections.lock().unwrap();
+
+        connections
+            .iter()
+            .map(|(&addr, connection)| {
+                let status = connection.status;
+                PeerInfo {
+                    addr,
+                    peer_id: connection.peer_id,
+                    client: connection.peer_id.client(),
+                    flags: status.flags,
+                    manual_choke: connection.manual_choke,
+                    transport: Transport::Tcp,
+                    progress: status.pieces as f64 / self.num_pieces().max(1) as f64,
+                    download_rate: connection.download_rate,
+                    upload_rate: connection.upload_rate,
+                    downloaded: status.downloaded,
+                    uploaded: status.uploaded,
+                    sources: swarm
+                        .peers
+                        .get(addr.ip())
+                        .map(|candidate| candidate.sources().to_vec())
+                        .unwrap_or_default(),
+                    connected_for: connection.since.elapsed(),
+                    pipeline: status.pipeline,
+                }
+            })
+            .collect()
+    }
+
+    /// Records a connection to `addr` that completed its handshake. The connection carries out
+    /// the [`PeerAction`]s it receives.
+    pub fn connection_opened(
+        &self,
+        addr: SocketAddr,
+        peer_id: PeerId,
+        status: ConnectionStatus,
+    ) -> mpsc::UnboundedReceiver<PeerAction> {
+        let now = Instant::now();
+        let (actions, receiver) = mpsc::unbounded_channel();
+        self.connections.lock().unwrap().insert(
+            addr,
+            Connection {
+                peer_id,
+                since: now,
+                status,
+                updated: now,
+                download_rate: 0,
+                upload_rate: 0,
+                manual_choke: None,
+                actions,
+            },
+        );
+
+        receiver
+    }
+
+    /// Overrides the automatic choker for the connected peer at `addr`, or disconnects it, e.g. to
+    /// police a leecher. Returns whether there was such a connection.
+    ///
+    /// Disconnecting doesn't keep the peer from connecting again, unlike a [`ban`](Self::ban).
+    pub fn peer_action(&self, addr: SocketAddr, action: PeerAction) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let Some(connection) = connections.get_mut(&addr) else {
+            return false;
+        };
+
+        connection.manual_choke = match action {
+            PeerAction::Choke => Some(true),
+            PeerAction::Unchoke => Some(false),
+            PeerAction::Automatic | PeerAction::Disconnect => None,
+        };
+        connection.actions.send(action).is_ok()
+    }
+
+    /// Updates what [`peers`](Self::peers) reports for the connection to `addr`. Rates are
+    /// averaged over the time since the last update.
+    pub fn update_connection(&self, addr: SocketAddr, status: ConnectionStatus) {
+        let mut connections = self.connections.lock().unwrap();
+        let Some(connection) = connections.get_mut(&addr) else {
+            return;
+        };
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(connection.updated).as_secs_f64();
+        if elapsed > 0.0 {
+            let last = connection.status;
+            let rate = |bytes: u64| (bytes as f64 / elapsed) as u64;
+            connection.download_rate = rate(status.downloaded.saturating_sub(last.downloaded));
+            connection.upload_rate = rate(status.uploaded.saturating_sub(last.uploaded));
+        }
+        connection.status = status;
+        connection.updated = now;
+    }
+
+    pub fn connection_closed(&self, addr: SocketAddr) {
+        self.connections.lock().unwrap().remove(&addr);
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    future::Future,
+    io,
+    ops::Range,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    task::JoinHandle,
+};
+
+use crate::error::{Result, StorageError, UsageError};
+
+use super::Download;
+
+/// Bytes buffered between the task reading pieces and the consumer of a [`RangeReader`].
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+impl Download {
+    /// Writes `length` bytes starting at `offset` in the torrent's byte stream to `sink`, waiting
+    /// for each piece to be verified before sending it.
+    ///
+    /// The pieces are requested ahead of all others until the call returns.
+    pub async fn write_range(
+        &self,
+        offset: usize,
+        length: usize,
+        mut sink: impl AsyncWrite + Unpin,
+    ) -> Result<()> {
+        if offset
+            .checked_add(length)
+            .is_none_or(|end| end > self.length)
+        {
+            return Err(UsageError::RangeOutOfBounds { offset, length }.into());
+        }
+        if length == 0 {
+            return Ok(());
+        }
+
+        let end = offset + length;
+        let pieces = offset / self.piece_length..(end - 1) / self.piece_length + 1;
+        let _priority = Priority::new(self, pieces.clone());
+
+        for index in pieces {
+            self.wait_piece(index).await?;
+
+            let piece_start = index * self.piece_length;
+            let start = offset.max(piece_start);
+            let stop = end.min(piece_start + self.piece_size(index));
+
+            let data = self.store.read(start, stop - start).await?;
+            sink.write_all(&data).await.map_err(StorageError::from)?;
+        }
+        sink.flush().await.map_err(StorageError::from)?;
+
+        Ok(())
+    }
+
+    /// Streams `length` bytes starting at `offset` as their pieces verify, e.g. to serve HTTP
+    /// range requests for a download still in progress.
+    ///
+    /// Like [`write_range`](Self::write_range), but the pieces are read by a background task that
+    /// stops when the reader is dropped.
+    pub fn read_range(self: &Arc<Self>, offset: usize, length: usize) -> RangeReader {
+        let (reader, writer) = tokio::io::duplex(PIPE_CAPACITY);
+        let download = self.clone();
+        let task = tokio::spawn(async move { download.write_range(offset, length, writer).await });
+
+        RangeReader { pipe: reader, task }
+    }
+}
+
+/// An [`AsyncRead`] over a byte range of a download. See [`Download::read_range`].
+///
+/// Fails if the range is out of bounds, the download is dropped, or reading from disk fails.
+#[derive(Debug)]
+pub struct RangeReader {
+    pipe: DuplexStream,
+    task: JoinHandle<Result<()>>,
+}
+
+impl AsyncRead for RangeReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.pipe).poll_read(cx, buf))?;
+        if buf.filled().len() > filled || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        // The writing side closed. Surface its error instead of a silently short read.
+        match ready!(Pin::new(&mut self.task).poll(cx)) {
+            Ok(Ok(())) => Poll::Ready(Ok(())),
+            Ok(Err(e)) => Poll::Ready(Err(io::Error::other(e))),
+            Err(e) => Poll::Ready(Err(io::Error::other(e))),
+        }
+    }
+}
+
+impl Drop for RangeReader {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Keeps a range of pieces prioritized in the picker while alive.
+struct Priority<'a> {
+    download: &'a Download,
+    pieces: Range<usize>,
+}
+
+impl<'a> Priority<'a> {
+    fn new(download: &'a Download, pieces: Range<usize>) -> Self {
+        if let Some(picker) = &download.picker {
+            let mut picker = picker.lock().unwrap();
+            for index in pieces.clone() {
+                picker.prioritize(index as u32);
+            }
+        }
+        Self { download, pieces }
+    }
+}
+
+impl Drop for Priority<'_> {
+    fn drop(&mut self) {
+        if let Some(picker) = &self.download.picker {
+            let mut picker = picker.lock().unwrap();
+            for index in self.pieces.clone() {
+                picker.unprioritize(index as u32);
+            }
+        }
+    }
+}
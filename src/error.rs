@@ -0,0 +1,561 @@
+//! Errors returned by the library.
+//!
+//! Every fallible API returns [`Error`] or one of the module errors it's made of, and
+//! [`Error::category`] sorts them into what a caller can do about them.
+
+use std::{error, fmt, io, path::PathBuf};
+
+use crate::{peer::WireError, storage::DiskFull, tracker::Retry};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Broad classes of failure, stable across versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    /// Connecting or talking to a remote host failed. Retrying later may help.
+    Network,
+    /// A remote host broke the protocol or refused the request.
+    Protocol,
+    /// Reading or writing local files failed.
+    Disk,
+    /// The torrent itself is invalid.
+    BadTorrent,
+    /// The API was used in a way that can't succeed, e.g. on a torrent that isn't added.
+    Usage,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Tracker(TrackerError),
+    Handshake(HandshakeError),
+    Peer(PeerError),
+    Storage(StorageError),
+    Metainfo(MetainfoError),
+    Usage(UsageError),
+    /// Binding the listen socket failed. Wraps [`PortInUse`](crate::listener::PortInUse) if no
+    /// port in range was free.
+    Listen(io::Error),
+}
+
+impl Error {
+    pub fn category(&self) -> Category {
+        match self {
+            Error::Tracker(e) => e.category(),
+            Error::Handshake(e) => e.category(),
+            Error::Peer(e) => e.category(),
+            Error::Storage(_) => Category::Disk,
+            Error::Metainfo(_) => Category::BadTorrent,
+            Error::Usage(_) => Category::Usage,
+            Error::Listen(_) => Category::Network,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Tracker(e) => e.fmt(f),
+            Error::Handshake(e) => e.fmt(f),
+            Error::Peer(e) => e.fmt(f),
+            Error::Storage(e) => e.fmt(f),
+            Error::Metainfo(e) => e.fmt(f),
+            Error::Usage(e) => e.fmt(f),
+            Error::Listen(e) => write!(f, "failed to listen for peers: {e}"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Tracker(e) => e.source(),
+            Error::Handshake(e) => e.source(),
+            Error::Peer(e) => e.source(),
+            Error::Storage(e) => e.source(),
+            Error::Metainfo(e) => e.source(),
+            Error::Usage(_) => None,
+            Error::Listen(e) => Some(e),
+        }
+    }
+}
+
+macro_rules! impl_from {
+    ($($variant:ident($error:ty)),* $(,)?) => {
+        $(
+            impl From<$error> for Error {
+                fn from(e: $error) -> Self {
+                    Error::$variant(e)
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(
+    Tracker(TrackerError),
+    Handshake(HandshakeError),
+    Peer(PeerError),
+    Storage(StorageError),
+    Metainfo(MetainfoError),
+    Usage(UsageError),
+);
+
+impl From<DiskFull> for Error {
+    fn from(e: DiskFull) -> Self {
+        Error::Storage(e.into())
+    }
+}
+
+#[derive(Debug)]
+pub enum TrackerError {
+    #[cfg(feature = "http")]
+    Http(reqwest::Error),
+    /// The announce URL doesn't parse.
+    #[cfg(feature = "http")]
+    Url(url::ParseError),
+    /// The announce URL's scheme is neither HTTP(S) nor UDP.
+    UnsupportedScheme(String),
+    /// The tracker's host name didn't resolve to any address.
+    Resolve { host: String, source: io::Error },
+    /// Talking to a UDP tracker failed.
+    Io(io::Error),
+    /// A UDP tracker didn't answer after every retransmission.
+    Timeout,
+    /// The tracker answered with a `failure reason`, and when it may be asked again.
+    Failure { reason: String, retry: Retry },
+    /// The tracker is skipped because an earlier failure asked us to back off.
+    BackingOff(Retry),
+    /// The tracker's response couldn't be understood.
+    Malformed(String),
+    /// The torrent lists no tracker we can announce to.
+    NoTrackers,
+}
+
+impl TrackerError {
+    pub fn category(&self) -> Category {
+        match self {
+            #[cfg(feature = "http")]
+            TrackerError::Http(_) => Category::Network,
+            #[cfg(feature = "http")]
+            TrackerError::Url(_) => Category::BadTorrent,
+            TrackerError::UnsupportedScheme(_) => Category::BadTorrent,
+            TrackerError::Resolve { .. } | TrackerError::Io(_) | TrackerError::Timeout => {
+                Category::Network
+            }
+            TrackerError::Failure { .. }
+            | TrackerError::BackingOff(_)
+            | TrackerError::Malformed(_) => Category::Protocol,
+            TrackerError::NoTrackers => Category::BadTorrent,
+        }
+    }
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "http")]
+            TrackerError::Http(e) => write!(f, "tracker request failed: {e}"),
+            #[cfg(feature = "http")]
+            TrackerError::Url(e) => write!(f, "invalid tracker URL: {e}"),
+            TrackerError::UnsupportedScheme(scheme) => {
+                write!(f, "unsupported tracker scheme: {scheme}")
+            }
+            TrackerError::Resolve { host, source } => {
+                write!(f, "couldn't resolve tracker host {host}: {source}")
+            }
+            TrackerError::Io(e) => write!(f, "tracker request failed: {e}"),
+            TrackerError::Timeout => write!(f, "tracker didn't answer"),
+            TrackerError::Failure { reason, .. } => write!(f, "tracker announce failed: {reason}"),
+            TrackerError::BackingOff(Retry::Never) => write!(f, "tracker refuses this torrent"),
+            TrackerError::BackingOff(Retry::After(delay)) => {
+                write!(f, "tracker asked to retry in {}s", delay.as_secs())
+            }
+            TrackerError::BackingOff(Retry::Default) => write!(f, "tracker asked to retry later"),
+            TrackerError::Malformed(reason) => write!(f, "malformed tracker response: {reason}"),
+            TrackerError::NoTrackers => write!(f, "torrent has no usable trackers"),
+        }
+    }
+}
+
+impl error::Error for TrackerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "http")]
+            TrackerError::Http(e) => Some(e),
+            #[cfg(feature = "http")]
+            TrackerError::Url(e) => Some(e),
+            TrackerError::Resolve { source, .. } => Some(source),
+            TrackerError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<reqwest::Error> for TrackerError {
+    fn from(e: reqwest::Error) -> Self {
+        TrackerError::Http(e)
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<url::ParseError> for TrackerError {
+    fn from(e: url::ParseError) -> Self {
+        TrackerError::Url(e)
+    }
+}
+
+impl From<io::Error> for TrackerError {
+    fn from(e: io::Error) -> Self {
+        TrackerError::Io(e)
+    }
+}
+
+impl From<bendy::serde::Error> for TrackerError {
+    fn from(e: bendy::serde::Error) -> Self {
+        TrackerError::Malformed(e.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(io::Error),
+    Wire(WireError),
+    /// The peer hung up before completing its handshake.
+    Closed,
+    /// The peer answered for a different torrent than we asked for.
+    InfoHashMismatch,
+    /// The peer asked for a torrent we don't serve.
+    UnknownTorrent,
+}
+
+impl HandshakeError {
+    pub fn category(&self) -> Category {
+        match self {
+            HandshakeError::Io(_) | HandshakeError::Closed => Category::Network,
+            _ => Category::Protocol,
+        }
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "handshake failed: {e}"),
+            HandshakeError::Wire(e) => write!(f, "invalid handshake: {e}"),
+            HandshakeError::Closed => write!(f, "peer closed the connection during the handshake"),
+            HandshakeError::InfoHashMismatch => {
+                write!(f, "peer answered the handshake for a different torrent")
+            }
+            HandshakeError::UnknownTorrent => {
+                write!(f, "peer asked for a torrent we aren't serving")
+            }
+        }
+    }
+}
+
+impl error::Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            HandshakeError::Io(e) => Some(e),
+            HandshakeError::Wire(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        match unwrap_wire(e) {
+            Ok(e) => HandshakeError::Wire(e),
+            Err(e) => HandshakeError::Io(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PeerError {
+    Io(io::Error),
+    Wire(WireError),
+    /// The peer broke the protocol often enough to be banned.
+    Misbehaved,
+}
+
+impl PeerError {
+    pub fn category(&self) -> Category {
+        match self {
+            PeerError::Io(_) => Category::Network,
+            PeerError::Wire(_) | PeerError::Misbehaved => Category::Protocol,
+        }
+    }
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerError::Io(e) => write!(f, "peer connection failed: {e}"),
+            PeerError::Wire(e) => write!(f, "peer sent an invalid message: {e}"),
+            PeerError::Misbehaved => write!(f, "peer banned for protocol violations"),
+        }
+    }
+}
+
+impl error::Error for PeerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            PeerError::Io(e) => Some(e),
+            PeerError::Wire(e) => Some(e),
+            PeerError::Misbehaved => None,
+        }
+    }
+}
+
+impl From<io::Error> for PeerError {
+    fn from(e: io::Error) -> Self {
+        match unwrap_wire(e) {
+            Ok(e) => PeerError::Wire(e),
+            Err(e) => PeerError::Io(e),
+        }
+    }
+}
+
+impl From<WireError> for PeerError {
+    fn from(e: WireError) -> Self {
+        PeerError::Wire(e)
+    }
+}
+
+/// Recovers a [`WireError`] the codecs had to wrap in an `io::Error`.
+fn unwrap_wire(e: io::Error) -> Result<WireError, io::Error> {
+    if e.get_ref().is_some_and(|inner| inner.is::<WireError>()) {
+        let inner = e.into_inner().expect("checked above");
+        return Ok(*inner.downcast::<WireError>().expect("checked above"));
+    }
+    Err(e)
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    /// Reading one of the torrent's files failed.
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// Writing one of the torrent's files failed.
+    Write {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// An existing file expected to be complete doesn't have the length the metainfo says.
+    LengthMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    /// A write to a store opened read-only.
+    ReadOnly,
+    DiskFull(DiskFull),
+    /// A metainfo path that isn't safe to create, refused under
+    /// [`PathPolicy::Fail`](crate::storage::PathPolicy::Fail).
+    UnsafePath(String),
+    /// Two files of the torrent map to the same path, refused under
+    /// [`PathPolicy::Fail`](crate::storage::PathPolicy::Fail).
+    PathCollision(PathBuf),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "storage I/O failed: {e}"),
+            StorageError::Read { path, source } => {
+                write!(f, "reading {} failed: {source}", path.display())
+            }
+            StorageError::Write { path, source } => {
+                write!(f, "writing {} failed: {source}", path.display())
+            }
+            StorageError::LengthMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} is {actual} bytes long, expected {expected}",
+                path.display()
+            ),
+            StorageError::ReadOnly => write!(f, "storage is read-only"),
+            StorageError::DiskFull(e) => e.fmt(f),
+            StorageError::UnsafePath(path) => write!(f, "unsafe path in metainfo: {path}"),
+            StorageError::PathCollision(path) => {
+                write!(f, "path {} is used by more than one file", path.display())
+            }
+        }
+    }
+}
+
+impl error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            StorageError::Io(e)
+            | StorageError::Read { source: e, .. }
+            | StorageError::Write { source: e, .. } => Some(e),
+            StorageError::DiskFull(e) => Some(e),
+            StorageError::UnsafePath(_)
+            | StorageError::PathCollision(_)
+            | StorageError::LengthMismatch { .. }
+            | StorageError::ReadOnly => None,
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl From<DiskFull> for StorageError {
+    fn from(e: DiskFull) -> Self {
+        StorageError::DiskFull(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum MetainfoError {
+    Bencode(bendy::serde::Error),
+    /// The metainfo decoded but breaks a rule of the format.
+    Invalid(String),
+    /// The metainfo exceeds one of the [`MetainfoLimits`](crate::info::MetainfoLimits).
+    LimitExceeded {
+        what: &'static str,
+        value: usize,
+        limit: usize,
+    },
+}
+
+impl fmt::Display for MetainfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetainfoError::Bencode(e) => write!(f, "invalid bencode in metainfo: {e}"),
+            MetainfoError::Invalid(reason) => write!(f, "invalid metainfo: {reason}"),
+            MetainfoError::LimitExceeded { what, value, limit } => {
+                write!(f, "metainfo {what} of {value} exceeds the limit of {limit}")
+            }
+        }
+    }
+}
+
+impl error::Error for MetainfoError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MetainfoError::Bencode(e) => Some(e),
+            MetainfoError::Invalid(_) | MetainfoError::LimitExceeded { .. } => None,
+        }
+    }
+}
+
+impl From<bendy::serde::Error> for MetainfoError {
+    fn from(e: bendy::serde::Error) -> Self {
+        MetainfoError::Bencode(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsageError {
+    AlreadyAdded,
+    NotAdded,
+    PieceOutOfRange(usize),
+    FileOutOfRange(usize),
+    RangeOutOfBounds {
+        offset: usize,
+        length: usize,
+    },
+    /// A seed-only torrent was missing this many pieces on disk.
+    Incomplete(usize),
+    /// The download was dropped while something was waiting on it.
+    Dropped,
+    /// Downloading was asked of a seed-only torrent.
+    SeedOnly,
+    /// The torrent has no connection slot left under the session's limits.
+    ConnectionLimit,
+    /// The peer's address is blocked by the session's IP filter.
+    Blocked,
+    /// The torrent is an SSL torrent, whose peers only talk TLS, which isn't supported.
+    SslTorrent,
+    /// The category isn't in [`SessionConfig::categories`].
+    ///
+    /// [`SessionConfig::categories`]: crate::session::SessionConfig::categories
+    UnknownCategory(String),
+    /// Another torrent of a [`Session::add_many`] batch failed, so none were added.
+    ///
+    /// [`Session::add_many`]: crate::session::Session::add_many
+    BatchAborted,
+}
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsageError::AlreadyAdded => write!(f, "torrent already added"),
+            UsageError::NotAdded => write!(f, "torrent not added to the session"),
+            UsageError::PieceOutOfRange(index) => write!(f, "piece index {index} out of range"),
+            UsageError::FileOutOfRange(index) => write!(f, "file index {index} out of range"),
+            UsageError::RangeOutOfBounds { offset, length } => {
+                write!(
+                    f,
+                    "{length} bytes at offset {offset} are past the end of the torrent"
+                )
+            }
+            UsageError::Incomplete(missing) => {
+                write!(f, "can't seed: {missing} pieces missing or corrupt on disk")
+            }
+            UsageError::Dropped => write!(f, "download dropped before completion"),
+            UsageError::SeedOnly => write!(f, "torrent is seed-only"),
+            UsageError::ConnectionLimit => write!(f, "connection limit reached"),
+            UsageError::Blocked => write!(f, "peer address is blocked"),
+            UsageError::SslTorrent => write!(f, "SSL torrents aren't supported"),
+            UsageError::UnknownCategory(category) => write!(f, "unknown category {category:?}"),
+            UsageError::BatchAborted => write!(f, "another torrent of the batch failed"),
+        }
+    }
+}
+
+impl error::Error for UsageError {}
+
+/// A configuration file that couldn't be loaded, see [`SessionConfig::load`].
+///
+/// [`SessionConfig::load`]: crate::session::SessionConfig::load
+#[cfg(feature = "config")]
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: PathBuf, source: io::Error },
+    Parse(toml::de::Error),
+}
+
+#[cfg(feature = "config")]
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "reading {} failed: {source}", path.display())
+            }
+            ConfigError::Parse(e) => write!(f, "invalid configuration: {e}"),
+        }
+    }
+}
+
+#[cfg(feature = "config")]
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            ConfigError::Parse(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(feature = "config")]
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
@@ -1,21 +1,263 @@
-use std::fmt;
+use std::{
+    borrow::Cow,
+    fmt,
+    path::{Path, PathBuf},
+};
 
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use bendy::{
+    decoding::{Decoder, FromBencode, Object},
+    value::Value,
+};
+use encoding_rs::Encoding;
+use serde::{de, ser, Deserialize, Serialize, Serializer};
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
 
-#[derive(Debug, Deserialize)]
+use crate::error::MetainfoError;
+
+pub use self::builder::{Builder, Version};
+
+mod builder;
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Torrent {
-    announce: String,
-    #[serde(rename = "announce-list")]
-    announce_list: Vec<Vec<String>>,
+    #[serde(
+        default,
+        with = "crate::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    announce: Option<String>,
+    #[serde(
+        rename = "announce-list",
+        default,
+        with = "crate::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    announce_list: Option<Vec<Vec<String>>>,
+    /// Character set of the names in `info`, set by some older clients.
+    #[serde(
+        default,
+        with = "crate::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    encoding: Option<String>,
+    /// DHT nodes to bootstrap from, for trackerless torrents (BEP 5).
+    #[serde(
+        default,
+        deserialize_with = "crate::optional::deserialize",
+        serialize_with = "serialize_nodes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    nodes: Option<Vec<NodeEntry>>,
+    #[serde(serialize_with = "serialize_info")]
     pub info: Info,
 }
 
+/// An entry of the `nodes` list. Anything but a `[host, port]` pair is ignored rather than
+/// failing the whole torrent.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum NodeEntry {
+    Node(String, i64),
+    Other(de::IgnoredAny),
+}
+
+/// Writes the well-formed `nodes` entries, dropping the ones that were ignored when parsing.
+fn serialize_nodes<S: Serializer>(
+    nodes: &Option<Vec<NodeEntry>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let nodes = nodes.iter().flatten().filter_map(|entry| match entry {
+        NodeEntry::Node(host, port) => Some((host, port)),
+        NodeEntry::Other(_) => None,
+    });
+    serializer.collect_seq(nodes)
+}
+
+/// Writes the info dictionary as it was parsed, keys this crate doesn't read included.
+fn serialize_info<S: Serializer>(info: &Info, serializer: S) -> Result<S::Ok, S::Error> {
+    match &info.raw {
+        Some(raw) => Value::from_bencode(raw)
+            .map_err(|e| ser::Error::custom(bendy::serde::Error::from(e)))?
+            .serialize(serializer),
+        None => info.serialize(serializer),
+    }
+}
+
+/// The bytes of the `info` dictionary in a bencoded `.torrent` file.
+fn raw_info(data: &[u8]) -> Result<&[u8], MetainfoError> {
+    let mut decoder = Decoder::new(data);
+    let Some(Object::Dict(mut torrent)) =
+        decoder.next_object().map_err(bendy::serde::Error::from)?
+    else {
+        return Err(MetainfoError::Invalid("metainfo isn't a dictionary".into()));
+    };
+    while let Some((key, value)) = torrent.next_pair().map_err(bendy::serde::Error::from)? {
+        if let (b"info", Object::Dict(info)) = (key, value) {
+            return Ok(info.into_raw().map_err(bendy::serde::Error::from)?);
+        }
+    }
+    Err(MetainfoError::Invalid("missing info dictionary".into()))
+}
+
+/// Rejects v2-only info dictionaries, which have a `file tree` but no v1 `pieces` to verify
+/// data against.
+fn check_v1(info: &[u8]) -> Result<(), MetainfoError> {
+    if let Ok(Value::Dict(info)) = Value::from_bencode(info) {
+        if info.contains_key(&b"file tree"[..]) && !info.contains_key(&b"pieces"[..]) {
+            return Err(MetainfoError::Invalid(
+                "v2-only torrents aren't supported, only v1 and hybrid ones".into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Bounds enforced when parsing metainfo, so crafted torrents can't exhaust memory or produce
+/// unusable paths.
+#[derive(Debug, Clone, Copy)]
+pub struct MetainfoLimits {
+    /// Size of the `.torrent` file itself, in bytes.
+    pub max_size: usize,
+    pub max_pieces: usize,
+    pub max_files: usize,
+    /// Bytes in the torrent name or any single path component.
+    pub max_name_length: usize,
+    /// Bytes in a file's whole path, name included.
+    pub max_path_length: usize,
+}
+
+impl Default for MetainfoLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 32 * 1024 * 1024,
+            max_pieces: 2 * 1024 * 1024,
+            max_files: 100_000,
+            max_name_length: 255,
+            max_path_length: 4096,
+        }
+    }
+}
+
 impl Torrent {
-    pub fn announce(&self) -> &str {
-        &self.announce
+    /// Parses a bencoded `.torrent` file within the default [`MetainfoLimits`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MetainfoError> {
+        Self::from_bytes_with_limits(data, &MetainfoLimits::default())
+    }
+
+    pub fn from_bytes_with_limits(
+        data: &[u8],
+        limits: &MetainfoLimits,
+    ) -> Result<Self, MetainfoError> {
+        check_limit("metainfo size", data.len(), limits.max_size)?;
+
+        let raw = raw_info(data)?;
+        check_v1(raw)?;
+        let mut torrent = bendy::serde::from_bytes::<Self>(data)?;
+        torrent.info.check(limits)?;
+        torrent.info.raw = Some(raw.to_vec());
+        torrent.info.encoding = torrent
+            .encoding
+            .as_deref()
+            .and_then(|label| Encoding::for_label(label.as_bytes()));
+
+        Ok(torrent)
+    }
+
+    /// Builds a torrent from a bare info dictionary obtained without its `.torrent` file, e.g.
+    /// through BEP 9 or from a cache, announcing to the given tiers of trackers.
+    pub fn from_info_bytes(info: &[u8], trackers: Vec<Vec<String>>) -> Result<Self, MetainfoError> {
+        let limits = MetainfoLimits::default();
+        check_limit("metainfo size", info.len(), limits.max_size)?;
+
+        check_v1(info)?;
+        let mut parsed = bendy::serde::from_bytes::<Info>(info)?;
+        parsed.check(&limits)?;
+        parsed.raw = Some(info.to_vec());
+
+        let trackers = trackers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .collect::<Vec<_>>();
+        Ok(Self {
+            announce: trackers.first().map(|tier| tier[0].clone()),
+            announce_list: (!trackers.is_empty()).then_some(trackers),
+            encoding: None,
+            nodes: None,
+            info: parsed,
+        })
+    }
+
+    /// Bencodes the torrent, e.g. to keep it alongside a download. Keys this crate doesn't read
+    /// are left out, except in the info dictionary, so the info hash stays the same.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MetainfoError> {
+        Ok(bendy::serde::to_bytes(self)?)
+    }
+
+    /// The `encoding` key, naming the character set of file names that aren't UTF-8.
+    pub fn encoding(&self) -> Option<&str> {
+        self.encoding.as_deref()
+    }
+
+    /// Decodes names that aren't valid UTF-8 with the WHATWG encoding `label`, e.g. `"shift_jis"`
+    /// or `"windows-1251"`, unless the torrent's own `encoding` key names a known one.
+    ///
+    /// Without either, such names are decoded lossily as UTF-8.
+    pub fn set_fallback_encoding(&mut self, label: &str) -> Result<(), MetainfoError> {
+        let encoding = Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| MetainfoError::Invalid(format!("unknown encoding {label:?}")))?;
+
+        let known = self
+            .encoding
+            .as_deref()
+            .and_then(|label| Encoding::for_label(label.as_bytes()));
+        self.info.encoding = Some(known.unwrap_or(encoding));
+
+        Ok(())
+    }
+
+    pub fn announce(&self) -> Option<&str> {
+        self.announce.as_deref()
+    }
+
+    pub fn announce_list(&self) -> &[Vec<String>] {
+        self.announce_list.as_deref().unwrap_or_default()
+    }
+
+    /// Tracker URLs grouped into tiers: `announce-list` if it names any tracker, otherwise
+    /// `announce` as the only tier (BEP 12).
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        let tiers = self
+            .announce_list()
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        match (tiers.is_empty(), self.announce()) {
+            (false, _) => tiers,
+            (true, Some(url)) if !url.is_empty() => vec![vec![url.to_owned()]],
+            (true, _) => Vec::new(),
+        }
+    }
+
+    /// The `nodes` key's DHT nodes as host and port, skipping malformed entries.
+    pub fn nodes(&self) -> Vec<(&str, u16)> {
+        self.nodes
+            .iter()
+            .flatten()
+            .filter_map(|entry| match entry {
+                NodeEntry::Node(host, port) => Some((host.as_str(), u16::try_from(*port).ok()?)),
+                NodeEntry::Other(_) => None,
+            })
+            .filter(|&(host, port)| !host.is_empty() && port != 0)
+            .collect()
+    }
+
+    /// Whether the torrent names no tracker at all, so peers can only come from other sources
+    /// such as incoming connections or a DHT.
+    pub fn is_trackerless(&self) -> bool {
+        self.trackers().is_empty()
     }
 }
 
@@ -26,8 +268,28 @@ pub struct Info {
     #[serde(rename = "piece length")]
     piece_length: usize,
     pieces: ByteBuf,
-    #[serde(default)]
-    private: bool,
+    /// Kept as given, since writing a `private` key the dictionary didn't have would change the
+    /// info hash.
+    #[serde(
+        default,
+        with = "crate::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    private: Option<i64>,
+    /// Read from the parsed dictionary, which is written back as it was.
+    #[serde(
+        rename = "ssl-cert",
+        default,
+        deserialize_with = "crate::optional::deserialize",
+        skip_serializing
+    )]
+    ssl_cert: Option<ByteBuf>,
+    /// The dictionary as it was parsed, which the info hash is calculated from.
+    #[serde(skip)]
+    raw: Option<Vec<u8>>,
+    /// Decoder for names that aren't UTF-8. Not part of the info dictionary.
+    #[serde(skip)]
+    encoding: Option<&'static Encoding>,
 }
 
 impl fmt::Debug for Info {
@@ -37,13 +299,71 @@ impl fmt::Debug for Info {
             .field("piece_length", &self.piece_length)
             .field("pieces", &"<pieces>")
             .field("private", &self.private)
+            .field("ssl_cert", &self.ssl_cert.is_some())
+            .field("encoding", &self.encoding.map(Encoding::name))
             .finish()
     }
 }
 
 impl Info {
-    pub fn calculate_info_hash(&self) -> Result<[u8; 20]> {
-        let bytes = bendy::serde::to_bytes(self)?;
+    fn check(&self, limits: &MetainfoLimits) -> Result<(), MetainfoError> {
+        if self.piece_length == 0 {
+            return Err(MetainfoError::Invalid("piece length is zero".into()));
+        }
+        if !self.pieces.len().is_multiple_of(20) {
+            return Err(MetainfoError::Invalid(
+                "pieces is not a multiple of 20 bytes".into(),
+            ));
+        }
+        check_limit("piece count", self.num_pieces(), limits.max_pieces)?;
+
+        let (name, files) = match self.mode {
+            FileMode::Single { ref name, .. } => (name, &[][..]),
+            FileMode::Multi {
+                ref name,
+                ref files,
+                ..
+            } => (name, &files[..]),
+        };
+        check_limit("name length", name.len(), limits.max_name_length)?;
+        check_limit("file count", files.len(), limits.max_files)?;
+
+        let mut total = 0usize;
+        for file in files {
+            for component in &file.path {
+                check_limit(
+                    "path component length",
+                    component.len(),
+                    limits.max_name_length,
+                )?;
+            }
+            let path_length = name.len() + file.path.iter().map(|c| c.len() + 1).sum::<usize>();
+            check_limit("path length", path_length, limits.max_path_length)?;
+
+            total = total
+                .checked_add(file.length)
+                .ok_or_else(|| MetainfoError::Invalid("total length overflows".into()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether peers may only come from the torrent's trackers (BEP 27).
+    pub fn is_private(&self) -> bool {
+        self.private.is_some_and(|private| private != 0)
+    }
+
+    /// The PEM certificate of an SSL torrent, whose peers only accept TLS connections from
+    /// certificates it signed.
+    pub fn ssl_cert(&self) -> Option<&[u8]> {
+        self.ssl_cert.as_deref().map(|cert| cert.as_slice())
+    }
+
+    pub fn calculate_info_hash(&self) -> Result<[u8; 20], MetainfoError> {
+        let bytes = match &self.raw {
+            Some(raw) => Cow::Borrowed(raw.as_slice()),
+            None => Cow::Owned(bendy::serde::to_bytes(self)?),
+        };
 
         let mut hasher = Sha1::new();
         hasher.update(&bytes);
@@ -60,19 +380,210 @@ impl Info {
     pub fn piece_length(&self) -> usize {
         self.piece_length
     }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len() / 20
+    }
+
+    pub fn piece_hash(&self, index: usize) -> Option<[u8; 20]> {
+        let mut hash = [0; 20];
+        hash.copy_from_slice(self.pieces.get(index * 20..)?.get(..20)?);
+        Some(hash)
+    }
+
+    /// The torrent name for display, decoded as described in [`Torrent::set_fallback_encoding`].
+    pub fn name(&self) -> Cow<'_, str> {
+        self.decode(self.raw_name())
+    }
+
+    /// The torrent name exactly as it appears in the metainfo.
+    pub fn raw_name(&self) -> &[u8] {
+        match self.mode {
+            FileMode::Single { ref name, .. } => name,
+            FileMode::Multi { ref name, .. } => name,
+        }
+    }
+
+    fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        match (std::str::from_utf8(bytes), self.encoding) {
+            (Ok(text), _) => Cow::Borrowed(text),
+            (Err(_), Some(encoding)) => encoding.decode_without_bom_handling(bytes).0,
+            (Err(_), None) => String::from_utf8_lossy(bytes),
+        }
+    }
+
+    fn entry_paths(&self, raw: Vec<Vec<u8>>) -> (PathBuf, Vec<String>, Vec<Vec<u8>>) {
+        let components = raw
+            .iter()
+            .map(|component| self.decode(component).into_owned())
+            .collect::<Vec<_>>();
+        (components.iter().collect(), components, raw)
+    }
+
+    /// Files in torrent order, with paths relative to the download directory.
+    ///
+    /// BEP 47 padding files are left out; their byte ranges show up as gaps between offsets.
+    pub fn files(&self) -> Vec<FileEntry> {
+        match self.mode {
+            FileMode::Single {
+                ref name,
+                length,
+                ref attr,
+                ref symlink_path,
+                ref sha1,
+                ..
+            } => {
+                let (path, components, raw_components) = self.entry_paths(vec![name.to_vec()]);
+                vec![FileEntry {
+                    path,
+                    components,
+                    raw_components,
+                    length,
+                    offset: 0,
+                    attr: attr.clone().unwrap_or_default(),
+                    symlink_target: symlink_path.as_ref().map(|path| path.iter().collect()),
+                    symlink_components: symlink_path.clone(),
+                    sha1: sha1
+                        .as_deref()
+                        .and_then(|sha1| sha1.as_slice().try_into().ok()),
+                }]
+            }
+            FileMode::Multi {
+                ref name,
+                ref files,
+                ..
+            } => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .filter_map(|f| {
+                        let raw = std::iter::once(name).chain(&f.path);
+                        let (path, components, raw_components) =
+                            self.entry_paths(raw.map(|c| c.to_vec()).collect());
+                        let entry = FileEntry {
+                            path,
+                            components,
+                            raw_components,
+                            length: f.length,
+                            offset,
+                            attr: f.attr.clone().unwrap_or_default(),
+                            symlink_target: f
+                                .symlink_path
+                                .as_ref()
+                                .map(|path| path.iter().collect()),
+                            symlink_components: f.symlink_path.clone(),
+                            sha1: f
+                                .sha1
+                                .as_deref()
+                                .and_then(|sha1| sha1.as_slice().try_into().ok()),
+                        };
+                        offset += f.length;
+                        (!f.is_padding()).then_some(entry)
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    path: PathBuf,
+    components: Vec<String>,
+    raw_components: Vec<Vec<u8>>,
+    length: usize,
+    offset: usize,
+    attr: String,
+    symlink_target: Option<PathBuf>,
+    symlink_components: Option<Vec<String>>,
+    sha1: Option<[u8; 20]>,
+}
+
+impl FileEntry {
+    /// The path as given in the metainfo. It isn't sanitized, so it may be absolute or climb out
+    /// of the download directory; [`FileStore`](crate::storage::FileStore) sanitizes it.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path's components decoded for display, torrent name first. Names that aren't UTF-8
+    /// are decoded as described in [`Torrent::set_fallback_encoding`].
+    pub fn components(&self) -> &[String] {
+        &self.components
+    }
+
+    /// The path's components exactly as they appear in the metainfo.
+    pub fn raw_components(&self) -> &[Vec<u8>] {
+        &self.raw_components
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Byte offset of the file within the torrent's concatenated data.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.attr.contains('x')
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.attr.contains('h')
+    }
+
+    /// Target of the file if it is a symlink, relative to the torrent's root directory.
+    pub fn symlink_target(&self) -> Option<&Path> {
+        if self.attr.contains('l') {
+            self.symlink_target.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Components of [`symlink_target`](Self::symlink_target) as they appear in the metainfo.
+    pub fn symlink_components(&self) -> Option<&[String]> {
+        self.symlink_target()?;
+        self.symlink_components.as_deref()
+    }
+
+    pub fn sha1(&self) -> Option<[u8; 20]> {
+        self.sha1
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 enum FileMode {
     Single {
-        name: String,
+        name: ByteBuf,
         length: usize,
         #[serde(skip)]
         md5sum: Option<String>,
+        #[serde(
+            default,
+            with = "crate::optional",
+            skip_serializing_if = "Option::is_none"
+        )]
+        attr: Option<String>,
+        #[serde(
+            rename = "symlink path",
+            default,
+            with = "crate::optional",
+            skip_serializing_if = "Option::is_none"
+        )]
+        symlink_path: Option<Vec<String>>,
+        #[serde(
+            default,
+            with = "crate::optional",
+            skip_serializing_if = "Option::is_none"
+        )]
+        sha1: Option<ByteBuf>,
     },
     Multi {
-        name: String,
+        name: ByteBuf,
         files: Vec<File>,
         #[serde(skip)]
         md5sum: Option<String>,
@@ -81,6 +592,204 @@ enum FileMode {
 
 #[derive(Debug, Deserialize, Serialize)]
 struct File {
+    #[serde(
+        default,
+        with = "crate::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    attr: Option<String>,
     length: usize,
-    path: Vec<String>,
+    path: Vec<ByteBuf>,
+    #[serde(
+        rename = "symlink path",
+        default,
+        with = "crate::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    symlink_path: Option<Vec<String>>,
+    #[serde(
+        default,
+        with = "crate::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    sha1: Option<ByteBuf>,
+}
+
+impl File {
+    fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+fn check_limit(what: &'static str, value: usize, limit: usize) -> Result<(), MetainfoError> {
+    if value > limit {
+        return Err(MetainfoError::LimitExceeded { what, value, limit });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::{collection::vec, prelude::*};
+
+    use super::*;
+    use crate::tracker::TrackerResponse;
+
+    #[derive(Debug, Clone)]
+    enum Value {
+        Int(i64),
+        Bytes(Vec<u8>),
+        List(Vec<Value>),
+        Dict(Vec<(Vec<u8>, Value)>),
+    }
+
+    impl Value {
+        fn encode(&self, dst: &mut Vec<u8>) {
+            match self {
+                Value::Int(n) => dst.extend_from_slice(format!("i{n}e").as_bytes()),
+                Value::Bytes(bytes) => {
+                    dst.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+                    dst.extend_from_slice(bytes);
+                }
+                Value::List(items) => {
+                    dst.push(b'l');
+                    items.iter().for_each(|item| item.encode(dst));
+                    dst.push(b'e');
+                }
+                Value::Dict(entries) => {
+                    let mut entries = entries.clone();
+                    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                    entries.dedup_by(|(a, _), (b, _)| a == b);
+                    dst.push(b'd');
+                    for (key, value) in &entries {
+                        Value::Bytes(key.clone()).encode(dst);
+                        value.encode(dst);
+                    }
+                    dst.push(b'e');
+                }
+            }
+        }
+    }
+
+    /// Bencode values, with dictionary keys mostly drawn from real metainfo and announce
+    /// responses so parsing gets past the first level.
+    fn value() -> impl Strategy<Value = Value> {
+        let key = prop_oneof![
+            prop::sample::select(vec![
+                "announce",
+                "announce-list",
+                "attr",
+                "complete",
+                "encoding",
+                "failure reason",
+                "files",
+                "incomplete",
+                "info",
+                "interval",
+                "length",
+                "name",
+                "nodes",
+                "path",
+                "peers",
+                "piece length",
+                "pieces",
+                "private",
+                "sha1",
+                "symlink path",
+            ])
+            .prop_map(|key| key.as_bytes().to_vec()),
+            vec(any::<u8>(), 0..8),
+        ];
+        let leaf = prop_oneof![
+            any::<i64>().prop_map(Value::Int),
+            (-2i64..0x10000).prop_map(Value::Int),
+            vec(any::<u8>(), 0..48).prop_map(Value::Bytes),
+        ];
+        leaf.prop_recursive(4, 64, 8, move |inner| {
+            prop_oneof![
+                vec(inner.clone(), 0..6).prop_map(Value::List),
+                vec((key.clone(), inner), 0..8).prop_map(Value::Dict),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn parses_random_bencode(value in value(), cut in any::<prop::sample::Index>()) {
+            let mut bytes = Vec::new();
+            value.encode(&mut bytes);
+
+            for bytes in [&bytes[..], &bytes[..cut.index(bytes.len() + 1)]] {
+                if let Ok(torrent) = Torrent::from_bytes(bytes) {
+                    torrent.trackers();
+                    torrent.nodes();
+                    torrent.info.name();
+                    torrent.info.files();
+                    torrent.info.piece_hash(torrent.info.num_pieces().saturating_sub(1));
+                }
+                let _ = TrackerResponse::from_bytes(bytes);
+            }
+        }
+
+        #[test]
+        fn parses_arbitrary_bytes(bytes in vec(any::<u8>(), 0..256)) {
+            let _ = Torrent::from_bytes(&bytes);
+            let _ = Torrent::from_info_bytes(&bytes, Vec::new());
+            let _ = TrackerResponse::from_bytes(&bytes);
+        }
+    }
+
+    #[test]
+    fn builds_torrent_from_info_bytes() {
+        let mut info = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend_from_slice(&[7; 20]);
+        info.push(b'e');
+
+        let trackers = vec![vec![], vec!["http://tracker/announce".to_owned()]];
+        let torrent = Torrent::from_info_bytes(&info, trackers).unwrap();
+        assert_eq!(
+            torrent.info.calculate_info_hash().unwrap(),
+            <[u8; 20]>::from(Sha1::digest(&info))
+        );
+        assert_eq!(torrent.announce(), Some("http://tracker/announce"));
+        assert_eq!(torrent.trackers(), [["http://tracker/announce"]]);
+        assert_eq!(
+            Torrent::from_bytes(&torrent.to_bytes().unwrap())
+                .unwrap()
+                .info
+                .calculate_info_hash()
+                .unwrap(),
+            torrent.info.calculate_info_hash().unwrap()
+        );
+
+        // Keys this crate doesn't read are kept, so they still count towards the info hash.
+        let mut extra = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        extra.extend_from_slice(&[7; 20]);
+        extra.extend_from_slice(b"6:source7:trackere");
+        let torrent = Torrent::from_info_bytes(&extra, Vec::new()).unwrap();
+        let info_hash = <[u8; 20]>::from(Sha1::digest(&extra));
+        assert_eq!(torrent.info.calculate_info_hash().unwrap(), info_hash);
+        assert_eq!(
+            Torrent::from_bytes(&torrent.to_bytes().unwrap())
+                .unwrap()
+                .info
+                .calculate_info_hash()
+                .unwrap(),
+            info_hash
+        );
+    }
+
+    #[test]
+    fn reads_ssl_cert_from_info() {
+        let mut info = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend_from_slice(&[7; 20]);
+        info.extend_from_slice(b"8:ssl-cert4:certe");
+
+        let torrent = Torrent::from_info_bytes(&info, Vec::new()).unwrap();
+        assert_eq!(torrent.info.ssl_cert(), Some(&b"cert"[..]));
+        assert_eq!(
+            torrent.info.calculate_info_hash().unwrap(),
+            <[u8; 20]>::from(Sha1::digest(&info))
+        );
+    }
 }
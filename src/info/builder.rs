@@ -0,0 +1,827 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use super::File;
+use crate::{
+    error::{MetainfoError, Result, StorageError},
+    optional,
+    progress::HashProgress,
+};
+
+const BLOCK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    /// Only v2 metadata, for other clients. [`Torrent`](super::Torrent) can't parse it.
+    V2,
+    /// Both v1 and v2 metadata, with v1 files padded to piece boundaries so either swarm can be
+    /// joined.
+    Hybrid,
+}
+
+impl Version {
+    fn has_v1(self) -> bool {
+        matches!(self, Version::V1 | Version::Hybrid)
+    }
+
+    fn has_v2(self) -> bool {
+        matches!(self, Version::V2 | Version::Hybrid)
+    }
+}
+
+/// Creates a `.torrent` file from a file or directory on disk.
+///
+/// Hashing is blocking and spread across `threads` OS threads, so async callers should run
+/// [`Builder::build`] through `spawn_blocking`.
+#[derive(Debug)]
+pub struct Builder {
+    path: PathBuf,
+    name: Option<String>,
+    piece_length: Option<usize>,
+    announce: Vec<Vec<String>>,
+    private: bool,
+    version: Version,
+    threads: usize,
+}
+
+impl Builder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            name: None,
+            piece_length: None,
+            announce: Vec::new(),
+            private: false,
+            version: Version::V1,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    /// Overrides the torrent name, which defaults to the file or directory name.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the piece length, which must be a power of two of at least 16 KiB. When unset, one is
+    /// picked based on the total size.
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tier of trackers. The first tracker of the first tier becomes `announce`.
+    pub fn tier(mut self, trackers: Vec<String>) -> Self {
+        self.announce.push(trackers);
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Hashes the input and returns the bencoded torrent.
+    ///
+    /// `progress` is called from the hashing threads after every piece. Pieces are hashed out of
+    /// order, so the reported file jumps around when there's more than one thread.
+    pub fn build(self, progress: impl Fn(&HashProgress) + Sync) -> Result<Vec<u8>> {
+        let name = match self.name {
+            Some(ref name) => name.clone(),
+            None => self
+                .path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| MetainfoError::Invalid("input path has no UTF-8 file name".into()))?
+                .to_owned(),
+        };
+
+        let single = !fs::metadata(&self.path)
+            .map_err(StorageError::from)?
+            .is_dir();
+        let files = if single {
+            vec![InputFile::scan(&self.path, Vec::new(), &self.path).map_err(StorageError::from)?]
+        } else {
+            let mut files = Vec::new();
+            walk(&self.path, &self.path, &mut files).map_err(StorageError::from)?;
+            // v2 file trees are sorted, and hybrid torrents need the v1 list in the same order.
+            files.sort_by(|a, b| a.components.cmp(&b.components));
+            files
+        };
+
+        let total = files.iter().map(|f| f.length as u64).sum::<u64>();
+        let piece_length = match self.piece_length {
+            Some(piece_length) => piece_length,
+            None => auto_piece_length(total),
+        };
+        if !piece_length.is_power_of_two() || piece_length < BLOCK_SIZE {
+            return Err(MetainfoError::Invalid(format!(
+                "piece length must be a power of two of at least {BLOCK_SIZE}"
+            ))
+            .into());
+        }
+
+        let hashes = hash(
+            &files,
+            piece_length,
+            self.version,
+            self.threads,
+            total,
+            &progress,
+        )
+        .map_err(StorageError::from)?;
+
+        let mut v1_files = Vec::new();
+        let mut v1_length = 0;
+        if self.version.has_v1() {
+            for (index, file) in files.iter().enumerate() {
+                v1_files.push(File {
+                    attr: file.attr(),
+                    length: file.length,
+                    path: file
+                        .components
+                        .iter()
+                        .map(|c| ByteBuf::from(c.as_bytes()))
+                        .collect(),
+                    symlink_path: file.symlink.clone(),
+                    sha1: None,
+                });
+                v1_length += file.length;
+
+                let padding = padding_after(&files, index, piece_length, self.version);
+                if padding > 0 {
+                    v1_files.push(File {
+                        attr: Some("p".to_owned()),
+                        length: padding,
+                        path: vec![
+                            ByteBuf::from(b".pad".to_vec()),
+                            ByteBuf::from(padding.to_string().into_bytes()),
+                        ],
+                        symlink_path: None,
+                        sha1: None,
+                    });
+                }
+            }
+        }
+
+        let mut file_tree = BTreeMap::new();
+        let mut piece_layers = BTreeMap::new();
+        if self.version.has_v2() {
+            for (file, tree) in files.iter().zip(&hashes.v2) {
+                let mut path = file.components.clone();
+                if single {
+                    path.push(name.clone());
+                }
+
+                let leaf = FileTreeNode::File {
+                    length: file.length,
+                    pieces_root: tree.as_ref().map(|tree| ByteBuf::from(tree.root.to_vec())),
+                    attr: file.attr(),
+                    symlink_path: file.symlink.clone(),
+                };
+                insert_leaf(&mut file_tree, &path, leaf);
+
+                if let Some(tree) = tree {
+                    if file.length > piece_length {
+                        piece_layers.insert(
+                            ByteBuf::from(tree.root.to_vec()),
+                            ByteBuf::from(tree.piece_layer.concat()),
+                        );
+                    }
+                }
+            }
+        }
+
+        let info = OutputInfo {
+            file_tree: self.version.has_v2().then_some(file_tree),
+            files: (self.version.has_v1() && !single).then_some(v1_files),
+            length: (self.version.has_v1() && single).then_some(v1_length),
+            meta_version: self.version.has_v2().then_some(2),
+            name,
+            piece_length,
+            pieces: self
+                .version
+                .has_v1()
+                .then(|| ByteBuf::from(hashes.v1.concat())),
+            private: self.private.then_some(1),
+        };
+
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        let torrent = OutputTorrent {
+            announce: self.announce.first().and_then(|tier| tier.first()).cloned(),
+            announce_list: (!self.announce.is_empty()).then_some(self.announce),
+            created_by: concat!("torrant ", env!("CARGO_PKG_VERSION")),
+            creation_date,
+            info,
+            piece_layers: (!piece_layers.is_empty()).then_some(piece_layers),
+        };
+
+        Ok(bendy::serde::to_bytes(&torrent).map_err(MetainfoError::from)?)
+    }
+}
+
+#[derive(Debug)]
+struct InputFile {
+    path: PathBuf,
+    components: Vec<String>,
+    length: usize,
+    executable: bool,
+    symlink: Option<Vec<String>>,
+}
+
+impl InputFile {
+    fn scan(path: &Path, components: Vec<String>, root: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+
+        if metadata.is_symlink() && path != root {
+            // Symlinks pointing inside the torrent are preserved, anything else is followed.
+            let target = fs::canonicalize(path)?;
+            let root = fs::canonicalize(root)?;
+            if let Ok(relative) = target.strip_prefix(&root) {
+                return Ok(Self {
+                    path: path.to_owned(),
+                    components,
+                    length: 0,
+                    executable: false,
+                    symlink: Some(path_components(relative)?),
+                });
+            }
+        }
+
+        let metadata = fs::metadata(path)?;
+
+        #[cfg(unix)]
+        let executable = {
+            use std::os::unix::fs::PermissionsExt;
+            metadata.permissions().mode() & 0o111 != 0
+        };
+        #[cfg(not(unix))]
+        let executable = false;
+
+        Ok(Self {
+            path: path.to_owned(),
+            components,
+            length: metadata.len() as usize,
+            executable,
+            symlink: None,
+        })
+    }
+
+    fn attr(&self) -> Option<String> {
+        if self.symlink.is_some() {
+            Some("l".to_owned())
+        } else if self.executable {
+            Some("x".to_owned())
+        } else {
+            None
+        }
+    }
+}
+
+fn walk(dir: &Path, root: &Path, files: &mut Vec<InputFile>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let relative = path
+            .strip_prefix(root)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let components = path_components(relative)?;
+
+        if fs::symlink_metadata(&path)?.is_dir() {
+            walk(&path, root, files)?;
+        } else {
+            files.push(InputFile::scan(&path, components, root)?);
+        }
+    }
+
+    Ok(())
+}
+
+fn path_components(path: &Path) -> io::Result<Vec<String>> {
+    path.iter()
+        .map(|component| {
+            component.to_str().map(str::to_owned).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not valid UTF-8", path.display()),
+                )
+            })
+        })
+        .collect()
+}
+
+fn auto_piece_length(total: u64) -> usize {
+    // Aim for roughly 1500 pieces, clamped between 16 KiB and 16 MiB.
+    let target = (total / 1500).max(1).next_power_of_two();
+    target.clamp(BLOCK_SIZE as u64, 16 * 1024 * 1024) as usize
+}
+
+/// Length of the padding file needed after `files[index]` to align the next file to a piece
+/// boundary in hybrid torrents.
+fn padding_after(
+    files: &[InputFile],
+    index: usize,
+    piece_length: usize,
+    version: Version,
+) -> usize {
+    if version != Version::Hybrid || index + 1 == files.len() {
+        return 0;
+    }
+
+    let remainder = files[index].length % piece_length;
+    if remainder == 0 {
+        0
+    } else {
+        piece_length - remainder
+    }
+}
+
+fn insert_leaf(tree: &mut BTreeMap<String, FileTreeNode>, path: &[String], leaf: FileTreeNode) {
+    match path {
+        [] => {
+            tree.insert(String::new(), leaf);
+        }
+        [first, rest @ ..] => {
+            let node = tree
+                .entry(first.clone())
+                .or_insert_with(|| FileTreeNode::Directory(BTreeMap::new()));
+            if let FileTreeNode::Directory(children) = node {
+                insert_leaf(children, rest, leaf);
+            }
+        }
+    }
+}
+
+struct Hashes {
+    v1: Vec<[u8; 20]>,
+    /// Merkle trees for each input file, `None` for empty files and symlinks.
+    v2: Vec<Option<MerkleTree>>,
+}
+
+struct MerkleTree {
+    root: [u8; 32],
+    piece_layer: Vec<[u8; 32]>,
+}
+
+/// A unit of hashing work, always covering at most one piece worth of data.
+enum Job {
+    /// A v1 piece of the concatenated file stream, which may span several files.
+    Stream { piece: usize },
+    /// A piece-aligned chunk of a single file, hashed for v2 and, in hybrid torrents, v1.
+    File { file: usize, piece: usize },
+}
+
+enum JobOutput {
+    Stream {
+        piece: usize,
+        hash: [u8; 20],
+    },
+    File {
+        file: usize,
+        piece: usize,
+        v1: Option<[u8; 20]>,
+        blocks: Vec<[u8; 32]>,
+    },
+}
+
+fn hash(
+    files: &[InputFile],
+    piece_length: usize,
+    version: Version,
+    threads: usize,
+    total: u64,
+    progress: &(impl Fn(&HashProgress) + Sync),
+) -> io::Result<Hashes> {
+    let jobs = if version.has_v2() {
+        files
+            .iter()
+            .enumerate()
+            .flat_map(|(file, input)| {
+                (0..input.length.div_ceil(piece_length)).map(move |piece| Job::File { file, piece })
+            })
+            .collect::<Vec<_>>()
+    } else {
+        (0..(total as usize).div_ceil(piece_length))
+            .map(|piece| Job::Stream { piece })
+            .collect()
+    };
+
+    let start = Instant::now();
+    let next = AtomicUsize::new(0);
+    let hashed = AtomicU64::new(0);
+    let pieces_hashed = AtomicUsize::new(0);
+    let outputs = Mutex::new(Vec::with_capacity(jobs.len()));
+
+    thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| -> io::Result<()> {
+                    let mut buffer = vec![0; piece_length];
+                    while let Some(job) = jobs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let (output, read) =
+                            run_job(job, files, piece_length, version, &mut buffer)?;
+                        outputs.lock().unwrap().push(output);
+
+                        let done = hashed.fetch_add(read as u64, Ordering::Relaxed) + read as u64;
+                        progress(&HashProgress {
+                            pieces_hashed: pieces_hashed.fetch_add(1, Ordering::Relaxed) + 1,
+                            pieces: jobs.len(),
+                            bytes_hashed: done,
+                            bytes: total,
+                            file: job_file(job, files, piece_length),
+                            elapsed: start.elapsed(),
+                        });
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("hashing thread panicked"))
+    })?;
+
+    let mut v1 = vec![[0; 20]; if version.has_v2() { 0 } else { jobs.len() }];
+    let mut blocks = files.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+    let mut outputs = outputs.into_inner().unwrap();
+    outputs.sort_by_key(|output| match *output {
+        JobOutput::Stream { piece, .. } => (0, piece),
+        JobOutput::File { file, piece, .. } => (file, piece),
+    });
+
+    for output in outputs {
+        match output {
+            JobOutput::Stream { piece, hash } => v1[piece] = hash,
+            JobOutput::File {
+                file,
+                v1: hash,
+                blocks: piece_blocks,
+                ..
+            } => {
+                v1.extend(hash);
+                blocks[file].push(piece_blocks);
+            }
+        }
+    }
+
+    let v2 = blocks
+        .into_iter()
+        .map(|pieces| (!pieces.is_empty()).then(|| merkle_tree(pieces, piece_length)))
+        .collect();
+
+    Ok(Hashes { v1, v2 })
+}
+
+/// The file a job's data starts in, relative to the torrent's root.
+fn job_file(job: &Job, files: &[InputFile], piece_length: usize) -> PathBuf {
+    let file = match *job {
+        Job::Stream { piece } => {
+            let mut offset = piece * piece_length;
+            files.iter().find(|file| {
+                let inside = offset < file.length;
+                offset = offset.saturating_sub(file.length);
+                inside
+            })
+        }
+        Job::File { file, .. } => files.get(file),
+    };
+    match file {
+        // A single-file torrent's file is named after the torrent instead.
+        Some(file) if file.components.is_empty() => {
+            file.path.file_name().map(PathBuf::from).unwrap_or_default()
+        }
+        Some(file) => file.components.iter().collect(),
+        None => PathBuf::new(),
+    }
+}
+
+fn run_job(
+    job: &Job,
+    files: &[InputFile],
+    piece_length: usize,
+    version: Version,
+    buffer: &mut [u8],
+) -> io::Result<(JobOutput, usize)> {
+    match *job {
+        Job::Stream { piece } => {
+            let start = piece * piece_length;
+            let len = read_stream(files, start, buffer)?;
+            let hash = Sha1::digest(&buffer[..len]).into();
+            Ok((JobOutput::Stream { piece, hash }, len))
+        }
+        Job::File { file, piece } => {
+            let input = &files[file];
+            let start = piece * piece_length;
+            let len = piece_length.min(input.length - start);
+
+            let mut handle = fs::File::open(&input.path)?;
+            handle.seek(SeekFrom::Start(start as u64))?;
+            handle.read_exact(&mut buffer[..len])?;
+
+            let blocks = buffer[..len]
+                .chunks(BLOCK_SIZE)
+                .map(|block| Sha256::digest(block).into())
+                .collect();
+
+            let v1 = version.has_v1().then(|| {
+                // Hybrid pieces are padded with zeroes up to the next file, except at the very end.
+                let padded = if file + 1 == files.len() {
+                    len
+                } else {
+                    piece_length
+                };
+                buffer[len..padded].fill(0);
+                Sha1::digest(&buffer[..padded]).into()
+            });
+
+            Ok((
+                JobOutput::File {
+                    file,
+                    piece,
+                    v1,
+                    blocks,
+                },
+                len,
+            ))
+        }
+    }
+}
+
+/// Reads from the concatenation of all files starting at `offset`, returning how much was read.
+fn read_stream(files: &[InputFile], offset: usize, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    let mut file_offset = 0;
+
+    for file in files {
+        let file_end = file_offset + file.length;
+        let position = offset + read;
+
+        if read < buffer.len() && position < file_end && file.length > 0 {
+            let len = (buffer.len() - read).min(file_end - position);
+
+            let mut handle = fs::File::open(&file.path)?;
+            handle.seek(SeekFrom::Start((position - file_offset) as u64))?;
+            handle.read_exact(&mut buffer[read..read + len])?;
+            read += len;
+        }
+
+        file_offset = file_end;
+    }
+
+    Ok(read)
+}
+
+/// Builds a file's merkle tree from per-piece block hashes, as described in BEP 52.
+fn merkle_tree(pieces: Vec<Vec<[u8; 32]>>, piece_length: usize) -> MerkleTree {
+    let blocks_per_piece = piece_length / BLOCK_SIZE;
+    let single_piece = pieces.len() == 1;
+
+    // Each piece is its own subtree, padded with zero hashes up to the full piece width.
+    let piece_layer = pieces
+        .iter()
+        .map(|blocks| merkle_root(blocks.clone(), blocks_per_piece, [0; 32]))
+        .collect::<Vec<_>>();
+
+    let root = if single_piece {
+        // Files no larger than a piece only pad their leaves up to the next power of two.
+        let blocks = pieces.into_iter().next().unwrap_or_default();
+        let width = blocks.len().next_power_of_two();
+        merkle_root(blocks, width, [0; 32])
+    } else {
+        let pad = merkle_root(Vec::new(), blocks_per_piece, [0; 32]);
+        let width = piece_layer.len().next_power_of_two();
+        merkle_root(piece_layer.clone(), width, pad)
+    };
+
+    MerkleTree { root, piece_layer }
+}
+
+fn merkle_root(mut layer: Vec<[u8; 32]>, width: usize, pad: [u8; 32]) -> [u8; 32] {
+    layer.resize(width.max(1), pad);
+
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update(pair[0]);
+                hasher.update(pair[1]);
+                hasher.finalize().into()
+            })
+            .collect();
+    }
+
+    layer[0]
+}
+
+#[derive(Serialize)]
+struct OutputTorrent {
+    #[serde(
+        serialize_with = "optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    announce: Option<String>,
+    #[serde(
+        rename = "announce-list",
+        serialize_with = "optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    announce_list: Option<Vec<Vec<String>>>,
+    #[serde(rename = "created by")]
+    created_by: &'static str,
+    #[serde(rename = "creation date")]
+    creation_date: u64,
+    info: OutputInfo,
+    #[serde(
+        rename = "piece layers",
+        serialize_with = "optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+}
+
+#[derive(Serialize)]
+struct OutputInfo {
+    #[serde(
+        rename = "file tree",
+        serialize_with = "optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    file_tree: Option<BTreeMap<String, FileTreeNode>>,
+    #[serde(
+        serialize_with = "optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    files: Option<Vec<File>>,
+    #[serde(
+        serialize_with = "optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    length: Option<usize>,
+    #[serde(
+        rename = "meta version",
+        serialize_with = "optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    meta_version: Option<u8>,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: usize,
+    #[serde(
+        serialize_with = "optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pieces: Option<ByteBuf>,
+    #[serde(
+        serialize_with = "optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    private: Option<u8>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum FileTreeNode {
+    Directory(BTreeMap<String, FileTreeNode>),
+    File {
+        #[serde(
+            serialize_with = "optional::serialize",
+            skip_serializing_if = "Option::is_none"
+        )]
+        attr: Option<String>,
+        length: usize,
+        #[serde(
+            rename = "pieces root",
+            serialize_with = "optional::serialize",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pieces_root: Option<ByteBuf>,
+        #[serde(
+            rename = "symlink path",
+            serialize_with = "optional::serialize",
+            skip_serializing_if = "Option::is_none"
+        )]
+        symlink_path: Option<Vec<String>>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use bendy::{decoding::FromBencode, value::Value};
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::info::{raw_info, Torrent};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "torrant-builder-{:016x}",
+            thread_rng().gen::<u64>()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn builds_merkle_root() {
+        let dir = temp_dir();
+        let path = dir.join("a.bin");
+        fs::write(
+            &path,
+            (0..40000).map(|i| (i % 251) as u8).collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        let bytes = Builder::new(&path)
+            .piece_length(16384)
+            .version(Version::V2)
+            .build(|_| {})
+            .unwrap();
+        let Value::Dict(torrent) = Value::from_bencode(&bytes).unwrap() else {
+            panic!("torrent isn't a dictionary");
+        };
+        let Some(Value::Dict(info)) = torrent.get(&b"info"[..]) else {
+            panic!("missing info");
+        };
+        let Some(Value::Dict(tree)) = info.get(&b"file tree"[..]) else {
+            panic!("missing file tree");
+        };
+        let Some(Value::Dict(file)) = tree.get(&b"a.bin"[..]) else {
+            panic!("missing file");
+        };
+        let Some(Value::Dict(leaf)) = file.get(&b""[..]) else {
+            panic!("missing leaf");
+        };
+        let Some(Value::Bytes(root)) = leaf.get(&b"pieces root"[..]) else {
+            panic!("missing pieces root");
+        };
+        assert_eq!(
+            root.iter().map(|b| format!("{b:02x}")).collect::<String>(),
+            "ab671631a9fa97a1fdac651fff6c68773b9acf0735b9c7f6ecdd54cbf1bf5dc2"
+        );
+        assert!(matches!(
+            Torrent::from_bytes(&bytes),
+            Err(MetainfoError::Invalid(_))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hybrid_info_hash_covers_v2_keys() {
+        let dir = temp_dir();
+        let content = dir.join("content");
+        fs::create_dir_all(&content).unwrap();
+        fs::write(content.join("a.bin"), vec![1; 20000]).unwrap();
+        fs::write(content.join("b.bin"), vec![2; 5000]).unwrap();
+
+        let bytes = Builder::new(&content)
+            .piece_length(16384)
+            .version(Version::Hybrid)
+            .build(|_| {})
+            .unwrap();
+        let info = raw_info(&bytes).unwrap();
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            torrent.info.calculate_info_hash().unwrap(),
+            <[u8; 20]>::from(Sha1::digest(info))
+        );
+        // a.bin is padded to the end of its second piece, so b.bin starts the third.
+        assert_eq!(torrent.info.num_pieces(), 3);
+        assert_eq!(
+            torrent.info.piece_hash(2).unwrap(),
+            <[u8; 20]>::from(Sha1::digest([2; 5000]))
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
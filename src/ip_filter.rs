@@ -0,0 +1,230 @@
+#[cfg(feature = "http")]
+use std::time::Duration;
+use std::{
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, RwLock},
+};
+
+use flate2::read::GzDecoder;
+#[cfg(feature = "http")]
+use reqwest::{Client, Url};
+#[cfg(feature = "http")]
+use tokio::task::JoinHandle;
+#[cfg(feature = "http")]
+use url::ParseError;
+
+/// A set of blocked IP ranges.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a blocklist in PeerGuardian (`.p2p`) or eMule (`.dat`) format, optionally gzipped.
+    ///
+    /// Comments and lines that don't parse are skipped, since published lists often contain a few.
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let mut decompressed = Vec::new();
+        let data = if data.starts_with(&[0x1f, 0x8b]) {
+            GzDecoder::new(data).read_to_end(&mut decompressed)?;
+            &decompressed[..]
+        } else {
+            data
+        };
+
+        let mut filter = Self::new();
+        // Descriptions are frequently Latin-1 rather than UTF-8.
+        for line in String::from_utf8_lossy(data).lines() {
+            if let Some((start, end)) = parse_line(line) {
+                filter.push(start, end);
+            }
+        }
+        filter.normalize();
+
+        Ok(filter)
+    }
+
+    /// Blocks every address from `start` to `end`, inclusive.
+    pub fn block(&mut self, start: IpAddr, end: IpAddr) -> io::Result<()> {
+        if start.is_ipv4() != end.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("range {start}-{end} mixes address families"),
+            ));
+        }
+
+        self.push(start, end);
+        self.normalize();
+        Ok(())
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => contains(&self.v4, u32::from(ip)),
+                None => contains(&self.v6, u128::from(ip)),
+            },
+        }
+    }
+
+    /// Number of disjoint blocked ranges.
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn push(&mut self, start: IpAddr, end: IpAddr) {
+        match (start, end) {
+            (IpAddr::V4(start), IpAddr::V4(end)) => {
+                let (start, end) = (u32::from(start), u32::from(end));
+                self.v4.push((start.min(end), start.max(end)));
+            }
+            (IpAddr::V6(start), IpAddr::V6(end)) => {
+                let (start, end) = (u128::from(start), u128::from(end));
+                self.v6.push((start.min(end), start.max(end)));
+            }
+            _ => {}
+        }
+    }
+
+    fn normalize(&mut self) {
+        merge(&mut self.v4);
+        merge(&mut self.v6);
+    }
+}
+
+fn merge<T: Ord + Copy>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    *ranges = merged;
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let index = ranges.partition_point(|&(start, _)| start <= ip);
+    index > 0 && ip <= ranges[index - 1].1
+}
+
+fn parse_line(line: &str) -> Option<(IpAddr, IpAddr)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+        return None;
+    }
+
+    if line.contains(',') {
+        // eMule: `start - end , access level , description`. Levels above 127 are allowed.
+        let mut fields = line.split(',');
+        let range = fields.next()?;
+        let level = fields.next()?.trim().parse::<u32>().ok()?;
+        return (level <= 127).then(|| parse_range(range)).flatten();
+    }
+
+    // PeerGuardian: `description:start-end`, where the description may contain colons. A bare
+    // range is accepted too, which is also the only way to tell IPv6 colons apart.
+    parse_range(line).or_else(|| parse_range(&line[line.rfind(':')? + 1..]))
+}
+
+fn parse_range(range: &str) -> Option<(IpAddr, IpAddr)> {
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (parse_ip(start)?, parse_ip(end)?);
+    (start.is_ipv4() == end.is_ipv4()).then_some((start, end))
+}
+
+/// Parses an address, accepting the zero-padded octets eMule lists use.
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    if let Ok(ip) = s.parse::<Ipv6Addr>() {
+        return Some(IpAddr::V6(ip));
+    }
+
+    let mut octets = [0; 4];
+    let mut parts = s.split('.');
+    for octet in &mut octets {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+/// An [`IpFilter`] that can be replaced while connections are consulting it.
+#[derive(Debug, Clone, Default)]
+pub struct SharedIpFilter {
+    current: Arc<RwLock<Arc<IpFilter>>>,
+}
+
+impl SharedIpFilter {
+    pub fn new(filter: IpFilter) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(filter))),
+        }
+    }
+
+    /// The active filter. It stays valid even if a newer one is stored meanwhile.
+    pub fn load(&self) -> Arc<IpFilter> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn store(&self, filter: IpFilter) {
+        *self.current.write().unwrap() = Arc::new(filter);
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.current.read().unwrap().is_blocked(ip)
+    }
+
+    /// Downloads the blocklist at `url` now and then every `interval`, swapping each successfully
+    /// parsed list in.
+    ///
+    /// A failed refresh keeps the previous list active until the next attempt.
+    #[cfg(feature = "http")]
+    pub fn spawn_updates(
+        &self,
+        url: &str,
+        interval: Duration,
+    ) -> Result<JoinHandle<()>, ParseError> {
+        let url = Url::parse(url)?;
+        let filter = self.clone();
+
+        Ok(tokio::spawn(async move {
+            let client = Client::new();
+            loop {
+                if let Some(update) = fetch(&client, url.clone()).await {
+                    filter.store(update);
+                }
+                tokio::time::sleep(interval).await;
+            }
+        }))
+    }
+}
+
+#[cfg(feature = "http")]
+async fn fetch(client: &Client, url: Url) -> Option<IpFilter> {
+    let response = client.get(url).send().await.ok()?;
+    let body = response.error_for_status().ok()?.bytes().await.ok()?;
+
+    // Lists run to hundreds of thousands of lines, so keep the parsing off the runtime's threads.
+    tokio::task::spawn_blocking(move || IpFilter::parse(&body))
+        .await
+        .ok()?
+        .ok()
+}
@@ -0,0 +1,27 @@
+pub mod bitfield;
+pub mod connection;
+pub mod download;
+pub mod error;
+pub mod info;
+pub mod ip_filter;
+#[cfg(feature = "net")]
+pub mod listener;
+#[cfg(feature = "net")]
+pub mod net;
+mod optional;
+pub mod peer;
+pub mod peer_id;
+pub mod peer_list;
+pub mod picker;
+pub mod progress;
+pub mod rate_limit;
+#[cfg(feature = "net")]
+pub mod session;
+pub mod slots;
+pub mod storage;
+#[cfg(feature = "streaming")]
+pub mod streaming;
+#[cfg(all(any(test, feature = "testing"), feature = "net"))]
+pub mod testing;
+pub mod tracker;
+pub mod wire;
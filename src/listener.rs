@@ -0,0 +1,168 @@
+use std::{
+    error, fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::RangeInclusive,
+};
+
+use futures::future;
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Ports [`ListenConfig::random_port`] picks from, above the well-known and registered ones.
+pub const RANDOM_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// Random ports tried before falling back to the configured ones.
+const RANDOM_ATTEMPTS: usize = 16;
+
+/// Where to accept incoming peer connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenConfig {
+    pub ip: IpAddr,
+    /// Port to try first. `0` lets the OS pick one.
+    pub port: u16,
+    /// Ports tried in order when `port` can't be bound.
+    pub fallback: RangeInclusive<u16>,
+    /// More addresses to listen on, e.g. `::` next to `0.0.0.0` or a VPN interface, bound to
+    /// whichever port `ip` got.
+    pub additional: Vec<IpAddr>,
+    /// Bind a random port from [`RANDOM_PORTS`] rather than `port`, falling back to `port` and
+    /// `fallback` only if none is free. A session keeps the port it got in its saved state.
+    pub random_port: bool,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 6881,
+            fallback: 6881..=6889,
+            additional: Vec::new(),
+            random_port: false,
+        }
+    }
+}
+
+/// Bound peer listeners, all on the same port. Its [`port`](Self::port) is the one to announce
+/// to trackers.
+#[derive(Debug)]
+pub struct Listener {
+    listeners: Vec<TcpListener>,
+    port: u16,
+}
+
+impl Listener {
+    /// Binds the preferred port, falling back to the first free port in the configured range.
+    /// With [`random_port`](ListenConfig::random_port), random ports are tried first.
+    ///
+    /// Fails with an [`AddrInUse`](io::ErrorKind::AddrInUse) error wrapping [`PortInUse`] if
+    /// none of them can be bound.
+    pub async fn bind(config: &ListenConfig) -> io::Result<Self> {
+        let random = if config.random_port {
+            RANDOM_ATTEMPTS
+        } else {
+            0
+        };
+        let ports = std::iter::repeat_with(|| rand::thread_rng().gen_range(RANDOM_PORTS))
+            .take(random)
+            .chain([config.port])
+            .chain(config.fallback.clone().filter(|&port| port != config.port));
+        // IPv6 sockets would otherwise also take the IPv4 port.
+        let only_v6 = !config.additional.is_empty();
+
+        for port in ports {
+            match bind_all(config, port, only_v6) {
+                Ok(listeners) => {
+                    let port = listeners[0].local_addr()?.port();
+                    return Ok(Self { listeners, port });
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::AddrInUse | io::ErrorKind::PermissionDenied
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            PortInUse {
+                port: config.port,
+                fallback: config.fallback.clone(),
+            },
+        ))
+    }
+
+    /// The port actually bound, which may differ from the preferred one.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The address of the listener bound to [`ListenConfig::ip`].
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
+    }
+
+    /// Accepts a connection on any of the addresses. The stream's `local_addr` tells which
+    /// interface it came in on.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let accepts = self
+            .listeners
+            .iter()
+            .map(|listener| Box::pin(listener.accept()));
+        future::select_all(accepts).await.0
+    }
+}
+
+/// Binds `port` on every configured address, or the port `ip` got from the OS if it's `0`.
+fn bind_all(config: &ListenConfig, port: u16, only_v6: bool) -> io::Result<Vec<TcpListener>> {
+    let first = bind(SocketAddr::new(config.ip, port), only_v6)?;
+    let port = first.local_addr()?.port();
+
+    let mut listeners = vec![first];
+    for &ip in &config.additional {
+        listeners.push(bind(SocketAddr::new(ip, port), only_v6)?);
+    }
+
+    Ok(listeners)
+}
+
+fn bind(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Matches what `TcpListener::bind` does, so restarts don't wait for TIME_WAIT to pass.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+#[derive(Debug)]
+pub struct PortInUse {
+    pub port: u16,
+    pub fallback: RangeInclusive<u16>,
+}
+
+impl fmt::Display for PortInUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no free listen port: {} and {}-{} are all in use",
+            self.port,
+            self.fallback.start(),
+            self.fallback.end()
+        )
+    }
+}
+
+impl error::Error for PortInUse {}
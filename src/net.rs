@@ -0,0 +1,3 @@
+//! Sockets shared between protocols.
+
+pub mod udp;
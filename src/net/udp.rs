@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+/// How many KRPC packets may wait for the DHT before new ones are dropped.
+const KRPC_BACKLOG: usize = 256;
+
+/// One UDP socket shared by UDP trackers and a DHT node, so only one UDP port needs forwarding.
+///
+/// Incoming packets are told apart by format. KRPC messages are bencoded dictionaries and start
+/// with `d`, while UDP tracker responses start with a big-endian action below 4 and go to whoever
+/// waits for their transaction id.
+///
+/// Clones share the socket, which is closed when the last one is dropped.
+#[derive(Debug, Clone)]
+pub struct UdpMux {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    socket: Arc<UdpSocket>,
+    routes: Arc<Routes>,
+    receive: JoinHandle<()>,
+}
+
+/// A packet and who sent it.
+type Datagram = (Vec<u8>, SocketAddr);
+
+#[derive(Debug, Default)]
+struct Routes {
+    transactions: Mutex<HashMap<u32, Waiter>>,
+    krpc: Mutex<Option<mpsc::Sender<Datagram>>>,
+}
+
+/// A transaction waiting for responses from the tracker at `addr`.
+#[derive(Debug)]
+struct Waiter {
+    addr: SocketAddr,
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl UdpMux {
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let routes = Arc::new(Routes::default());
+        let receive = tokio::spawn(receive_loop(socket.clone(), routes.clone()));
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                socket,
+                routes,
+                receive,
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    pub async fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        // A dual-stack IPv6 socket only takes IPv4 destinations in their mapped form.
+        let addr = match addr {
+            SocketAddr::V4(v4) if self.local_addr()?.is_ipv6() => {
+                SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+            }
+            addr => addr,
+        };
+        self.inner.socket.send_to(data, addr).await
+    }
+
+    /// Routes KRPC packets to the returned receiver along with their sender, replacing any
+    /// earlier receiver. Packets arriving while it's full are dropped.
+    pub fn krpc(&self) -> mpsc::Receiver<Datagram> {
+        let (sender, receiver) = mpsc::channel(KRPC_BACKLOG);
+        *self.inner.routes.krpc.lock().unwrap() = Some(sender);
+        receiver
+    }
+
+    /// Routes tracker responses from `addr` carrying `id` to the returned transaction until it's
+    /// dropped.
+    pub fn transaction(&self, id: u32, addr: SocketAddr) -> Transaction {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.inner
+            .routes
+            .transactions
+            .lock()
+            .unwrap()
+            .insert(id, Waiter { addr, sender });
+
+        Transaction {
+            routes: self.inner.routes.clone(),
+            id,
+            receiver,
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.receive.abort();
+    }
+}
+
+/// Responses to one UDP tracker request, see [`UdpMux::transaction`].
+#[derive(Debug)]
+pub struct Transaction {
+    routes: Arc<Routes>,
+    id: u32,
+    receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl Transaction {
+    /// Waits for the next response. Fails once the socket stopped receiving.
+    pub async fn recv(&mut self) -> io::Result<Vec<u8>> {
+        self.receiver
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "UDP socket closed"))
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.routes.transactions.lock().unwrap().remove(&self.id);
+    }
+}
+
+async fn receive_loop(socket: Arc<UdpSocket>, routes: Arc<Routes>) {
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let (len, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            // ICMP errors for earlier sends show up here on some platforms.
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+            Err(_) => break,
+        };
+        let packet = &buffer[..len];
+
+        if packet.first() == Some(&b'd') {
+            if let Some(krpc) = &*routes.krpc.lock().unwrap() {
+                let _ = krpc.try_send((packet.to_vec(), from));
+            }
+            continue;
+        }
+
+        let Some(id) = packet.get(4..8) else {
+            continue;
+        };
+        let id = u32::from_be_bytes(id.try_into().expect("4 bytes"));
+        let transactions = routes.transactions.lock().unwrap();
+        if let Some(waiter) = transactions.get(&id) {
+            if same_endpoint(waiter.addr, from) {
+                let _ = waiter.sender.send(packet.to_vec());
+            }
+        }
+    }
+
+    // Wakes up every waiting transaction with an error.
+    routes.transactions.lock().unwrap().clear();
+}
+
+/// Compares addresses, treating IPv4-mapped IPv6 addresses as the IPv4 ones they map.
+fn same_endpoint(a: SocketAddr, b: SocketAddr) -> bool {
+    a.ip().to_canonical() == b.ip().to_canonical() && a.port() == b.port()
+}
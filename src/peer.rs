@@ -1,14 +1,23 @@
-use std::io;
+use std::{
+    error, fmt, io,
+    net::{IpAddr, SocketAddr},
+};
+
+use bytes::{Buf, BytesMut};
 
-use anyhow::Result;
-use bytes::{Buf, BufMut, BytesMut};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, ToSocketAddrs},
+pub use crate::wire::PROTOCOL_NAME;
+use crate::{
+    peer_id::PeerId,
+    wire::{self, id, FAST_EXTENSION},
 };
-use tokio_util::codec::{Decoder, Encoder, Framed};
 
-#[derive(Debug)]
+#[cfg(feature = "net")]
+pub use self::net::{accept, connect, HandshakeCodec, PeerCodec};
+
+#[cfg(feature = "net")]
+mod net;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PeerMessage {
     KeepAlive,
     Choke,
@@ -20,9 +29,16 @@ pub enum PeerMessage {
     Request(u32, u32, u32),
     Piece(u32, u32, Vec<u8>),
     Cancel(u32, u32, u32),
+    /// Fast extension (BEP 6): the sender has every piece, in place of a bitfield.
+    HaveAll,
+    /// Fast extension: the sender has no pieces, in place of a bitfield.
+    HaveNone,
+    /// Fast extension: the request won't be answered.
+    Reject(u32, u32, u32),
 }
 
-pub struct PeerCodec;
+/// The most buffer space reserved up front for a message that hasn't fully arrived.
+const MAX_RESERVE: usize = 256 * 1024;
 
 macro_rules! read_const_bytes {
     ($src:expr,  $start:expr, $len:expr) => {{
@@ -42,153 +58,503 @@ macro_rules! read_u32 {
     };
 }
 
-impl Encoder<PeerMessage> for PeerCodec {
-    type Error = io::Error;
-
-    fn encode(&mut self, msg: PeerMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let len = match msg {
+impl PeerMessage {
+    /// Bytes the message takes on the wire, length prefix included.
+    pub fn wire_len(&self) -> usize {
+        4 + match self {
             PeerMessage::KeepAlive => 0,
             PeerMessage::Choke => 1,
             PeerMessage::Unchoke => 1,
             PeerMessage::Interested => 1,
             PeerMessage::NotInterested => 1,
             PeerMessage::Have(_) => 1 + 4,
-            PeerMessage::Bitfield(ref bitfield) => 1 + bitfield.len() as u32,
+            PeerMessage::Bitfield(bitfield) => 1 + bitfield.len(),
             PeerMessage::Request(_, _, _) => 13,
-            PeerMessage::Piece(_, _, ref block) => 9 + block.len() as u32,
+            PeerMessage::Piece(_, _, block) => 9 + block.len(),
             PeerMessage::Cancel(_, _, _) => 13,
-        };
-
-        dst.put_u32(len);
-
-        let id = match msg {
-            PeerMessage::KeepAlive => None,
-            PeerMessage::Choke => Some(0),
-            PeerMessage::Unchoke => Some(1),
-            PeerMessage::Interested => Some(2),
-            PeerMessage::NotInterested => Some(3),
-            PeerMessage::Have(_) => Some(4),
-            PeerMessage::Bitfield(_) => Some(5),
-            PeerMessage::Request(_, _, _) => Some(6),
-            PeerMessage::Piece(_, _, _) => Some(7),
-            PeerMessage::Cancel(_, _, _) => Some(8),
-        };
-
-        if let Some(id) = id {
-            dst.put_u8(id);
+            PeerMessage::HaveAll => 1,
+            PeerMessage::HaveNone => 1,
+            PeerMessage::Reject(_, _, _) => 13,
         }
+    }
 
-        match msg {
-            PeerMessage::Have(piece_index) => dst.put_u32(piece_index),
-            PeerMessage::Bitfield(bitfield) => dst.put_slice(&bitfield),
+    /// Appends the message's wire form, length prefix included, to `dst`.
+    pub fn encode(self, dst: &mut BytesMut) {
+        match self {
+            PeerMessage::KeepAlive => wire::put_keep_alive(dst),
+            PeerMessage::Choke => wire::put_empty(dst, id::CHOKE),
+            PeerMessage::Unchoke => wire::put_empty(dst, id::UNCHOKE),
+            PeerMessage::Interested => wire::put_empty(dst, id::INTERESTED),
+            PeerMessage::NotInterested => wire::put_empty(dst, id::NOT_INTERESTED),
+            PeerMessage::Have(piece_index) => wire::put_have(dst, piece_index),
+            PeerMessage::Bitfield(bitfield) => wire::put_bitfield(dst, &bitfield),
             PeerMessage::Request(piece_index, block_index, block_length) => {
-                dst.put_u32(piece_index);
-                dst.put_u32(block_index);
-                dst.put_u32(block_length);
+                wire::put_request(dst, piece_index, block_index, block_length)
             }
-            PeerMessage::Piece(piece_index, block_index, ref block) => {
-                dst.put_u32(piece_index);
-                dst.put_u32(block_index);
-                dst.put_slice(block);
+            PeerMessage::Piece(piece_index, block_index, block) => {
+                wire::put_piece(dst, piece_index, block_index, &block)
             }
             PeerMessage::Cancel(piece_index, block_index, block_length) => {
-                dst.put_u32(piece_index);
-                dst.put_u32(block_index);
-                dst.put_u32(block_length);
+                wire::put_cancel(dst, piece_index, block_index, block_length)
+            }
+            PeerMessage::HaveAll => wire::put_empty(dst, id::HAVE_ALL),
+            PeerMessage::HaveNone => wire::put_empty(dst, id::HAVE_NONE),
+            PeerMessage::Reject(piece_index, block_index, block_length) => {
+                wire::put_reject(dst, piece_index, block_index, block_length)
             }
-            _ => {}
         }
-
-        Ok(())
     }
-}
 
-impl Decoder for PeerCodec {
-    type Item = PeerMessage;
-
-    type Error = io::Error;
-
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+    /// Parses one complete message at the front of `src`, returning it along with the number of
+    /// bytes it took up, or `None` if more bytes are needed.
+    pub fn parse(src: &[u8]) -> Result<Option<(Self, usize)>, WireError> {
         if src.len() < 4 {
-            src.reserve(4 - src.len());
             return Ok(None);
         }
 
-        let mut len = [0; 4];
-        len.copy_from_slice(&src[0..4]);
-        let len = u32::from_be_bytes(len);
-
+        let len = read_u32!(src, 0) as usize;
         if len == 0 {
-            src.advance(4);
-            return Ok(Some(PeerMessage::KeepAlive));
+            return Ok(Some((PeerMessage::KeepAlive, 4)));
         }
-
-        if src.len() < 4 + len as usize {
-            src.reserve(len as usize - src.len());
+        if src.len() < 4 + len {
             return Ok(None);
         }
 
-        let mut id = [0; 1];
-        id.copy_from_slice(&src[4..][..1]);
-        let id = u8::from_be_bytes(id);
+        let id = src[4];
+        let expected = match id {
+            id::CHOKE..=id::NOT_INTERESTED | id::HAVE_ALL | id::HAVE_NONE => Some(1),
+            id::HAVE => Some(5),
+            id::REQUEST | id::CANCEL | id::REJECT => Some(13),
+            id::BITFIELD => None,
+            id::PIECE if len >= 9 => None,
+            id::PIECE => return Err(WireError::Length { id, len }),
+            _ => return Err(WireError::UnknownMessage(id)),
+        };
+        if expected.is_some_and(|expected| expected != len) {
+            return Err(WireError::Length { id, len });
+        }
 
+        let body = &src[5..4 + len];
         let peer_message = match id {
-            0 => PeerMessage::Choke,
-            1 => PeerMessage::Unchoke,
-            2 => PeerMessage::Interested,
-            3 => PeerMessage::NotInterested,
-            4 => PeerMessage::Have(read_u32!(src, 5)),
-            5 => {
-                let bitfield_length = len - 1;
-                let bitfield = src[5..][..bitfield_length as usize].to_vec();
-                PeerMessage::Bitfield(bitfield)
-            }
-            6 => PeerMessage::Request(read_u32!(src, 5), read_u32!(src, 9), read_u32!(src, 13)),
-            7 => {
-                let block_length = len - 9;
-                PeerMessage::Piece(
-                    read_u32!(src, 5),
-                    read_u32!(src, 9),
-                    src[13..][..block_length as usize].to_vec(),
-                )
-            }
-            8 => PeerMessage::Cancel(read_u32!(src, 5), read_u32!(src, 9), read_u32!(src, 13)),
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("only peer message 'keep-alive' recognized. got id of {id}"),
-                ))
+            id::CHOKE => PeerMessage::Choke,
+            id::UNCHOKE => PeerMessage::Unchoke,
+            id::INTERESTED => PeerMessage::Interested,
+            id::NOT_INTERESTED => PeerMessage::NotInterested,
+            id::HAVE => PeerMessage::Have(read_u32!(body, 0)),
+            id::BITFIELD => PeerMessage::Bitfield(body.to_vec()),
+            id::REQUEST => {
+                PeerMessage::Request(read_u32!(body, 0), read_u32!(body, 4), read_u32!(body, 8))
+            }
+            id::PIECE => {
+                PeerMessage::Piece(read_u32!(body, 0), read_u32!(body, 4), body[8..].to_vec())
+            }
+            id::CANCEL => {
+                PeerMessage::Cancel(read_u32!(body, 0), read_u32!(body, 4), read_u32!(body, 8))
             }
+            id::HAVE_ALL => PeerMessage::HaveAll,
+            id::HAVE_NONE => PeerMessage::HaveNone,
+            _ => PeerMessage::Reject(read_u32!(body, 0), read_u32!(body, 4), read_u32!(body, 8)),
         };
 
-        src.advance(4 + len as usize);
+        Ok(Some((peer_message, 4 + len)))
+    }
+
+    /// Takes one complete message off the front of `src`, or returns `None` if more bytes are
+    /// needed.
+    pub fn decode(src: &mut BytesMut) -> io::Result<Option<Self>> {
+        match Self::parse(src) {
+            Ok(Some((message, len))) => {
+                src.advance(len);
+                Ok(Some(message))
+            }
+            Ok(None) => {
+                if src.len() >= 4 {
+                    // The length is the peer's claim, so don't allocate ahead for more than a
+                    // large message.
+                    let len = read_u32!(src, 0) as usize;
+                    src.reserve((4 + len - src.len()).min(MAX_RESERVE));
+                }
+                Ok(None)
+            }
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// A malformed peer wire message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    UnknownMessage(u8),
+    /// A message whose length doesn't fit its id.
+    Length {
+        id: u8,
+        len: usize,
+    },
+    /// A handshake for a protocol other than BitTorrent.
+    Protocol,
+}
 
-        Ok(Some(peer_message))
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::UnknownMessage(id) => write!(f, "unknown peer message id {id}"),
+            WireError::Length { id, len } => {
+                write!(f, "peer message {id} has invalid length {len}")
+            }
+            WireError::Protocol => write!(f, "peer doesn't speak the BitTorrent protocol"),
+        }
     }
 }
 
-pub async fn connect(
+impl error::Error for WireError {}
+
+/// Reserved handshake bits we send, advertising the extensions we support.
+pub const RESERVED: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, FAST_EXTENSION];
+
+/// The handshake both sides send before any other peer message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    reserved: [u8; 8],
     info_hash: [u8; 20],
-    peer_id: [u8; 20],
-    addr: impl ToSocketAddrs,
-) -> Result<Framed<TcpStream, PeerCodec>> {
-    let mut stream = TcpStream::connect(addr).await?;
-    stream.write_u8(19).await?;
-    stream.write_all(b"BitTorrent protocol").await?;
-    stream.write_u64(0).await?;
-    stream.write_all(&info_hash).await?;
-    stream.write_all(&peer_id).await?;
-
-    let mut handshake_recv = [0; 68];
-    stream.read_exact(&mut handshake_recv).await?;
-
-    assert_eq!(handshake_recv[0], 19);
-    assert_eq!(&handshake_recv[1..20], b"BitTorrent protocol");
-    // Reserved bits
-    // assert_eq!(&handshake_recv[20..28], &[0, 0, 0, 0, 0, 0, 0, 0]);
-    assert_eq!(&handshake_recv[28..48], &info_hash);
-
-    let framed = Framed::new(stream, PeerCodec);
-
-    Ok(framed)
+    peer_id: PeerId,
+}
+
+impl Handshake {
+    /// Size of a handshake on the wire.
+    pub const LEN: usize = wire::HANDSHAKE_LEN;
+
+    pub fn new(info_hash: [u8; 20], peer_id: PeerId, reserved: [u8; 8]) -> Self {
+        Self {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Extension bits advertised by the sender.
+    pub fn reserved(&self) -> [u8; 8] {
+        self.reserved
+    }
+
+    /// Whether the sender supports the Fast extension. It's in use only if both sides do.
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & FAST_EXTENSION != 0
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Appends the handshake's wire form to `dst`.
+    pub fn encode(self, dst: &mut BytesMut) {
+        wire::put_handshake(dst, self.reserved, self.info_hash, *self.peer_id.as_bytes());
+    }
+
+    /// Parses a complete handshake at the front of `src`, or returns `None` if more bytes are
+    /// needed. A handshake always takes up [`Handshake::LEN`] bytes.
+    pub fn parse(src: &[u8]) -> Result<Option<Self>, WireError> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        // Reject other protocols as soon as possible rather than waiting for a full handshake.
+        let name = &src[1..src.len().min(1 + PROTOCOL_NAME.len())];
+        if src[0] as usize != PROTOCOL_NAME.len() || !PROTOCOL_NAME.starts_with(name) {
+            return Err(WireError::Protocol);
+        }
+
+        if src.len() < Self::LEN {
+            return Ok(None);
+        }
+
+        Ok(Some(Handshake {
+            reserved: read_const_bytes!(src, wire::RESERVED_OFFSET, 8),
+            info_hash: read_const_bytes!(src, wire::INFO_HASH_OFFSET, 20),
+            peer_id: read_const_bytes!(src, wire::PEER_ID_OFFSET, 20, PeerId::from),
+        }))
+    }
+
+    /// Takes a complete handshake off the front of `src`, or returns `None` if more bytes are
+    /// needed.
+    pub fn decode(src: &mut BytesMut) -> io::Result<Option<Self>> {
+        match Self::parse(src) {
+            Ok(Some(handshake)) => {
+                src.advance(Self::LEN);
+                Ok(Some(handshake))
+            }
+            Ok(None) => {
+                src.reserve(Self::LEN - src.len());
+                Ok(None)
+            }
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// Canonical peer priority from BEP 40, used to decide which peers to connect to first.
+///
+/// Both sides of a connection compute the same value, so swarms converge on the same set of
+/// connections. Addresses of different families get the lowest priority.
+pub fn canonical_priority(ours: SocketAddr, theirs: SocketAddr) -> u32 {
+    let (a, b) = if ours <= theirs {
+        (ours, theirs)
+    } else {
+        (theirs, ours)
+    };
+
+    if a.ip() == b.ip() {
+        let mut ports = [0; 4];
+        ports[..2].copy_from_slice(&a.port().to_be_bytes());
+        ports[2..].copy_from_slice(&b.port().to_be_bytes());
+        return crc32c(&ports);
+    }
+
+    match (a.ip(), b.ip()) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let (mut a, mut b) = (a.octets(), b.octets());
+            let mask = canonical_mask(&a, &b, 2, [0xff, 0xff, 0x55, 0x55]);
+            a.iter_mut().zip(&mask).for_each(|(x, m)| *x &= m);
+            b.iter_mut().zip(&mask).for_each(|(x, m)| *x &= m);
+            crc32c(&[a, b].concat())
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            let (mut a, mut b) = (a.octets(), b.octets());
+            let mut base = [0x55; 16];
+            base[..6].fill(0xff);
+            let mask = canonical_mask(&a, &b, 6, base);
+            a.iter_mut().zip(&mask).for_each(|(x, m)| *x &= m);
+            b.iter_mut().zip(&mask).for_each(|(x, m)| *x &= m);
+            crc32c(&[a, b].concat())
+        }
+        _ => 0,
+    }
+}
+
+/// Orders candidate peers by descending canonical priority relative to our address of the same
+/// family in `ours`. Peers of a family we have no address for go last, in their original order.
+pub fn sort_by_priority(ours: &[SocketAddr], peers: &mut [SocketAddr]) {
+    peers.sort_by_cached_key(|&peer| {
+        let ours = ours.iter().find(|ours| ours.is_ipv6() == peer.is_ipv6());
+        std::cmp::Reverse(ours.map_or(0, |&ours| canonical_priority(ours, peer)))
+    });
+}
+
+/// Keeps up to two more bytes of `base` in full when the addresses share a longer prefix.
+fn canonical_mask<const N: usize>(a: &[u8], b: &[u8], prefix: usize, base: [u8; N]) -> [u8; N] {
+    let mut mask = base;
+    for i in prefix..prefix + 2 {
+        if a[..i] != b[..i] {
+            break;
+        }
+        mask[i] = 0xff;
+    }
+    mask
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::{collection::vec, prelude::*};
+
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [0xaa; 20];
+
+    fn wire(reserved: [u8; 8], info_hash: [u8; 20], peer_id: [u8; 20]) -> Vec<u8> {
+        let mut bytes = vec![19];
+        bytes.extend_from_slice(b"BitTorrent protocol");
+        bytes.extend_from_slice(&reserved);
+        bytes.extend_from_slice(&info_hash);
+        bytes.extend_from_slice(&peer_id);
+        bytes
+    }
+
+    #[test]
+    fn protocol_name_matches_length_prefix() {
+        assert_eq!(PROTOCOL_NAME.len(), 19);
+        assert_eq!(PROTOCOL_NAME, b"BitTorrent protocol");
+    }
+
+    #[test]
+    fn encodes_handshake() {
+        let handshake = Handshake::new(
+            INFO_HASH,
+            PeerId::from([1; 20]),
+            [0, 0, 0, 0, 0, 0x10, 0, 1],
+        );
+
+        let mut dst = BytesMut::new();
+        handshake.encode(&mut dst);
+
+        assert_eq!(
+            &dst[..],
+            wire([0, 0, 0, 0, 0, 0x10, 0, 1], INFO_HASH, [1; 20])
+        );
+    }
+
+    #[test]
+    fn round_trips_handshake() {
+        let handshake = Handshake::new(INFO_HASH, PeerId::generate(), [0xff; 8]);
+
+        let mut buf = BytesMut::new();
+        handshake.encode(&mut buf);
+        let decoded = Handshake::decode(&mut buf).unwrap();
+
+        assert_eq!(decoded, Some(handshake));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decodes_partial_handshake() {
+        let bytes = wire([0; 8], INFO_HASH, [2; 20]);
+        let mut buf = BytesMut::from(&bytes[..40]);
+
+        assert_eq!(Handshake::decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(&bytes[40..]);
+        buf.extend_from_slice(&[0, 0, 0, 1, 2]);
+        let handshake = Handshake::decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(handshake.peer_id(), PeerId::from([2; 20]));
+        assert_eq!(&buf[..], [0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        let mut bytes = wire([0; 8], INFO_HASH, [0; 20]);
+        bytes[1..20].copy_from_slice(b"BitTorrentprotocol!");
+        assert!(Handshake::decode(&mut BytesMut::from(&bytes[..])).is_err());
+
+        let mut bytes = wire([0; 8], INFO_HASH, [0; 20]);
+        bytes[0] = 18;
+        assert!(Handshake::decode(&mut BytesMut::from(&bytes[..])).is_err());
+    }
+
+    #[test]
+    fn sorts_candidates_by_canonical_priority() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let ours = addr("123.213.32.10:6881");
+        // The examples from BEP 40.
+        assert_eq!(
+            canonical_priority(ours, addr("98.76.54.32:6881")),
+            0xec2d_7224
+        );
+        assert_eq!(
+            canonical_priority(ours, addr("123.213.32.234:6881")),
+            0x9956_8189
+        );
+
+        let mut peers = [
+            addr("[2001:db8::1]:6881"),
+            addr("123.213.32.234:6881"),
+            addr("98.76.54.32:6881"),
+        ];
+        sort_by_priority(&[ours], &mut peers);
+
+        assert_eq!(
+            peers,
+            [
+                addr("98.76.54.32:6881"),
+                addr("123.213.32.234:6881"),
+                addr("[2001:db8::1]:6881"),
+            ]
+        );
+    }
+
+    fn peer_message() -> impl Strategy<Value = PeerMessage> {
+        let block = || (any::<u32>(), any::<u32>(), any::<u32>());
+        prop_oneof![
+            Just(PeerMessage::KeepAlive),
+            Just(PeerMessage::Choke),
+            Just(PeerMessage::Unchoke),
+            Just(PeerMessage::Interested),
+            Just(PeerMessage::NotInterested),
+            any::<u32>().prop_map(PeerMessage::Have),
+            vec(any::<u8>(), 0..64).prop_map(PeerMessage::Bitfield),
+            block().prop_map(|(index, begin, len)| PeerMessage::Request(index, begin, len)),
+            (any::<u32>(), any::<u32>(), vec(any::<u8>(), 0..64))
+                .prop_map(|(index, begin, data)| PeerMessage::Piece(index, begin, data)),
+            block().prop_map(|(index, begin, len)| PeerMessage::Cancel(index, begin, len)),
+            Just(PeerMessage::HaveAll),
+            Just(PeerMessage::HaveNone),
+            block().prop_map(|(index, begin, len)| PeerMessage::Reject(index, begin, len)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_peer_messages(messages in vec(peer_message(), 0..8), split in any::<usize>()) {
+            let mut wire = BytesMut::new();
+            for message in messages.clone() {
+                message.encode(&mut wire);
+            }
+
+            // Bytes arrive in two arbitrary chunks, as they might off a socket.
+            let split = split % (wire.len() + 1);
+            let mut buf = BytesMut::from(&wire[..split]);
+            let mut decoded = Vec::new();
+            while let Some(message) = PeerMessage::decode(&mut buf).unwrap() {
+                decoded.push(message);
+            }
+            buf.extend_from_slice(&wire[split..]);
+            while let Some(message) = PeerMessage::decode(&mut buf).unwrap() {
+                decoded.push(message);
+            }
+
+            prop_assert_eq!(decoded, messages);
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn parses_arbitrary_bytes(bytes in vec(any::<u8>(), 0..256)) {
+            if let Ok(Some((message, len))) = PeerMessage::parse(&bytes) {
+                prop_assert!(len <= bytes.len());
+                let mut wire = BytesMut::new();
+                message.encode(&mut wire);
+                prop_assert_eq!(&wire[..], &bytes[..len]);
+            }
+        }
+
+        #[test]
+        fn decodes_mutated_handshakes(
+            mutations in vec((any::<prop::sample::Index>(), any::<u8>()), 0..4),
+            len in 0..=Handshake::LEN,
+        ) {
+            let mut bytes = wire(RESERVED, INFO_HASH, [7; 20]);
+            for (index, byte) in mutations {
+                bytes[index.index(Handshake::LEN)] = byte;
+            }
+            bytes.truncate(len);
+
+            // Only the protocol name is checked, and as soon as it has arrived.
+            let header = &wire(RESERVED, INFO_HASH, [7; 20])[..20];
+            let checked = bytes.len().min(header.len());
+            match Handshake::parse(&bytes) {
+                Ok(Some(handshake)) => {
+                    let mut wire = BytesMut::new();
+                    handshake.encode(&mut wire);
+                    prop_assert_eq!(&wire[..], &bytes[..]);
+                }
+                Ok(None) => {
+                    prop_assert!(bytes.len() < Handshake::LEN);
+                    prop_assert_eq!(&bytes[..checked], &header[..checked]);
+                }
+                Err(_) => prop_assert_ne!(&bytes[..checked], &header[..checked]),
+            }
+        }
+    }
 }
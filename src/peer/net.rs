@@ -0,0 +1,204 @@
+use std::io;
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::{error::HandshakeError, peer_id::PeerId};
+
+use super::{Handshake, PeerMessage, RESERVED};
+
+pub struct PeerCodec;
+
+impl Encoder<PeerMessage> for PeerCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: PeerMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        msg.encode(dst);
+        Ok(())
+    }
+}
+
+impl Decoder for PeerCodec {
+    type Item = PeerMessage;
+
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        PeerMessage::decode(src)
+    }
+}
+
+pub struct HandshakeCodec;
+
+impl Encoder<Handshake> for HandshakeCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, handshake: Handshake, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        handshake.encode(dst);
+        Ok(())
+    }
+}
+
+impl Decoder for HandshakeCodec {
+    type Item = Handshake;
+
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        Handshake::decode(src)
+    }
+}
+
+/// Connects to a peer and exchanges handshakes, returning the connection ready for peer messages
+/// along with the handshake the peer sent.
+pub async fn connect(
+    info_hash: [u8; 20],
+    peer_id: PeerId,
+    addr: impl ToSocketAddrs,
+) -> Result<(Framed<TcpStream, PeerCodec>, Handshake), HandshakeError> {
+    let stream = TcpStream::connect(addr).await?;
+    let mut framed = Framed::new(stream, HandshakeCodec);
+
+    framed
+        .send(Handshake::new(info_hash, peer_id, RESERVED))
+        .await?;
+
+    let remote = receive_handshake(&mut framed).await?;
+    if remote.info_hash() != info_hash {
+        return Err(HandshakeError::InfoHashMismatch);
+    }
+
+    // Anything the peer sent right after its handshake stays buffered for the message codec.
+    Ok((framed.map_codec(|_| PeerCodec), remote))
+}
+
+/// Answers the handshake on a connection taken from the listener.
+///
+/// `serves` decides whether we have the torrent the peer asked for. The connection is dropped
+/// without answering if we don't.
+pub async fn accept(
+    stream: TcpStream,
+    peer_id: PeerId,
+    serves: impl FnOnce([u8; 20]) -> bool,
+) -> Result<(Framed<TcpStream, PeerCodec>, Handshake), HandshakeError> {
+    let mut framed = Framed::new(stream, HandshakeCodec);
+
+    let remote = receive_handshake(&mut framed).await?;
+    if !serves(remote.info_hash()) {
+        return Err(HandshakeError::UnknownTorrent);
+    }
+
+    framed
+        .send(Handshake::new(remote.info_hash(), peer_id, RESERVED))
+        .await?;
+
+    Ok((framed.map_codec(|_| PeerCodec), remote))
+}
+
+async fn receive_handshake(
+    framed: &mut Framed<TcpStream, HandshakeCodec>,
+) -> Result<Handshake, HandshakeError> {
+    framed
+        .next()
+        .await
+        .transpose()?
+        .ok_or(HandshakeError::Closed)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [0xaa; 20];
+
+    fn wire(reserved: [u8; 8], info_hash: [u8; 20], peer_id: [u8; 20]) -> Vec<u8> {
+        let mut bytes = vec![19];
+        bytes.extend_from_slice(b"BitTorrent protocol");
+        bytes.extend_from_slice(&reserved);
+        bytes.extend_from_slice(&info_hash);
+        bytes.extend_from_slice(&peer_id);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn connect_interoperates_with_raw_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let remote = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = [0; 68];
+            stream.read_exact(&mut received).await.unwrap();
+
+            let mut reply = wire([0; 8], INFO_HASH, [3; 20]);
+            // A message sent in the same segment as the handshake must not be lost.
+            reply.extend_from_slice(&[0, 0, 0, 1, 1]);
+            stream.write_all(&reply).await.unwrap();
+
+            received
+        });
+
+        let (mut framed, handshake) = connect(INFO_HASH, PeerId::from([4; 20]), addr)
+            .await
+            .unwrap();
+
+        assert_eq!(handshake.peer_id(), PeerId::from([3; 20]));
+        assert_eq!(
+            remote.await.unwrap(),
+            wire(RESERVED, INFO_HASH, [4; 20])[..]
+        );
+        assert!(matches!(
+            framed.next().await.unwrap().unwrap(),
+            PeerMessage::Unchoke
+        ));
+    }
+
+    #[tokio::test]
+    async fn accept_answers_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept(stream, PeerId::from([5; 20]), |info_hash| {
+                info_hash == INFO_HASH
+            })
+            .await
+        });
+
+        let (_, accepted) = connect(INFO_HASH, PeerId::from([6; 20]), addr)
+            .await
+            .unwrap();
+        let (_, connected) = server.await.unwrap().unwrap();
+
+        assert_eq!(accepted.peer_id(), PeerId::from([5; 20]));
+        assert_eq!(connected.peer_id(), PeerId::from([6; 20]));
+    }
+
+    #[tokio::test]
+    async fn connect_rejects_wrong_torrent() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = [0; 68];
+            stream.read_exact(&mut received).await.unwrap();
+            stream
+                .write_all(&wire([0; 8], [0xbb; 20], [3; 20]))
+                .await
+                .unwrap();
+        });
+
+        assert!(connect(INFO_HASH, PeerId::from([4; 20]), addr)
+            .await
+            .is_err());
+    }
+}
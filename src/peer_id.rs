@@ -0,0 +1,107 @@
+use std::fmt;
+
+use rand::{thread_rng, RngCore};
+
+/// The 20 byte id identifying us to trackers and peers.
+///
+/// Generate one per session and use it everywhere, as some trackers reject clients whose id
+/// changes between announces.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId([u8; 20]);
+
+impl PeerId {
+    /// Generates an Azureus-style id for torrant, `-TAxyz0-` followed by random bytes.
+    pub fn generate() -> Self {
+        let version = [
+            version_digit(env!("CARGO_PKG_VERSION_MAJOR")),
+            version_digit(env!("CARGO_PKG_VERSION_MINOR")),
+            version_digit(env!("CARGO_PKG_VERSION_PATCH")),
+            b'0',
+        ];
+
+        Self::azureus(*b"TA", version)
+    }
+
+    /// Generates an Azureus-style id, `-` + client code + version + `-` followed by random bytes.
+    pub fn azureus(client: [u8; 2], version: [u8; 4]) -> Self {
+        let mut id = [0; 20];
+        id[0] = b'-';
+        id[1..3].copy_from_slice(&client);
+        id[3..7].copy_from_slice(&version);
+        id[7] = b'-';
+        thread_rng().fill_bytes(&mut id[8..]);
+
+        Self(id)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// Client code and version of an Azureus-style id.
+    pub fn azureus_client(&self) -> Option<(&str, &str)> {
+        if self.0[0] != b'-' || self.0[7] != b'-' {
+            return None;
+        }
+
+        let client = std::str::from_utf8(&self.0[1..3]).ok()?;
+        let version = std::str::from_utf8(&self.0[3..7]).ok()?;
+        Some((client, version))
+    }
+
+    /// Name and version of the client that generated an Azureus-style id, e.g. `qBittorrent 4.5.2`.
+    ///
+    /// Unknown client codes are shown as they are.
+    pub fn client(&self) -> Option<String> {
+        let (code, version) = self.azureus_client()?;
+        let name = match code {
+            "AZ" => "Vuze",
+            "BI" => "BiglyBT",
+            "BT" => "BitTorrent",
+            "DE" => "Deluge",
+            "FD" => "Free Download Manager",
+            "LT" => "libtorrent",
+            "lt" => "libTorrent",
+            "qB" => "qBittorrent",
+            "TA" => "torrant",
+            "TR" => "Transmission",
+            "UT" => "\u{b5}Torrent",
+            "UW" => "\u{b5}Torrent Web",
+            "WW" => "WebTorrent",
+            code => code,
+        };
+
+        // Trailing zero digits are usually padding, but keep at least major.minor.
+        let mut digits = version.chars().collect::<Vec<_>>();
+        while digits.len() > 2 && digits.last() == Some(&'0') {
+            digits.pop();
+        }
+        let version = digits
+            .iter()
+            .map(char::to_string)
+            .collect::<Vec<_>>()
+            .join(".");
+
+        Some(format!("{name} {version}"))
+    }
+}
+
+impl From<[u8; 20]> for PeerId {
+    fn from(id: [u8; 20]) -> Self {
+        Self(id)
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PeerId(\"{}\")", self.0.escape_ascii())
+    }
+}
+
+fn version_digit(part: &str) -> u8 {
+    match part.parse::<u8>() {
+        Ok(n @ 0..=9) => b'0' + n,
+        Ok(n @ 10..=35) => b'A' + n - 10,
+        _ => b'Z',
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::Instant,
+};
+
+/// Default cap on the peers kept per torrent, plenty to fill every connection slot many times over.
+pub const MAX_PEERS: usize = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    Tracker,
+    Pex,
+    Dht,
+    /// Local Service Discovery (BEP 14).
+    Lsd,
+    Incoming,
+}
+
+/// A newly discovered peer and where it came from, see [`Download::discover`].
+///
+/// [`Download::discover`]: crate::download::Download::discover
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCandidate {
+    pub addr: SocketAddr,
+    pub source: PeerSource,
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    addr: SocketAddr,
+    sources: Vec<PeerSource>,
+    last_seen: Instant,
+    connected: bool,
+    listening: bool,
+    interface: Option<SocketAddr>,
+}
+
+impl Candidate {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn sources(&self) -> &[PeerSource] {
+        &self.sources
+    }
+
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Whether the peer listens on [`addr`](Self::addr). A peer only ever seen connecting in is
+    /// known by the source port of that connection, which can't be dialed.
+    pub fn is_listening(&self) -> bool {
+        self.listening
+    }
+
+    /// Our local address the peer last connected in through, for multi-homed hosts.
+    pub fn interface(&self) -> Option<SocketAddr> {
+        self.interface
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Insert {
+    New,
+    /// The peer was known under another port, which has been replaced.
+    PortChanged(u16),
+    Refreshed,
+    /// The list is full of peers we're connected to, so the new one wasn't stored.
+    Full,
+}
+
+/// Known peers of a torrent, keyed by IP so the same peer learned from several sources or under
+/// several ports is only dialed once.
+#[derive(Debug, Default)]
+pub struct PeerList {
+    peers: HashMap<IpAddr, Candidate>,
+    max_len: Option<usize>,
+}
+
+impl PeerList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps at most `max_len` peers, making room for new ones by forgetting the unconnected
+    /// peer seen least recently.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    pub fn set_max_len(&mut self, max_len: Option<usize>) {
+        self.max_len = max_len;
+        while self.is_over(0) && self.evict() {}
+    }
+
+    fn is_over(&self, adding: usize) -> bool {
+        self.max_len
+            .is_some_and(|max_len| self.peers.len() + adding > max_len)
+    }
+
+    /// Forgets the unconnected peer seen least recently, if there is one.
+    fn evict(&mut self) -> bool {
+        let oldest = self
+            .peers
+            .values()
+            .filter(|candidate| !candidate.connected)
+            .min_by_key(|candidate| candidate.last_seen)
+            .map(|candidate| candidate.addr.ip());
+
+        match oldest {
+            Some(ip) => self.peers.remove(&ip).is_some(),
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Records a sighting of `addr`, preferring the most recently advertised port for its IP.
+    ///
+    /// An [`Incoming`](PeerSource::Incoming) peer's port is where it connected from, so it never
+    /// replaces an advertised one.
+    pub fn insert(&mut self, addr: SocketAddr, source: PeerSource) -> Insert {
+        let now = Instant::now();
+
+        let Some(candidate) = self.peers.get_mut(&addr.ip()) else {
+            if self.is_over(1) && !self.evict() {
+                return Insert::Full;
+            }
+            self.peers.insert(
+                addr.ip(),
+                Candidate {
+                    addr,
+                    sources: vec![source],
+                    last_seen: now,
+                    connected: false,
+                    listening: source != PeerSource::Incoming,
+                    interface: None,
+                },
+            );
+            return Insert::New;
+        };
+
+        if !candidate.sources.contains(&source) {
+            candidate.sources.push(source);
+        }
+        candidate.last_seen = now;
+
+        if source == PeerSource::Incoming || candidate.addr.port() == addr.port() {
+            candidate.listening |= source != PeerSource::Incoming;
+            return Insert::Refreshed;
+        }
+
+        // A connection we dialed already tells us the right port, so don't let stale
+        // advertisements move it.
+        if candidate.connected && candidate.listening {
+            return Insert::Refreshed;
+        }
+
+        let old = candidate.addr.port();
+        candidate.addr = addr;
+        candidate.listening = true;
+        if candidate.connected {
+            // Connected in, so it's no candidate to dial until that connection closes.
+            return Insert::Refreshed;
+        }
+        Insert::PortChanged(old)
+    }
+
+    /// Records that the peer at `addr` connected in through our local address `interface`.
+    pub fn set_interface(&mut self, addr: SocketAddr, interface: SocketAddr) {
+        if let Some(candidate) = self.peers.get_mut(&addr.ip()) {
+            candidate.interface = Some(interface);
+        }
+    }
+
+    pub fn get(&self, ip: IpAddr) -> Option<&Candidate> {
+        self.peers.get(&ip)
+    }
+
+    pub fn remove(&mut self, ip: IpAddr) -> Option<Candidate> {
+        self.peers.remove(&ip)
+    }
+
+    /// Marks whether we currently have a connection to the peer at `addr`.
+    ///
+    /// The port stays as it was: a connection the peer made to us comes from a port it doesn't
+    /// listen on.
+    pub fn set_connected(&mut self, addr: SocketAddr, connected: bool) {
+        if let Some(candidate) = self.peers.get_mut(&addr.ip()) {
+            candidate.connected = connected;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Candidate> {
+        self.peers.values()
+    }
+
+    /// Addresses of known peers we aren't connected to, where they listen.
+    pub fn dialable(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.peers
+            .values()
+            .filter(|candidate| !candidate.connected && candidate.listening)
+            .map(|candidate| candidate.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incoming_port_is_never_dialed() {
+        let advertised = "10.0.0.1:6881".parse().unwrap();
+        let ephemeral = "10.0.0.1:52000".parse().unwrap();
+
+        let mut peers = PeerList::new();
+        peers.insert(advertised, PeerSource::Tracker);
+        peers.insert(ephemeral, PeerSource::Incoming);
+        peers.set_connected(ephemeral, true);
+        peers.set_connected(ephemeral, false);
+        assert_eq!(peers.dialable().collect::<Vec<_>>(), [advertised]);
+
+        let other = "10.0.0.2:53000".parse().unwrap();
+        let mut peers = PeerList::new();
+        peers.insert(other, PeerSource::Incoming);
+        assert_eq!(peers.dialable().count(), 0);
+
+        let listening = "10.0.0.2:6881".parse().unwrap();
+        assert_eq!(
+            peers.insert(listening, PeerSource::Pex),
+            Insert::PortChanged(53000)
+        );
+        assert_eq!(peers.dialable().collect::<Vec<_>>(), [listening]);
+    }
+}
@@ -0,0 +1,475 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use crate::bitfield::Bitfield;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod sim;
+
+/// The block size nearly every client requests, and the default for ours.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/// The longest request served by default, matching libtorrent.
+pub const MAX_REQUEST: usize = 128 * 1024;
+
+/// A block request as it appears on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    Requested,
+    Received,
+}
+
+/// Blocks of a torrent by how far along they are, see [`Picker::block_counts`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockCounts {
+    /// Not requested from any peer yet.
+    pub queued: usize,
+    /// Requested and not received yet.
+    pub in_flight: usize,
+    /// Received, waiting for the rest of their piece to be verified.
+    pub unverified: usize,
+    /// Part of a verified piece.
+    pub verified: usize,
+}
+
+/// Decides which blocks to request next, making sure no block is outstanding twice.
+#[derive(Debug)]
+pub struct Picker {
+    piece_length: usize,
+    length: usize,
+    num_pieces: usize,
+    block_size: usize,
+    in_progress: HashMap<u32, Vec<BlockState>>,
+    /// Pieces picked ahead of the rest, counted per caller that asked for them.
+    priority: BTreeMap<u32, usize>,
+    locality: bool,
+    last_written: Option<u32>,
+}
+
+impl Picker {
+    pub fn new(piece_length: usize, length: usize) -> Self {
+        Self {
+            piece_length,
+            length,
+            num_pieces: length.div_ceil(piece_length),
+            block_size: BLOCK_SIZE.min(piece_length),
+            in_progress: HashMap::new(),
+            priority: BTreeMap::new(),
+            locality: false,
+            last_written: None,
+        }
+    }
+
+    /// Requests blocks of `size` bytes instead of [`BLOCK_SIZE`], clamped to the piece length.
+    ///
+    /// Blocks never cross a piece boundary, so the last block of a piece may be shorter.
+    pub fn with_block_size(mut self, size: usize) -> Self {
+        self.block_size = size.clamp(1, self.piece_length);
+        self
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Picks the pieces nearest the one last [`written`](Self::written) first instead of going
+    /// in piece order, so a spinning disk seeks less.
+    pub fn with_locality(mut self, locality: bool) -> Self {
+        self.locality = locality;
+        self
+    }
+
+    pub fn locality(&self) -> bool {
+        self.locality
+    }
+
+    /// Records that a piece was written to the disk.
+    pub fn written(&mut self, piece: u32) {
+        self.last_written = Some(piece);
+    }
+
+    fn piece_size(&self, piece: u32) -> usize {
+        let start = piece as usize * self.piece_length;
+        self.piece_length.min(self.length.saturating_sub(start))
+    }
+
+    fn block(&self, piece: u32, index: usize) -> Block {
+        let begin = index * self.block_size;
+        Block {
+            piece,
+            begin: begin as u32,
+            length: self.block_size.min(self.piece_size(piece) - begin) as u32,
+        }
+    }
+
+    fn block_index(&self, block: Block) -> Option<usize> {
+        let begin = block.begin as usize;
+        let index = begin / self.block_size;
+        (begin.is_multiple_of(self.block_size)
+            && (block.piece as usize) < self.num_pieces
+            && begin < self.piece_size(block.piece)
+            && self.block(block.piece, index) == block)
+            .then_some(index)
+    }
+
+    /// Picks the next block to request from a peer that has `available`, in piece order with
+    /// prioritized pieces first. With [`locality`](Self::with_locality), the pieces nearest the
+    /// last one written come first, the next one ahead before the one behind.
+    pub fn pick(&mut self, available: &Bitfield, verified: &Bitfield) -> Option<Block> {
+        let priority = self.priority.keys().copied().collect::<Vec<_>>();
+        let pieces = self.num_pieces as u32;
+        let order: Box<dyn Iterator<Item = u32>> = match self.last_written.filter(|_| self.locality)
+        {
+            Some(center) => Box::new((0..pieces).flat_map(move |distance| {
+                let behind = center.checked_sub(distance).filter(|_| distance > 0);
+                [center.checked_add(distance), behind]
+                    .into_iter()
+                    .flatten()
+                    .filter(move |&piece| piece < pieces)
+            })),
+            None => Box::new(0..pieces),
+        };
+
+        priority
+            .into_iter()
+            .chain(order)
+            .find_map(|piece| self.pick_in(piece, available, verified))
+    }
+
+    fn pick_in(&mut self, piece: u32, available: &Bitfield, verified: &Bitfield) -> Option<Block> {
+        if verified.get(piece as usize) || !available.get(piece as usize) {
+            return None;
+        }
+
+        let blocks = self.piece_size(piece).div_ceil(self.block_size);
+        let states = self
+            .in_progress
+            .entry(piece)
+            .or_insert_with(|| vec![BlockState::Missing; blocks]);
+
+        let index = states.iter().position(|&s| s == BlockState::Missing)?;
+        states[index] = BlockState::Requested;
+        Some(self.block(piece, index))
+    }
+
+    /// Picks `piece` ahead of unprioritized ones until a matching [`unprioritize`] call.
+    ///
+    /// [`unprioritize`]: Self::unprioritize
+    pub fn prioritize(&mut self, piece: u32) {
+        if (piece as usize) < self.num_pieces {
+            *self.priority.entry(piece).or_default() += 1;
+        }
+    }
+
+    pub fn unprioritize(&mut self, piece: u32) {
+        if let Some(count) = self.priority.get_mut(&piece) {
+            *count -= 1;
+            if *count == 0 {
+                self.priority.remove(&piece);
+            }
+        }
+    }
+
+    /// Marks a requested block as received.
+    ///
+    /// Returns `None` if the block was never requested, otherwise whether its piece now has every
+    /// block.
+    pub fn received(&mut self, block: Block) -> Option<bool> {
+        let index = self.block_index(block)?;
+        let states = self.in_progress.get_mut(&block.piece)?;
+
+        if states[index] != BlockState::Requested {
+            return None;
+        }
+        states[index] = BlockState::Received;
+
+        Some(states.iter().all(|&s| s == BlockState::Received))
+    }
+
+    /// Marks a block that arrived without being requested as received, if it lies on our block
+    /// grid and we still need it.
+    ///
+    /// Returns `None` if the block is of no use, otherwise whether its piece now has every block.
+    pub fn claim(&mut self, block: Block, verified: &Bitfield) -> Option<bool> {
+        let index = self.block_index(block)?;
+        if verified.get(block.piece as usize) {
+            return None;
+        }
+
+        let blocks = self.piece_size(block.piece).div_ceil(self.block_size);
+        let states = self
+            .in_progress
+            .entry(block.piece)
+            .or_insert_with(|| vec![BlockState::Missing; blocks]);
+        // A copy still requested from another peer will be dropped when it arrives.
+        if states[index] == BlockState::Received {
+            return None;
+        }
+        states[index] = BlockState::Received;
+
+        Some(states.iter().all(|&s| s == BlockState::Received))
+    }
+
+    /// Returns a requested block to the pool, e.g. after a timeout or disconnect.
+    pub fn cancel(&mut self, block: Block) {
+        let Some(index) = self.block_index(block) else {
+            return;
+        };
+
+        if let Some(states) = self.in_progress.get_mut(&block.piece) {
+            if states[index] == BlockState::Requested {
+                states[index] = BlockState::Missing;
+            }
+        }
+    }
+
+    /// Counts the blocks of every piece by state, taking the pieces in `verified` as done.
+    pub fn block_counts(&self, verified: &Bitfield) -> BlockCounts {
+        let mut counts = BlockCounts::default();
+        for piece in 0..self.num_pieces as u32 {
+            let blocks = self.piece_size(piece).div_ceil(self.block_size);
+            if verified.get(piece as usize) {
+                counts.verified += blocks;
+                continue;
+            }
+            let Some(states) = self.in_progress.get(&piece) else {
+                counts.queued += blocks;
+                continue;
+            };
+            for state in states {
+                match state {
+                    BlockState::Missing => counts.queued += 1,
+                    BlockState::Requested => counts.in_flight += 1,
+                    BlockState::Received => counts.unverified += 1,
+                }
+            }
+        }
+
+        counts
+    }
+
+    /// The fraction of a piece's blocks received so far, `0` for pieces not started.
+    pub fn piece_progress(&self, piece: u32) -> f64 {
+        self.in_progress.get(&piece).map_or(0.0, |states| {
+            let received = states
+                .iter()
+                .filter(|&&s| s == BlockState::Received)
+                .count();
+            received as f64 / states.len() as f64
+        })
+    }
+
+    /// Forgets a piece's progress, either because it verified or so it gets downloaded again.
+    pub fn reset_piece(&mut self, piece: u32) {
+        self.in_progress.remove(&piece);
+    }
+}
+
+/// Bounds for how long a requested block may stay outstanding before it's handed to another peer.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestTimeouts {
+    pub min: Duration,
+    pub max: Duration,
+    /// Multiple of the time a peer's queue should take at its measured rate.
+    pub slack: f64,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        Self {
+            min: Duration::from_secs(5),
+            max: Duration::from_secs(60),
+            slack: 3.0,
+        }
+    }
+}
+
+/// How every connection's [`Pipeline`] is sized.
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineConfig {
+    /// Most requests outstanding to one peer.
+    pub max_depth: usize,
+    /// Keeps every pipeline at `max_depth` instead of adapting it to the peer, e.g. to benchmark
+    /// links with high latency.
+    pub fixed: bool,
+    pub timeouts: RequestTimeouts,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            fixed: false,
+            timeouts: RequestTimeouts::default(),
+        }
+    }
+}
+
+/// A [`Pipeline`] at one moment, see [`Pipeline::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PipelineStats {
+    /// Requests allowed outstanding right now.
+    pub depth: usize,
+    pub max_depth: usize,
+    pub outstanding: usize,
+    /// Smoothed rate blocks arrive at in bytes per second, `None` before the first one.
+    pub rate: Option<f64>,
+    /// How long the outstanding requests should take to arrive at `rate`, `None` before the
+    /// first block.
+    pub queue_time: Option<Duration>,
+    /// How long a request may be outstanding before it's given up on.
+    pub timeout: Duration,
+}
+
+/// Requests outstanding to a single peer, sized by how fast the peer has been delivering.
+#[derive(Debug)]
+pub struct Pipeline {
+    outstanding: HashMap<Block, Instant>,
+    depth: usize,
+    max_depth: usize,
+    fixed: bool,
+    /// Smoothed download rate in bytes per second.
+    rate: Option<f64>,
+    last_received: Option<Instant>,
+}
+
+impl Pipeline {
+    pub fn new(max_depth: usize) -> Self {
+        Self {
+            outstanding: HashMap::new(),
+            depth: 4.min(max_depth),
+            max_depth,
+            fixed: false,
+            rate: None,
+            last_received: None,
+        }
+    }
+
+    /// Keeps the depth at its maximum instead of growing it as blocks arrive and halving it
+    /// when they time out.
+    pub fn with_fixed_depth(mut self, fixed: bool) -> Self {
+        self.fixed = fixed;
+        if fixed {
+            self.depth = self.max_depth;
+        }
+        self
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Smoothed rate blocks arrive at in bytes per second, `None` before the first one.
+    pub fn rate(&self) -> Option<f64> {
+        self.rate
+    }
+
+    pub fn stats(&self, timeouts: &RequestTimeouts) -> PipelineStats {
+        PipelineStats {
+            depth: self.depth,
+            max_depth: self.max_depth,
+            outstanding: self.outstanding.len(),
+            rate: self.rate,
+            queue_time: self
+                .rate
+                .map(|rate| Duration::from_secs_f64(self.queued() / rate)),
+            timeout: self.timeout(timeouts),
+        }
+    }
+
+    /// Bytes requested and not received yet.
+    fn queued(&self) -> f64 {
+        self.outstanding.keys().map(|b| b.length as f64).sum()
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.outstanding.len() < self.depth
+    }
+
+    pub fn push(&mut self, block: Block, now: Instant) {
+        self.outstanding.insert(block, now);
+    }
+
+    /// Records a received block, returning `false` if it wasn't outstanding to this peer.
+    pub fn complete(&mut self, block: Block, now: Instant) -> bool {
+        let Some(requested) = self.outstanding.remove(&block) else {
+            return false;
+        };
+
+        let since = match self.last_received {
+            Some(last) => last.max(requested),
+            None => requested,
+        };
+        let elapsed = now.duration_since(since).as_secs_f64().max(0.001);
+        let sample = block.length as f64 / elapsed;
+        self.rate = Some(match self.rate {
+            Some(rate) => rate * 0.8 + sample * 0.2,
+            None => sample,
+        });
+        self.last_received = Some(now);
+
+        if !self.fixed {
+            self.depth = (self.depth + 1).min(self.max_depth);
+        }
+
+        true
+    }
+
+    /// How long a block may be outstanding, based on how long the queue should take to drain.
+    pub fn timeout(&self, timeouts: &RequestTimeouts) -> Duration {
+        match self.rate {
+            Some(rate) => Duration::from_secs_f64(self.queued() / rate * timeouts.slack)
+                .clamp(timeouts.min, timeouts.max),
+            None => timeouts.max,
+        }
+    }
+
+    /// Removes requests that have been outstanding too long, halving the pipeline depth if any
+    /// did so the slow peer is given less work, unless it's fixed.
+    pub fn expire(&mut self, now: Instant, timeouts: &RequestTimeouts) -> Vec<Block> {
+        let timeout = self.timeout(timeouts);
+
+        let expired = self
+            .outstanding
+            .iter()
+            .filter(|(_, &requested)| now.duration_since(requested) > timeout)
+            .map(|(&block, _)| block)
+            .collect::<Vec<_>>();
+
+        if !expired.is_empty() {
+            for block in &expired {
+                self.outstanding.remove(block);
+            }
+            if !self.fixed {
+                self.depth = (self.depth / 2).max(1);
+            }
+        }
+
+        expired
+    }
+
+    /// Drops an outstanding request without counting it as received, returning `false` if it
+    /// wasn't outstanding.
+    pub fn cancel(&mut self, block: Block) -> bool {
+        self.outstanding.remove(&block).is_some()
+    }
+
+    /// Drops every outstanding request, e.g. when the peer chokes us or disconnects.
+    pub fn drain(&mut self) -> Vec<Block> {
+        self.outstanding.drain().map(|(block, _)| block).collect()
+    }
+}
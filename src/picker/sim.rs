@@ -0,0 +1,372 @@
+//! Deterministic simulation of a download against scripted peers, for exercising the picker and
+//! request pipelines without sockets or real time.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::bitfield::Bitfield;
+
+use super::{Block, Picker, Pipeline, RequestTimeouts};
+
+/// Simulated time that passes per [`Simulation::step`].
+pub const TICK: Duration = Duration::from_millis(100);
+
+/// A scripted remote peer.
+#[derive(Debug, Clone)]
+pub struct MockPeer {
+    have: Bitfield,
+    rate: usize,
+    chokes: Vec<(u64, bool)>,
+}
+
+impl MockPeer {
+    /// A peer with every piece, serving `rate` bytes per tick.
+    pub fn seed(num_pieces: usize, rate: usize) -> Self {
+        Self::with_pieces(num_pieces, 0..num_pieces, rate)
+    }
+
+    /// A peer with only `pieces`, serving `rate` bytes per tick.
+    pub fn with_pieces(
+        num_pieces: usize,
+        pieces: impl IntoIterator<Item = usize>,
+        rate: usize,
+    ) -> Self {
+        let mut have = Bitfield::new(num_pieces);
+        for piece in pieces {
+            have.set(piece);
+        }
+
+        Self {
+            have,
+            rate,
+            chokes: Vec::new(),
+        }
+    }
+
+    /// Chokes (or unchokes) us at the start of `tick`. Peers start out unchoking.
+    pub fn choke_at(mut self, tick: u64, choked: bool) -> Self {
+        self.chokes.push((tick, choked));
+        self
+    }
+}
+
+#[derive(Debug)]
+struct PeerState {
+    mock: MockPeer,
+    pipeline: Pipeline,
+    /// Requests in the order the peer will serve them.
+    queue: VecDeque<Block>,
+    choked: bool,
+    budget: usize,
+    requested: usize,
+    received: usize,
+}
+
+/// What happened over a simulation run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub ticks: u64,
+    pub complete: bool,
+    /// Blocks requested from each peer, in the order peers were added.
+    pub requested: Vec<usize>,
+    /// Payload bytes received from each peer.
+    pub received: Vec<usize>,
+    /// Requests for a block that was already outstanding to some peer.
+    pub duplicates: usize,
+    /// Requests for a piece the peer didn't have.
+    pub invalid: usize,
+}
+
+/// Drives a [`Picker`] and one [`Pipeline`] per peer in lockstep simulated time.
+#[derive(Debug)]
+pub struct Simulation {
+    picker: Picker,
+    piece_length: usize,
+    length: usize,
+    verified: Bitfield,
+    peers: Vec<PeerState>,
+    outstanding: HashSet<Block>,
+    timeouts: RequestTimeouts,
+    max_depth: usize,
+    start: Instant,
+    tick: u64,
+    duplicates: usize,
+    invalid: usize,
+}
+
+impl Simulation {
+    pub fn new(piece_length: usize, length: usize) -> Self {
+        let num_pieces = length.div_ceil(piece_length);
+
+        Self {
+            picker: Picker::new(piece_length, length),
+            piece_length,
+            length,
+            verified: Bitfield::new(num_pieces),
+            peers: Vec::new(),
+            outstanding: HashSet::new(),
+            timeouts: RequestTimeouts::default(),
+            max_depth: 64,
+            start: Instant::now(),
+            tick: 0,
+            duplicates: 0,
+            invalid: 0,
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.length.div_ceil(self.piece_length)
+    }
+
+    pub fn timeouts(mut self, timeouts: RequestTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn add_peer(&mut self, mock: MockPeer) {
+        self.peers.push(PeerState {
+            mock,
+            pipeline: Pipeline::new(self.max_depth),
+            queue: VecDeque::new(),
+            choked: false,
+            budget: 0,
+            requested: 0,
+            received: 0,
+        });
+    }
+
+    pub fn verified(&self) -> &Bitfield {
+        &self.verified
+    }
+
+    /// Advances simulated time by one [`TICK`].
+    ///
+    /// Chokes and timeouts are applied to every peer first. Peers then request and receive in an
+    /// order that rotates every tick, the way independent connections would interleave, so no
+    /// peer always gets first pick of freed blocks.
+    pub fn step(&mut self) {
+        self.tick += 1;
+        let now = self.start + TICK * self.tick as u32;
+
+        for index in 0..self.peers.len() {
+            self.release(index, now);
+        }
+
+        let count = self.peers.len();
+        for offset in 0..count {
+            self.transfer((self.tick as usize + offset) % count, now);
+        }
+    }
+
+    fn release(&mut self, index: usize, now: Instant) {
+        let tick = self.tick;
+        let peer = &mut self.peers[index];
+
+        if let Some(&(_, choked)) = peer.mock.chokes.iter().rfind(|&&(at, _)| at == tick) {
+            peer.choked = choked;
+            if choked {
+                // A choking peer discards every request it hasn't served.
+                peer.queue.clear();
+                for block in peer.pipeline.drain() {
+                    self.outstanding.remove(&block);
+                    self.picker.cancel(block);
+                }
+            }
+        }
+
+        for block in peer.pipeline.expire(now, &self.timeouts) {
+            peer.queue.retain(|&queued| queued != block);
+            self.outstanding.remove(&block);
+            self.picker.cancel(block);
+        }
+    }
+
+    fn transfer(&mut self, index: usize, now: Instant) {
+        let peer = &mut self.peers[index];
+        if peer.choked {
+            return;
+        }
+
+        while peer.pipeline.has_room() {
+            let Some(block) = self.picker.pick(&peer.mock.have, &self.verified) else {
+                break;
+            };
+
+            if !self.outstanding.insert(block) {
+                self.duplicates += 1;
+            }
+            if !peer.mock.have.get(block.piece as usize) {
+                self.invalid += 1;
+            }
+
+            peer.pipeline.push(block, now);
+            peer.queue.push_back(block);
+            peer.requested += 1;
+        }
+
+        peer.budget += peer.mock.rate;
+        while let Some(&block) = peer.queue.front() {
+            if block.length as usize > peer.budget {
+                break;
+            }
+            peer.queue.pop_front();
+            peer.budget -= block.length as usize;
+            peer.received += block.length as usize;
+
+            if !peer.pipeline.complete(block, now) {
+                continue;
+            }
+            self.outstanding.remove(&block);
+
+            if self.picker.received(block) == Some(true) {
+                self.verified.set(block.piece as usize);
+                self.picker.reset_piece(block.piece);
+            }
+        }
+        // Bandwidth doesn't accumulate while there's nothing to send.
+        if peer.queue.is_empty() {
+            peer.budget = 0;
+        }
+    }
+
+    /// Steps until every piece is verified or `max_ticks` have passed.
+    pub fn run(&mut self, max_ticks: u64) -> Report {
+        while !self.verified.is_complete() && self.tick < max_ticks {
+            self.step();
+        }
+
+        Report {
+            ticks: self.tick,
+            complete: self.verified.is_complete(),
+            requested: self.peers.iter().map(|peer| peer.requested).collect(),
+            received: self.peers.iter().map(|peer| peer.received).collect(),
+            duplicates: self.duplicates,
+            invalid: self.invalid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::picker::BLOCK_SIZE;
+
+    const PIECE: usize = 4 * BLOCK_SIZE;
+
+    #[test]
+    fn downloads_everything_from_a_seed() {
+        let mut sim = Simulation::new(PIECE, 10 * PIECE + 1000);
+        sim.add_peer(MockPeer::seed(sim.num_pieces(), 4 * BLOCK_SIZE));
+
+        let report = sim.run(1000);
+
+        assert!(report.complete);
+        assert_eq!(report.received, vec![10 * PIECE + 1000]);
+        assert_eq!(report.duplicates, 0);
+        assert_eq!(report.invalid, 0);
+    }
+
+    #[test]
+    fn combines_partial_peers() {
+        let mut sim = Simulation::new(PIECE, 8 * PIECE);
+        sim.add_peer(MockPeer::with_pieces(8, 0..4, BLOCK_SIZE));
+        sim.add_peer(MockPeer::with_pieces(8, 4..8, BLOCK_SIZE));
+
+        let report = sim.run(1000);
+
+        assert!(report.complete);
+        assert_eq!(report.received, vec![4 * PIECE, 4 * PIECE]);
+        assert_eq!(report.invalid, 0);
+    }
+
+    #[test]
+    fn never_requests_a_block_twice() {
+        let mut sim = Simulation::new(PIECE, 16 * PIECE);
+        for rate in [BLOCK_SIZE, 2 * BLOCK_SIZE, 8 * BLOCK_SIZE] {
+            sim.add_peer(MockPeer::seed(16, rate));
+        }
+
+        let report = sim.run(1000);
+
+        assert!(report.complete);
+        assert_eq!(report.duplicates, 0);
+        assert_eq!(report.requested.iter().sum::<usize>(), 16 * 4);
+    }
+
+    #[test]
+    fn stalls_without_a_source_for_every_piece() {
+        let mut sim = Simulation::new(PIECE, 4 * PIECE);
+        sim.add_peer(MockPeer::with_pieces(4, [0, 1, 2], 4 * BLOCK_SIZE));
+
+        let report = sim.run(200);
+
+        assert!(!report.complete);
+        assert_eq!(sim.verified().count_ones(), 3);
+    }
+
+    #[test]
+    fn choke_returns_requests_to_other_peers() {
+        let mut sim = Simulation::new(PIECE, 8 * PIECE);
+        sim.add_peer(MockPeer::seed(8, BLOCK_SIZE / 2).choke_at(3, true));
+        sim.add_peer(MockPeer::seed(8, BLOCK_SIZE));
+
+        let report = sim.run(1000);
+
+        assert!(report.complete);
+        assert_eq!(report.duplicates, 0);
+        assert_eq!(report.received.iter().sum::<usize>(), 8 * PIECE);
+    }
+
+    #[test]
+    fn unchoked_peer_resumes() {
+        let mut sim = Simulation::new(PIECE, 4 * PIECE);
+        sim.add_peer(
+            MockPeer::seed(4, BLOCK_SIZE)
+                .choke_at(2, true)
+                .choke_at(20, false),
+        );
+
+        let report = sim.run(1000);
+
+        assert!(report.complete);
+        assert!(report.ticks > 20);
+    }
+
+    #[test]
+    fn timed_out_requests_move_to_a_responsive_peer() {
+        let mut sim = Simulation::new(PIECE, 4 * PIECE).timeouts(RequestTimeouts {
+            min: Duration::from_secs(1),
+            max: Duration::from_secs(2),
+            slack: 2.0,
+        });
+        // Never serves anything.
+        sim.add_peer(MockPeer::seed(4, 0));
+        sim.add_peer(MockPeer::with_pieces(4, 0..4, BLOCK_SIZE));
+
+        let report = sim.run(1000);
+
+        assert!(report.complete);
+        assert_eq!(report.received[0], 0);
+        assert_eq!(report.received[1], 4 * PIECE);
+    }
+
+    #[test]
+    fn is_deterministic() {
+        let run = || {
+            let mut sim = Simulation::new(PIECE, 12 * PIECE);
+            sim.add_peer(MockPeer::with_pieces(12, 0..8, BLOCK_SIZE));
+            sim.add_peer(MockPeer::with_pieces(12, 4..12, 3 * BLOCK_SIZE).choke_at(5, true));
+            sim.add_peer(MockPeer::seed(12, 2 * BLOCK_SIZE));
+            sim.run(1000)
+        };
+
+        assert_eq!(run(), run());
+    }
+}
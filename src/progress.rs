@@ -0,0 +1,26 @@
+use std::{path::PathBuf, time::Duration};
+
+/// How far hashing a torrent's data has come, reported while checking files already on disk and
+/// while creating a torrent.
+#[derive(Debug, Clone)]
+pub struct HashProgress {
+    pub pieces_hashed: usize,
+    pub pieces: usize,
+    pub bytes_hashed: u64,
+    pub bytes: u64,
+    /// The file the last hashed piece starts in, relative to the torrent's root.
+    pub file: PathBuf,
+    /// Time since hashing started.
+    pub elapsed: Duration,
+}
+
+impl HashProgress {
+    /// Time left at the average rate so far, `None` until something was hashed.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.bytes_hashed == 0 {
+            return None;
+        }
+        let left = self.bytes.saturating_sub(self.bytes_hashed);
+        Some(self.elapsed.mul_f64(left as f64 / self.bytes_hashed as f64))
+    }
+}
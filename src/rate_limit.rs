@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How long a child keeps its share of the parent's rate after its last transfer is paid for.
+const SHARE_IDLE: Duration = Duration::from_secs(1);
+
+/// A token bucket limiting throughput in bytes per second.
+///
+/// Clones share the same bucket. A limiter created with [`child`](Self::child) also draws from its
+/// parent, so per-torrent limits nest under a session-wide one. Children transferring at the same
+/// time split the parent's rate in proportion to their [`weight`](Self::weight).
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+    parent: Option<Box<RateLimiter>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Identifies the limiter among its parent's children.
+    id: u64,
+    weight: u32,
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+    /// Children's slices of `rate`, by id.
+    shares: HashMap<u64, Share>,
+}
+
+#[derive(Debug)]
+struct Share {
+    weight: u32,
+    tokens: f64,
+    last: Instant,
+    /// When the child's last transfer is paid for.
+    paid: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            weight: 1,
+            rate: None,
+            tokens: 0.0,
+            last: Instant::now(),
+            shares: HashMap::new(),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `rate` bytes per second, or unlimited throughput for `None`.
+    pub fn new(rate: Option<u64>) -> Self {
+        let limiter = Self::default();
+        limiter.set_rate(rate);
+        limiter
+    }
+
+    /// Creates an unlimited limiter whose traffic also counts against `self`.
+    pub fn child(&self) -> Self {
+        Self {
+            bucket: Arc::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.rate = rate.filter(|&rate| rate > 0);
+        // Start a new limit with a full second of burst rather than leftover debt.
+        bucket.tokens = bucket.rate.unwrap_or(0) as f64;
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.bucket.lock().unwrap().weight
+    }
+
+    /// Sets how much of the parent's rate this limiter gets relative to its siblings, `1` by
+    /// default.
+    pub fn set_weight(&self, weight: u32) {
+        self.bucket.lock().unwrap().weight = weight.max(1);
+    }
+
+    /// Waits until `bytes` may be transferred under this limit and every parent limit.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.bucket.lock().unwrap().take(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+
+        let mut child = self;
+        while let Some(parent) = child.parent.as_deref() {
+            let (id, weight) = {
+                let bucket = child.bucket.lock().unwrap();
+                (bucket.id, bucket.weight)
+            };
+            let wait = parent
+                .bucket
+                .lock()
+                .unwrap()
+                .take_share(id, weight, bytes, Instant::now());
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+            child = parent;
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+
+        if let Some(rate) = self.rate {
+            // Allow at most one second of burst.
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+    }
+
+    /// Takes `bytes` from the bucket, returning how long to wait until they're paid for.
+    fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        self.refill(now);
+
+        let Some(rate) = self.rate else {
+            return Duration::ZERO;
+        };
+
+        // Going into debt lets transfers larger than the burst through while still holding back
+        // the next caller until it's repaid.
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate as f64)
+        }
+    }
+
+    /// Like [`take`](Self::take), but from the slice of the rate that child `id` gets among the
+    /// children that transferred recently.
+    fn take_share(&mut self, id: u64, weight: u32, bytes: usize, now: Instant) -> Duration {
+        let Some(rate) = self.rate else {
+            return Duration::ZERO;
+        };
+
+        self.shares
+            .retain(|&other, share| other == id || now < share.paid + SHARE_IDLE);
+        let share = self.shares.entry(id).or_insert_with(|| Share {
+            weight,
+            tokens: 0.0,
+            last: now,
+            paid: now,
+        });
+        share.weight = weight;
+        let total = self
+            .shares
+            .values()
+            .map(|share| u64::from(share.weight))
+            .sum::<u64>();
+        let share = self.shares.get_mut(&id).expect("inserted above");
+        let rate = rate as f64 * f64::from(share.weight) / total as f64;
+
+        // Same as a bucket of its own: one second of burst, debt repaid before the next caller.
+        let elapsed = now.duration_since(share.last).as_secs_f64();
+        share.last = now;
+        share.tokens = (share.tokens + elapsed * rate).min(rate);
+        share.tokens -= bytes as f64;
+        let wait = if share.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-share.tokens / rate)
+        };
+        share.paid = now + wait;
+        wait
+    }
+}
@@ -0,0 +1,732 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
+    mem,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    bitfield::Bitfield,
+    connection::{self, ViolationPolicy},
+    download::{CheckConfig, Download, Priority},
+    error::{Error, Result, StorageError, UsageError},
+    info::Torrent,
+    ip_filter::SharedIpFilter,
+    listener::{ListenConfig, Listener},
+    net::udp::UdpMux,
+    peer,
+    peer_id::PeerId,
+    peer_list::MAX_PEERS,
+    picker::PipelineConfig,
+    progress::HashProgress,
+    rate_limit::RateLimiter,
+    slots::{SlotLimits, SlotPool},
+    storage::{Dedup, DiskQuota, FileStore, PathPolicy, SyncPolicy},
+};
+
+pub use self::{
+    connectivity::Connectivity,
+    labels::{TorrentFilter, TorrentStats},
+};
+
+mod batch;
+#[cfg(feature = "config")]
+mod config;
+mod connectivity;
+mod dedup;
+#[cfg(feature = "http")]
+mod external_ip;
+mod labels;
+mod state;
+
+/// How long accepting pauses after it failed, e.g. because the process ran out of file
+/// descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionConfig {
+    pub listen: ListenConfig,
+    /// Budgets every torrent's files are allocated against.
+    pub quotas: Vec<DiskQuota>,
+    /// What happens to unsafe or colliding file paths.
+    pub paths: PathPolicy,
+    /// Appended to the names of files until they're complete, e.g. `".part"`. See
+    /// [`FileStore::create`].
+    pub part_suffix: Option<String>,
+    /// Reuse files other torrents of the session already have complete instead of downloading
+    /// them again, if set.
+    pub dedup: Option<Dedup>,
+    /// When peers breaking the protocol are disconnected and banned.
+    pub violations: ViolationPolicy,
+    /// Addresses never connected to or accepted from. [`Session::apply_config`] keeps the one
+    /// the session was created with; update the list through it, see [`SharedIpFilter::store`].
+    pub ip_filter: SharedIpFilter,
+    /// Send peers lazy bitfields. See [`PeerState::with_lazy_bitfield`].
+    ///
+    /// [`PeerState::with_lazy_bitfield`]: crate::connection::PeerState::with_lazy_bitfield
+    pub lazy_bitfield: bool,
+    /// When written pieces are flushed to the disk.
+    pub sync: SyncPolicy,
+    /// Download directories are on spinning disks. See [`FileStore::with_rotational`].
+    pub rotational: bool,
+    /// How many pieces are checked at once when torrents are added or rechecked.
+    pub check: CheckConfig,
+    /// Size of the blocks we request, [`BLOCK_SIZE`] if unset.
+    ///
+    /// [`BLOCK_SIZE`]: crate::picker::BLOCK_SIZE
+    pub block_size: Option<usize>,
+    /// Longest request peers may send, [`MAX_REQUEST`] if unset.
+    ///
+    /// [`MAX_REQUEST`]: crate::picker::MAX_REQUEST
+    pub max_request: Option<usize>,
+    /// How many requests are outstanding to each peer.
+    pub pipeline: PipelineConfig,
+    /// Most known peers kept per torrent, [`MAX_PEERS`] if unset.
+    ///
+    /// [`MAX_PEERS`]: crate::peer_list::MAX_PEERS
+    pub max_peers: Option<usize>,
+    /// Connections and unchoked peers across all torrents.
+    pub slots: SlotLimits,
+    /// Download rate across all torrents in bytes per second, unlimited if unset.
+    pub download_limit: Option<u64>,
+    /// Upload rate across all torrents in bytes per second, unlimited if unset.
+    pub upload_limit: Option<u64>,
+    /// Where torrents of each category are added, see [`Session::add_to_category`].
+    pub categories: BTreeMap<String, PathBuf>,
+}
+
+/// Runs any number of torrents behind a single peer id and listen port.
+pub struct Session {
+    shared: Arc<Shared>,
+    listening: Mutex<Listening>,
+}
+
+/// The bound listen port, replaced when [`Session::apply_config`] changes it.
+struct Listening {
+    port: u16,
+    addrs: Vec<SocketAddr>,
+    accept: JoinHandle<()>,
+    udp: Option<UdpMux>,
+}
+
+impl Listening {
+    async fn bind(config: &ListenConfig, shared: &Arc<Shared>) -> Result<Self> {
+        let listener = Listener::bind(config).await.map_err(Error::Listen)?;
+        let port = listener.port();
+        let addrs = listener.local_addrs().map_err(Error::Listen)?;
+
+        // Not being able to share the port over UDP only costs UDP trackers a socket each.
+        let udp = UdpMux::bind((config.ip, port)).await.ok();
+
+        Ok(Self {
+            port,
+            addrs,
+            accept: tokio::spawn(accept_loop(listener, shared.clone())),
+            udp,
+        })
+    }
+}
+
+type CheckProgress = Arc<dyn Fn([u8; 20], &HashProgress) + Send + Sync>;
+
+struct Shared {
+    peer_id: PeerId,
+    config: Mutex<SessionConfig>,
+    slots: SlotPool,
+    download_limit: RateLimiter,
+    upload_limit: RateLimiter,
+    torrents: Mutex<HashMap<[u8; 20], Added>>,
+    ip_filter: SharedIpFilter,
+    /// Files of the torrents by content, for [`SessionConfig::dedup`].
+    content: Mutex<HashMap<[u8; 20], Vec<dedup::Content>>>,
+    /// Whether any peer has completed a handshake on the listen port.
+    incoming: AtomicBool,
+    /// Our address as the internet sees it, see [`Session::set_external_ip`].
+    external_ip: watch::Sender<Option<IpAddr>>,
+    check_progress: Mutex<Option<CheckProgress>>,
+    connectivity: watch::Sender<Connectivity>,
+}
+
+/// A torrent of the session along with what it was added from, to save with the session state.
+struct Added {
+    download: Arc<Download>,
+    metainfo: Vec<u8>,
+    root: PathBuf,
+    seed_only: bool,
+    category: Option<String>,
+    labels: BTreeSet<String>,
+}
+
+impl Added {
+    fn new(download: Arc<Download>, metainfo: Vec<u8>, root: PathBuf, seed_only: bool) -> Self {
+        Self {
+            download,
+            metainfo,
+            root,
+            seed_only,
+            category: None,
+            labels: BTreeSet::new(),
+        }
+    }
+}
+
+impl Shared {
+    fn update_connectivity(&self) -> Connectivity {
+        let status = connectivity::classify(
+            self.incoming.load(Ordering::Relaxed),
+            *self.external_ip.borrow(),
+        );
+        self.connectivity.send_if_modified(|current| {
+            let changed = *current != status;
+            *current = status;
+            changed
+        });
+        status
+    }
+
+    fn set_external_ip(&self, ip: IpAddr) -> bool {
+        let mut changed = false;
+        self.external_ip.send_if_modified(|current| {
+            changed = current.is_some_and(|known| known != ip);
+            current.replace(ip) != Some(ip)
+        });
+        self.update_connectivity();
+        changed
+    }
+}
+
+impl Session {
+    /// Binds the listen port and starts accepting peers for the torrents added later.
+    pub async fn new(config: SessionConfig) -> Result<Self> {
+        let listen = config.listen.clone();
+        let shared = Arc::new(Shared {
+            peer_id: PeerId::generate(),
+            slots: SlotPool::new(config.slots),
+            download_limit: RateLimiter::new(config.download_limit),
+            upload_limit: RateLimiter::new(config.upload_limit),
+            ip_filter: config.ip_filter.clone(),
+            config: Mutex::new(config),
+            torrents: Mutex::default(),
+            content: Mutex::default(),
+            incoming: AtomicBool::new(false),
+            external_ip: watch::channel(None).0,
+            check_progress: Mutex::default(),
+            connectivity: watch::channel(Connectivity::Unknown).0,
+        });
+
+        let listening = Listening::bind(&listen, &shared).await?;
+
+        Ok(Self {
+            shared,
+            listening: Mutex::new(listening),
+        })
+    }
+
+    /// The configuration the session runs with, including changes made by
+    /// [`apply_config`](Self::apply_config).
+    pub fn config(&self) -> SessionConfig {
+        self.shared.config.lock().unwrap().clone()
+    }
+
+    /// Changes the configuration of a running session.
+    ///
+    /// Rate limits, slot limits, and the most peers kept per torrent take effect right away for
+    /// every torrent. The other settings apply to torrents added afterwards, and a disk quota
+    /// whose limit is unchanged keeps counting what was already allocated against it.
+    ///
+    /// If the listen settings changed, the new port is bound before the old one is closed, so a
+    /// failure leaves the session listening as before. Trackers keep any UDP socket they were
+    /// given from [`udp_socket`](Self::udp_socket) and need the new one.
+    pub async fn apply_config(&self, mut config: SessionConfig) -> Result<()> {
+        let old = self.config();
+
+        if config.listen != old.listen {
+            self.rebind(&config.listen).await?;
+        }
+
+        self.shared.download_limit.set_rate(config.download_limit);
+        self.shared.upload_limit.set_rate(config.upload_limit);
+        self.shared.slots.set_limits(config.slots);
+        if config.max_peers != old.max_peers {
+            for download in self.torrents() {
+                download.set_max_peers(Some(config.max_peers.unwrap_or(MAX_PEERS)));
+            }
+        }
+
+        config.ip_filter = old.ip_filter;
+        for quota in &mut config.quotas {
+            if let Some(same) = old.quotas.iter().find(|old| old.limit() == quota.limit()) {
+                *quota = same.clone();
+            }
+        }
+        *self.shared.config.lock().unwrap() = config;
+
+        Ok(())
+    }
+
+    /// Moves to another random port from [`RANDOM_PORTS`], e.g. when the current one turned out
+    /// to be blocked, and returns it. The old port stays bound if no new one can be.
+    ///
+    /// [`RANDOM_PORTS`]: crate::listener::RANDOM_PORTS
+    pub async fn randomize_port(&self) -> Result<u16> {
+        let listen = ListenConfig {
+            random_port: true,
+            ..self.config().listen
+        };
+        self.rebind(&listen).await?;
+        Ok(self.port())
+    }
+
+    /// Binds `listen` and swaps it in, closing the old listeners once the new ones are up.
+    async fn rebind(&self, listen: &ListenConfig) -> Result<()> {
+        let listening = Listening::bind(listen, &self.shared).await?;
+        let old = mem::replace(&mut *self.listening.lock().unwrap(), listening);
+        old.accept.abort();
+        Ok(())
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.shared.peer_id
+    }
+
+    /// The port peers can reach us on, to announce to trackers.
+    pub fn port(&self) -> u16 {
+        self.listening.lock().unwrap().port
+    }
+
+    /// Every address peers are accepted on, all with [`port`](Self::port).
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        self.listening.lock().unwrap().addrs.clone()
+    }
+
+    /// A UDP socket on [`port`](Self::port) to share between UDP trackers and a DHT node, see
+    /// [`TrackerTiers::set_udp_socket`]. `None` if the port is taken for UDP.
+    ///
+    /// [`TrackerTiers::set_udp_socket`]: crate::tracker::TrackerTiers::set_udp_socket
+    pub fn udp_socket(&self) -> Option<UdpMux> {
+        self.listening.lock().unwrap().udp.clone()
+    }
+
+    /// The address to announce to a tracker at `tracker`, see [`Tracker::set_ip`].
+    ///
+    /// That's the first listen address of the same family if it's a specific one, and `None` if
+    /// the tracker should use the address the announce comes from.
+    ///
+    /// [`Tracker::set_ip`]: crate::tracker::Tracker::set_ip
+    pub fn announce_ip(&self, tracker: IpAddr) -> Option<IpAddr> {
+        self.listening
+            .lock()
+            .unwrap()
+            .addrs
+            .iter()
+            .map(SocketAddr::ip)
+            .find(|ip| ip.is_ipv6() == tracker.is_ipv6())
+            .filter(|ip| !ip.is_unspecified())
+    }
+
+    /// Our addresses as peers see them, one per family at most: the external address if known,
+    /// otherwise a specific listen address.
+    fn canonical_addrs(&self) -> Vec<SocketAddr> {
+        let port = self.port();
+        let external = self.external_ip();
+        let listen = self.listen_addrs().into_iter().map(|addr| addr.ip());
+        let mut addrs = Vec::<SocketAddr>::new();
+        for ip in external.into_iter().chain(listen) {
+            if !ip.is_unspecified() && !addrs.iter().any(|addr| addr.is_ipv6() == ip.is_ipv6()) {
+                addrs.push((ip, port).into());
+            }
+        }
+        addrs
+    }
+
+    /// Connection and upload slots shared by the torrents, whose limits can be changed at runtime.
+    pub fn slots(&self) -> &SlotPool {
+        &self.shared.slots
+    }
+
+    /// Caps the download rate across all torrents in bytes per second, or lifts the cap with
+    /// `None`.
+    pub fn set_download_limit(&self, rate: Option<u64>) {
+        self.shared.download_limit.set_rate(rate);
+    }
+
+    /// Caps the upload rate across all torrents in bytes per second, or lifts the cap with `None`.
+    pub fn set_upload_limit(&self, rate: Option<u64>) {
+        self.shared.upload_limit.set_rate(rate);
+    }
+
+    /// Records our address as reported by a tracker's `external ip`, or found otherwise, for
+    /// [`test_connectivity`](Self::test_connectivity).
+    ///
+    /// Returns whether it changed from a previously known address. Peers and trackers only know
+    /// the old one then, so torrents should announce again, see
+    /// [`AnnounceSchedule::set_external_ip`].
+    ///
+    /// [`AnnounceSchedule::set_external_ip`]: crate::tracker::AnnounceSchedule::set_external_ip
+    pub fn set_external_ip(&self, ip: IpAddr) -> bool {
+        self.shared.set_external_ip(ip)
+    }
+
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        *self.shared.external_ip.borrow()
+    }
+
+    /// Subscribes to changes of our [`external_ip`](Self::external_ip), e.g. to re-announce
+    /// every torrent when a dynamic address changes.
+    pub fn watch_external_ip(&self) -> watch::Receiver<Option<IpAddr>> {
+        self.shared.external_ip.subscribe()
+    }
+
+    /// Reports whether peers can reach our listen port, judged by whether any have connected in
+    /// and the external address trackers reported.
+    pub fn test_connectivity(&self) -> Connectivity {
+        self.shared.update_connectivity()
+    }
+
+    /// Subscribes to changes of the [`test_connectivity`](Self::test_connectivity) status, e.g.
+    /// when the first peer connects in.
+    pub fn watch_connectivity(&self) -> watch::Receiver<Connectivity> {
+        self.shared.connectivity.subscribe()
+    }
+
+    /// Calls `hook` with the info hash and progress after every piece checked while torrents are
+    /// added, so frontends can show how far checking has come.
+    pub fn set_check_progress(
+        &self,
+        hook: impl Fn([u8; 20], &HashProgress) + Send + Sync + 'static,
+    ) {
+        *self.shared.check_progress.lock().unwrap() = Some(Arc::new(hook));
+    }
+
+    /// Adds a torrent whose files live under `root`, checking any data already there.
+    pub async fn add(&self, torrent: &Torrent, root: impl AsRef<Path>) -> Result<Arc<Download>> {
+        self.insert(torrent, root.as_ref(), false, None).await
+    }
+
+    /// Adds a torrent from a bare info dictionary, see [`Torrent::from_info_bytes`], whose files
+    /// live under `root`.
+    pub async fn add_from_info_bytes(
+        &self,
+        info: &[u8],
+        trackers: Vec<Vec<String>>,
+        root: impl AsRef<Path>,
+    ) -> Result<Arc<Download>> {
+        let torrent = Torrent::from_info_bytes(info, trackers)?;
+        self.add(&torrent, root).await
+    }
+
+    /// Adds a torrent whose files under `root` are already complete, only ever uploading them.
+    ///
+    /// The files are never opened for writing, so seeding from the originals a torrent was created
+    /// from can't modify them. The data is verified once, and the torrent isn't added if a file is
+    /// missing, has the wrong length, or any piece fails.
+    pub async fn seed(&self, torrent: &Torrent, root: impl AsRef<Path>) -> Result<Arc<Download>> {
+        self.insert(torrent, root.as_ref(), true, None).await
+    }
+
+    /// Adds a torrent whose files under `root` are trusted to be complete, seeding right away
+    /// without hashing them, e.g. when moving many torrents over from another client.
+    ///
+    /// Only the file lengths are checked: the torrent isn't added if a file is missing or has the
+    /// wrong length. Corrupt data goes unnoticed until peers reject it, so
+    /// [`Download::recheck`] it if in doubt.
+    pub async fn add_complete(
+        &self,
+        torrent: &Torrent,
+        root: impl AsRef<Path>,
+    ) -> Result<Arc<Download>> {
+        let root = root.as_ref();
+        // Opening read-only checks every length without creating missing files.
+        FileStore::open_read_only(root, &torrent.info, self.config().paths).await?;
+
+        let verified = Bitfield::full(torrent.info.num_pieces());
+        self.insert(torrent, root, false, Some(verified.as_bytes()))
+            .await
+    }
+
+    /// Adds a torrent, taking its verified pieces from `resume` if given instead of checking the
+    /// data on disk.
+    async fn insert(
+        &self,
+        torrent: &Torrent,
+        root: &Path,
+        seed_only: bool,
+        resume: Option<&[u8]>,
+    ) -> Result<Arc<Download>> {
+        let info_hash = self.validate(torrent)?;
+        let store = self.open_store(torrent, root, seed_only).await?;
+        let download = self
+            .prepare(torrent, info_hash, store, seed_only, resume)
+            .await?;
+        self.activate(torrent, info_hash, &download, root, seed_only)?;
+
+        Ok(download)
+    }
+
+    /// Checks that a torrent can be added, returning its info hash.
+    fn validate(&self, torrent: &Torrent) -> Result<[u8; 20]> {
+        let info_hash = torrent.info.calculate_info_hash()?;
+        // Its peers would reject every connection we make or accept.
+        if torrent.info.ssl_cert().is_some() {
+            return Err(UsageError::SslTorrent.into());
+        }
+        if self
+            .shared
+            .torrents
+            .lock()
+            .unwrap()
+            .contains_key(&info_hash)
+        {
+            return Err(UsageError::AlreadyAdded.into());
+        }
+
+        Ok(info_hash)
+    }
+
+    async fn open_store(
+        &self,
+        torrent: &Torrent,
+        root: &Path,
+        seed_only: bool,
+    ) -> Result<FileStore> {
+        let config = self.config();
+        let store = if seed_only {
+            FileStore::open_read_only(root, &torrent.info, config.paths).await?
+        } else {
+            FileStore::create(
+                root,
+                &torrent.info,
+                &config.quotas,
+                config.paths,
+                config.part_suffix.as_deref(),
+            )
+            .await?
+            .with_rotational(config.rotational)
+        };
+
+        Ok(store)
+    }
+
+    /// Builds a torrent's download on `store` and finds out which pieces it has, without adding
+    /// it to the session yet.
+    async fn prepare(
+        &self,
+        torrent: &Torrent,
+        info_hash: [u8; 20],
+        store: FileStore,
+        seed_only: bool,
+        resume: Option<&[u8]>,
+    ) -> Result<Arc<Download>> {
+        let config = self.config();
+        let download = if seed_only {
+            Download::seed_only(&torrent.info, store)
+        } else {
+            Download::new(&torrent.info, store)
+        };
+        let mut download = download
+            .with_violation_policy(config.violations)
+            .with_ip_filter(self.shared.ip_filter.clone())
+            .with_lazy_bitfield(config.lazy_bitfield)
+            .with_sync_policy(config.sync)
+            .with_check_config(config.check)
+            .with_pipeline_config(config.pipeline)
+            .with_slots(&self.shared.slots)
+            .with_global_limits(&self.shared.download_limit, &self.shared.upload_limit);
+        if let Some(size) = config.block_size {
+            download = download.with_block_size(size);
+        }
+        if let Some(length) = config.max_request {
+            download = download.with_max_request(length);
+        }
+        if let Some(max) = config.max_peers {
+            download = download.with_max_peers(max);
+        }
+        let download = Arc::new(download);
+        if let Some(dedup) = config.dedup.filter(|_| !seed_only) {
+            self.dedup(&torrent.info, &download, dedup).await;
+        }
+
+        let verified = match resume {
+            Some(bitfield) => {
+                let verified = download
+                    .restore_progress(bitfield)
+                    .map_err(StorageError::Io)?;
+                download.finalize_files(&download.verified()).await?;
+                verified
+            }
+            None => {
+                let hook = self.shared.check_progress.lock().unwrap().clone();
+                download
+                    .recheck_with_progress(|progress| {
+                        if let Some(hook) = &hook {
+                            hook(info_hash, progress);
+                        }
+                    })
+                    .await?
+            }
+        };
+        if seed_only && verified < download.num_pieces() {
+            return Err(UsageError::Incomplete(download.num_pieces() - verified).into());
+        }
+
+        Ok(download)
+    }
+
+    /// Makes a prepared torrent part of the session.
+    fn activate(
+        &self,
+        torrent: &Torrent,
+        info_hash: [u8; 20],
+        download: &Arc<Download>,
+        root: &Path,
+        seed_only: bool,
+    ) -> Result<()> {
+        let metainfo = torrent.to_bytes()?;
+        match self.shared.torrents.lock().unwrap().entry(info_hash) {
+            // Added by someone else while this one was being checked.
+            Entry::Occupied(_) => return Err(UsageError::AlreadyAdded.into()),
+            Entry::Vacant(entry) => {
+                entry.insert(Added::new(
+                    download.clone(),
+                    metainfo,
+                    root.to_owned(),
+                    seed_only,
+                ));
+            }
+        }
+        self.index_content(info_hash, &torrent.info);
+
+        Ok(())
+    }
+
+    pub fn get(&self, info_hash: [u8; 20]) -> Option<Arc<Download>> {
+        self.shared
+            .torrents
+            .lock()
+            .unwrap()
+            .get(&info_hash)
+            .map(|added| added.download.clone())
+    }
+
+    /// Every torrent added, highest priority first.
+    pub fn torrents(&self) -> Vec<Arc<Download>> {
+        let mut torrents = self
+            .shared
+            .torrents
+            .lock()
+            .unwrap()
+            .values()
+            .map(|added| added.download.clone())
+            .collect::<Vec<_>>();
+        torrents.sort_by_key(|download| std::cmp::Reverse(download.priority()));
+        torrents
+    }
+
+    /// Changes a torrent's share of the session's bandwidth and slots. See
+    /// [`Download::set_priority`].
+    pub fn set_priority(&self, info_hash: [u8; 20], priority: Priority) -> Result<()> {
+        let download = self.get(info_hash).ok_or(UsageError::NotAdded)?;
+        download.set_priority(priority);
+        Ok(())
+    }
+
+    /// A torrent's [`candidates`](Download::candidates), best to dial first: by the canonical
+    /// priority of BEP 40 against our address, so both ends of the swarm prefer the same
+    /// connections.
+    pub fn candidates(&self, info_hash: [u8; 20]) -> Result<Vec<SocketAddr>> {
+        let download = self.get(info_hash).ok_or(UsageError::NotAdded)?;
+        let mut candidates = download.candidates();
+        peer::sort_by_priority(&self.canonical_addrs(), &mut candidates);
+        Ok(candidates)
+    }
+
+    /// Connects to a peer of a torrent that has been added, exchanging pieces in the background.
+    ///
+    /// Fails with [`UsageError::ConnectionLimit`] if the torrent may not open another connection
+    /// under the session's [`SlotLimits`], and with [`UsageError::Blocked`] if
+    /// [`SessionConfig::ip_filter`] blocks `addr`.
+    pub async fn connect(&self, info_hash: [u8; 20], addr: SocketAddr) -> Result<JoinHandle<()>> {
+        let download = self.get(info_hash).ok_or(UsageError::NotAdded)?;
+        if self.shared.ip_filter.is_blocked(addr.ip()) {
+            return Err(UsageError::Blocked.into());
+        }
+        let slot = download
+            .slots()
+            .try_connection()
+            .ok_or(UsageError::ConnectionLimit)?;
+
+        let (framed, remote) = peer::connect(info_hash, self.shared.peer_id, addr).await?;
+
+        Ok(tokio::spawn(async move {
+            let _ = connection::run(download, framed, remote, addr).await;
+            drop(slot);
+        }))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.listening.lock().unwrap().accept.abort();
+    }
+}
+
+/// Accepts incoming connections until aborted. Failures, mostly a peer hanging up before it was
+/// accepted or running out of file descriptors, only pause it for a bit.
+async fn accept_loop(listener: Listener, shared: Arc<Shared>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(_) => {
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        // Dropped before the handshake, so blocked peers learn nothing about our torrents.
+        if shared.ip_filter.is_blocked(addr.ip()) {
+            continue;
+        }
+        let shared = shared.clone();
+
+        tokio::spawn(async move {
+            let interface = stream.local_addr();
+
+            // Banned peers are dropped without an answer, just like unknown torrents and torrents
+            // out of connection slots.
+            let lookup = |info_hash| {
+                let torrents = shared.torrents.lock().unwrap();
+                let download = &torrents.get(&info_hash)?.download;
+                if download.is_banned(addr.ip()) {
+                    return None;
+                }
+                let slot = download.slots().try_connection()?;
+                Some((download.clone(), slot))
+            };
+
+            let mut download = None;
+            let accepted = peer::accept(stream, shared.peer_id, |info_hash| {
+                download = lookup(info_hash);
+                download.is_some()
+            })
+            .await;
+
+            if accepted.is_ok() {
+                shared.incoming.store(true, Ordering::Relaxed);
+                shared.update_connectivity();
+            }
+
+            if let (Ok((framed, remote)), Some((download, _slot))) = (accepted, download) {
+                if let Ok(interface) = interface {
+                    download.add_incoming(addr, interface);
+                }
+                let _ = connection::run(download, framed, remote, addr).await;
+            }
+        });
+    }
+}
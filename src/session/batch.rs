@@ -0,0 +1,104 @@
+use std::{collections::HashSet, path::Path, sync::Arc};
+
+use crate::{
+    download::Download,
+    error::{Error, Result, UsageError},
+    info::Torrent,
+};
+
+use super::{Added, Session};
+
+impl Session {
+    /// Adds several torrents like [`add`](Self::add), each with the directory its files live
+    /// under, either all of them or none. Returns a result per torrent, in order.
+    ///
+    /// Every torrent is validated before any file is touched, so a batch with a torrent that's
+    /// invalid, already added, or in it twice fails right away. The files of the whole batch are
+    /// then created, reserving its space under the session's quotas, before any data is checked.
+    /// The torrents only join the session once every one of them is ready.
+    ///
+    /// A torrent that fails gets its error and the others fail with
+    /// [`UsageError::BatchAborted`]. Files already created for the batch are left on disk.
+    pub async fn add_many<'a, P: AsRef<Path>>(
+        &self,
+        torrents: impl IntoIterator<Item = (&'a Torrent, P)>,
+    ) -> Vec<Result<Arc<Download>>> {
+        let torrents = torrents.into_iter().collect::<Vec<_>>();
+
+        let mut seen = HashSet::new();
+        let validated = torrents
+            .iter()
+            .map(|(torrent, _)| {
+                let info_hash = self.validate(torrent)?;
+                if !seen.insert(info_hash) {
+                    return Err(UsageError::AlreadyAdded.into());
+                }
+                Ok((info_hash, torrent.to_bytes()?))
+            })
+            .collect::<Vec<Result<_>>>();
+        if validated.iter().any(Result::is_err) {
+            return validated
+                .into_iter()
+                .map(|result| result.and(Err(UsageError::BatchAborted.into())))
+                .collect();
+        }
+        let (info_hashes, metainfo): (Vec<_>, Vec<_>) = validated.into_iter().flatten().unzip();
+
+        // The stores hold their quota reservations until the batch is dropped or added.
+        let mut stores = Vec::with_capacity(torrents.len());
+        for (index, (torrent, root)) in torrents.iter().enumerate() {
+            match self.open_store(torrent, root.as_ref(), false).await {
+                Ok(store) => stores.push(store),
+                Err(e) => return aborted(torrents.len(), index, e),
+            }
+        }
+
+        let mut downloads = Vec::with_capacity(torrents.len());
+        for (index, ((torrent, _), store)) in torrents.iter().zip(stores).enumerate() {
+            match self
+                .prepare(torrent, info_hashes[index], store, false, None)
+                .await
+            {
+                Ok(download) => downloads.push(download),
+                Err(e) => return aborted(torrents.len(), index, e),
+            }
+        }
+
+        {
+            let mut added = self.shared.torrents.lock().unwrap();
+            // Something else may have added one of them while the batch was being checked.
+            if let Some(index) = info_hashes
+                .iter()
+                .position(|info_hash| added.contains_key(info_hash))
+            {
+                return aborted(torrents.len(), index, UsageError::AlreadyAdded.into());
+            }
+            for (((info_hash, download), metainfo), (_, root)) in info_hashes
+                .iter()
+                .zip(&downloads)
+                .zip(metainfo)
+                .zip(&torrents)
+            {
+                let root = root.as_ref().to_owned();
+                added.insert(
+                    *info_hash,
+                    Added::new(download.clone(), metainfo, root, false),
+                );
+            }
+        }
+        for ((torrent, _), info_hash) in torrents.iter().zip(info_hashes) {
+            self.index_content(info_hash, &torrent.info);
+        }
+
+        downloads.into_iter().map(Ok).collect()
+    }
+}
+
+/// Results for a batch of `len` torrents in which the one at `failed` failed with `error`.
+fn aborted(len: usize, failed: usize, error: Error) -> Vec<Result<Arc<Download>>> {
+    let mut results = (0..len)
+        .map(|_| Err(UsageError::BatchAborted.into()))
+        .collect::<Vec<_>>();
+    results[failed] = Err(error);
+    results
+}
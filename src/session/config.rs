@@ -0,0 +1,267 @@
+//! The TOML form of [`SessionConfig`].
+
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use futures::{stream, Stream};
+use serde::Deserialize;
+
+use crate::{
+    error::ConfigError,
+    storage::{Dedup, DiskQuota, PathPolicy, SyncPolicy},
+};
+
+use super::SessionConfig;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    listen: ListenSection,
+    limits: LimitsSection,
+    disk: DiskSection,
+    peers: PeersSection,
+    categories: BTreeMap<String, PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ListenSection {
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+    fallback: Option<[u16; 2]>,
+    additional: Vec<IpAddr>,
+    random_port: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    download: Option<u64>,
+    upload: Option<u64>,
+    connections: Option<usize>,
+    uploads: Option<usize>,
+    peers: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DiskSection {
+    quota: Option<u64>,
+    paths: Option<Paths>,
+    sync: Option<SyncValue>,
+    part_suffix: Option<String>,
+    dedup: Option<DedupValue>,
+    check_threads: Option<usize>,
+    check_read_ahead: Option<usize>,
+    rotational: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DedupValue {
+    Hardlink,
+    Copy,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Paths {
+    Rename,
+    Fail,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SyncValue {
+    Named(SyncName),
+    Seconds(u64),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SyncName {
+    Never,
+    Piece,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PeersSection {
+    lazy_bitfield: Option<bool>,
+    block_size: Option<usize>,
+    max_request: Option<usize>,
+    pipeline_depth: Option<usize>,
+    fixed_pipeline: Option<bool>,
+    ban_threshold: Option<u32>,
+    ban_duration: Option<u64>,
+}
+
+/// `0` means unlimited.
+fn limit<T: Default + PartialEq>(value: T) -> Option<T> {
+    (value != T::default()).then_some(value)
+}
+
+impl SessionConfig {
+    /// Parses a configuration file. Every key is optional and defaults to the
+    /// [`SessionConfig::default`] value, and a limit of `0` lifts it.
+    ///
+    /// ```toml
+    /// [listen]
+    /// ip = "0.0.0.0"
+    /// port = 6881
+    /// fallback = [6881, 6889]
+    /// additional = ["::"]
+    /// random_port = false   # kept across restarts by the saved session state
+    ///
+    /// [limits]
+    /// download = 1048576  # bytes per second
+    /// upload = 524288
+    /// connections = 200
+    /// uploads = 20
+    /// peers = 2000        # known peers kept per torrent
+    ///
+    /// [disk]
+    /// quota = 500000000000  # bytes, shared by every torrent
+    /// paths = "rename"      # or "fail"
+    /// sync = "never"        # or "piece", or seconds between syncs
+    /// part_suffix = ".part" # until a file is complete
+    /// dedup = "hardlink"    # or "copy", reusing files other torrents have
+    /// check_threads = 4     # pieces hashed at once, half the cores by default
+    /// check_read_ahead = 8  # pieces read ahead, twice the threads by default
+    /// rotational = false    # pick pieces near the last written on spinning disks
+    ///
+    /// [peers]
+    /// lazy_bitfield = false
+    /// block_size = 16384
+    /// max_request = 131072
+    /// pipeline_depth = 64   # most requests outstanding to a peer
+    /// fixed_pipeline = false # always keep pipeline_depth requests outstanding
+    /// ban_threshold = 100
+    /// ban_duration = 3600   # seconds
+    ///
+    /// [categories]          # where torrents of each category are added
+    /// movies = "/data/movies"
+    /// ```
+    pub fn from_toml(toml: &str) -> Result<Self, ConfigError> {
+        let file = toml::from_str::<ConfigFile>(toml)?;
+        let mut config = SessionConfig::default();
+
+        let listen = file.listen;
+        config.listen.ip = listen.ip.unwrap_or(config.listen.ip);
+        config.listen.port = listen.port.unwrap_or(config.listen.port);
+        if let Some([start, end]) = listen.fallback {
+            config.listen.fallback = start..=end;
+        }
+        config.listen.additional = listen.additional;
+        config.listen.random_port = listen.random_port;
+
+        let limits = file.limits;
+        config.download_limit = limits.download.and_then(limit);
+        config.upload_limit = limits.upload.and_then(limit);
+        if let Some(connections) = limits.connections {
+            config.slots.max_connections = limit(connections);
+        }
+        if let Some(uploads) = limits.uploads {
+            config.slots.max_uploads = limit(uploads);
+        }
+        config.max_peers = limits.peers.and_then(limit);
+
+        let disk = file.disk;
+        config.quotas = disk.quota.map(DiskQuota::new).into_iter().collect();
+        if let Some(paths) = disk.paths {
+            config.paths = match paths {
+                Paths::Rename => PathPolicy::Rename,
+                Paths::Fail => PathPolicy::Fail,
+            };
+        }
+        config.part_suffix = disk.part_suffix.filter(|suffix| !suffix.is_empty());
+        config.dedup = disk.dedup.map(|dedup| match dedup {
+            DedupValue::Hardlink => Dedup::HardLink,
+            DedupValue::Copy => Dedup::Copy,
+        });
+        if let Some(threads) = disk.check_threads {
+            config.check.threads = threads.max(1);
+            config.check.read_ahead = threads.max(1) * 2;
+        }
+        if let Some(read_ahead) = disk.check_read_ahead {
+            config.check.read_ahead = read_ahead.max(1);
+        }
+        config.rotational = disk.rotational.unwrap_or(config.rotational);
+        if let Some(sync) = disk.sync {
+            config.sync = match sync {
+                SyncValue::Named(SyncName::Never) => SyncPolicy::Never,
+                SyncValue::Named(SyncName::Piece) => SyncPolicy::OnPiece,
+                SyncValue::Seconds(seconds) => SyncPolicy::Interval(Duration::from_secs(seconds)),
+            };
+        }
+
+        let peers = file.peers;
+        config.lazy_bitfield = peers.lazy_bitfield.unwrap_or(config.lazy_bitfield);
+        config.block_size = peers.block_size;
+        config.max_request = peers.max_request;
+        if let Some(depth) = peers.pipeline_depth {
+            config.pipeline.max_depth = depth.max(1);
+        }
+        config.pipeline.fixed = peers.fixed_pipeline.unwrap_or(config.pipeline.fixed);
+        if let Some(threshold) = peers.ban_threshold {
+            config.violations.threshold = threshold;
+        }
+        if let Some(seconds) = peers.ban_duration {
+            config.violations.ban_duration = Duration::from_secs(seconds);
+        }
+
+        config.categories = file.categories;
+
+        Ok(config)
+    }
+
+    /// Reads and parses a TOML configuration file, see [`from_toml`](Self::from_toml).
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let toml = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|source| ConfigError::Read {
+                path: path.to_owned(),
+                source,
+            })?;
+        Self::from_toml(&toml)
+    }
+
+    /// Checks the configuration file at `path` for changes every `interval`, yielding it again
+    /// each time it was modified, e.g. to pass to [`Session::apply_config`].
+    ///
+    /// A file that fails to load is reported once per change, so fixing it yields the new
+    /// configuration.
+    ///
+    /// [`Session::apply_config`]: super::Session::apply_config
+    pub fn watch(
+        path: impl Into<PathBuf>,
+        interval: Duration,
+    ) -> impl Stream<Item = Result<Self, ConfigError>> {
+        let path = path.into();
+        let seen = modified(&path);
+
+        stream::unfold((path, seen), move |(path, mut seen)| async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let modified = modified(&path);
+                if modified != seen {
+                    seen = modified;
+                    let config = Self::load(&path).await;
+                    return Some((config, (path, seen)));
+                }
+            }
+        })
+    }
+}
+
+/// When the file was last modified, `None` if it can't be read.
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
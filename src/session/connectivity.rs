@@ -0,0 +1,44 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+/// Whether peers can reach our listen port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connectivity {
+    /// Nothing is known yet: no peer has connected in, and no external address was reported.
+    Unknown,
+    /// A peer connected to us, so the port is reachable.
+    Open,
+    /// Our external address isn't one of ours, so we're behind a NAT that doesn't forward the
+    /// port, or hasn't yet.
+    Nated,
+    /// Our address is public, but no peer has managed to connect in.
+    Firewalled,
+}
+
+/// Classifies reachability from what's been observed so far.
+pub(super) fn classify(incoming: bool, external_ip: Option<IpAddr>) -> Connectivity {
+    if incoming {
+        return Connectivity::Open;
+    }
+
+    match external_ip {
+        Some(ip) if is_local(ip) => Connectivity::Firewalled,
+        Some(_) => Connectivity::Nated,
+        None => Connectivity::Unknown,
+    }
+}
+
+/// Whether `ip` is assigned to this host, judged by the source address the OS would pick to
+/// reach it. Connecting a UDP socket sends nothing.
+fn is_local(ip: IpAddr) -> bool {
+    let unspecified: IpAddr = match ip {
+        IpAddr::V4(_) => [0, 0, 0, 0].into(),
+        IpAddr::V6(_) => [0u16; 8].into(),
+    };
+
+    UdpSocket::bind(SocketAddr::new(unspecified, 0))
+        .and_then(|socket| {
+            socket.connect(SocketAddr::new(ip, 9))?;
+            socket.local_addr()
+        })
+        .is_ok_and(|local| local.ip() == ip)
+}
@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    download::Download,
+    info::{FileEntry, Info},
+    storage::Dedup,
+};
+
+use super::Session;
+
+/// Where a file of an added torrent is, indexed by what it holds.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Content {
+    info_hash: [u8; 20],
+    offset: usize,
+}
+
+impl Session {
+    /// Fills the files of a newly added torrent that another torrent of the session has complete
+    /// with their data, so they don't have to be downloaded. Returns how many were.
+    ///
+    /// Must run before the torrent's data is checked.
+    pub(super) async fn dedup(&self, info: &Info, download: &Download, dedup: Dedup) -> usize {
+        let mut adopted = 0;
+        for (entry, key) in content_keys(info) {
+            let Some(source) = self.find_content(key, entry.length()) else {
+                continue;
+            };
+            // Downloading the file is always the fallback, e.g. when hard links can't cross
+            // filesystems.
+            if let Ok(true) = download.store().adopt(entry.offset(), &source, dedup).await {
+                adopted += 1;
+            }
+        }
+
+        adopted
+    }
+
+    /// A file some added torrent has complete with the content `key` identifies.
+    fn find_content(&self, key: [u8; 20], length: usize) -> Option<PathBuf> {
+        let candidates = self.shared.content.lock().unwrap().get(&key).cloned()?;
+        let torrents = self.shared.torrents.lock().unwrap();
+        candidates.iter().find_map(|content| {
+            let added = torrents.get(&content.info_hash)?;
+            added.download.complete_file(content.offset, length)
+        })
+    }
+
+    /// Adds the files of a torrent to the content index, for later torrents to reuse.
+    pub(super) fn index_content(&self, info_hash: [u8; 20], info: &Info) {
+        let mut content = self.shared.content.lock().unwrap();
+        for (entry, key) in content_keys(info) {
+            content.entry(key).or_default().push(Content {
+                info_hash,
+                offset: entry.offset(),
+            });
+        }
+    }
+}
+
+/// The files of `info` that can be identified by their content across torrents: by their BEP 47
+/// `sha1` if they have one, otherwise by the hashes of their pieces if none of them hold data of
+/// another file.
+///
+/// Empty files and symlinks are left out.
+fn content_keys(info: &Info) -> Vec<(FileEntry, [u8; 20])> {
+    let piece_length = info.piece_length();
+    let mut keys = Vec::new();
+    // Where the data of the file after the current one starts, walking backwards.
+    let mut next_start = info.length();
+    for entry in info.files().into_iter().rev() {
+        if entry.length() == 0 || entry.symlink_target().is_some() {
+            continue;
+        }
+
+        let start = entry.offset();
+        let end = start + entry.length();
+        let mut hasher = Sha1::new();
+        hasher.update((entry.length() as u64).to_be_bytes());
+        let key = if let Some(sha1) = entry.sha1() {
+            hasher.update(b"sha1");
+            hasher.update(sha1);
+            Some(hasher.finalize().into())
+        } else if start % piece_length == 0
+            // The last piece may run on into padding, which is zeroes in every torrent, but not
+            // into another file.
+            && next_start >= end.next_multiple_of(piece_length).min(info.length())
+        {
+            hasher.update(b"pieces");
+            hasher.update((piece_length as u64).to_be_bytes());
+            (start / piece_length..end.div_ceil(piece_length))
+                .map(|index| info.piece_hash(index))
+                .collect::<Option<Vec<_>>>()
+                .map(|hashes| {
+                    hashes.iter().for_each(|hash| hasher.update(hash));
+                    hasher.finalize().into()
+                })
+        } else {
+            None
+        };
+
+        next_start = start;
+        if let Some(key) = key {
+            keys.push((entry, key));
+        }
+    }
+
+    keys
+}
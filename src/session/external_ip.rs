@@ -0,0 +1,40 @@
+use std::{net::IpAddr, time::Duration};
+
+use reqwest::{Client, Url};
+use tokio::task::JoinHandle;
+use url::ParseError;
+
+use super::Session;
+
+impl Session {
+    /// Asks the service at `url` for our external address now and then every `interval`,
+    /// recording it with [`set_external_ip`](Self::set_external_ip). The service must answer with
+    /// nothing but the address, like `https://api.ipify.org` does.
+    ///
+    /// Useful when trackers don't report `external ip`, e.g. for seedboxes with dynamic addresses.
+    /// Failed checks keep the last known address.
+    pub fn spawn_external_ip_check(
+        &self,
+        url: &str,
+        interval: Duration,
+    ) -> Result<JoinHandle<()>, ParseError> {
+        let url = Url::parse(url)?;
+        let shared = self.shared.clone();
+
+        Ok(tokio::spawn(async move {
+            let client = Client::new();
+            loop {
+                if let Some(ip) = fetch(&client, url.clone()).await {
+                    shared.set_external_ip(ip);
+                }
+                tokio::time::sleep(interval).await;
+            }
+        }))
+    }
+}
+
+async fn fetch(client: &Client, url: Url) -> Option<IpAddr> {
+    let response = client.get(url).send().await.ok()?;
+    let body = response.error_for_status().ok()?.text().await.ok()?;
+    body.trim().parse().ok()
+}
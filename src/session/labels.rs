@@ -0,0 +1,190 @@
+use std::{cmp::Reverse, collections::BTreeSet, path::PathBuf, sync::Arc};
+
+use crate::{
+    download::{Download, Stats},
+    error::{Result, UsageError},
+    info::Torrent,
+};
+
+use super::{Added, Session};
+
+/// Which torrents [`Session::stats`] and [`Session::torrents_matching`] report. The default
+/// matches every torrent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TorrentFilter {
+    /// Only torrents in this category.
+    pub category: Option<String>,
+    /// Only torrents carrying all of these labels.
+    pub labels: Vec<String>,
+}
+
+impl TorrentFilter {
+    pub fn category(category: impl Into<String>) -> Self {
+        Self {
+            category: Some(category.into()),
+            ..Self::default()
+        }
+    }
+
+    pub fn label(label: impl Into<String>) -> Self {
+        Self {
+            labels: vec![label.into()],
+            ..Self::default()
+        }
+    }
+
+    fn matches(&self, added: &Added) -> bool {
+        self.category
+            .as_ref()
+            .is_none_or(|category| added.category.as_ref() == Some(category))
+            && self.labels.iter().all(|label| added.labels.contains(label))
+    }
+}
+
+/// A torrent's [`Stats`] along with its category and labels.
+#[derive(Debug, Clone)]
+pub struct TorrentStats {
+    pub info_hash: [u8; 20],
+    pub category: Option<String>,
+    pub labels: BTreeSet<String>,
+    pub stats: Stats,
+}
+
+impl Session {
+    /// Adds a torrent under the path [`SessionConfig::categories`] maps `category` to, see
+    /// [`add`](Self::add).
+    ///
+    /// [`SessionConfig::categories`]: super::SessionConfig::categories
+    pub async fn add_to_category(
+        &self,
+        torrent: &Torrent,
+        category: &str,
+    ) -> Result<Arc<Download>> {
+        let root = self.category_path(category)?;
+        let download = self.insert(torrent, &root, false, None).await?;
+        self.update(torrent.info.calculate_info_hash()?, |added| {
+            added.category = Some(category.to_owned());
+        })?;
+        Ok(download)
+    }
+
+    /// Moves a torrent into `category`, or out of any with `None`. Its files stay where they are.
+    ///
+    /// Fails with [`UsageError::UnknownCategory`] if `category` isn't in
+    /// [`SessionConfig::categories`].
+    ///
+    /// [`SessionConfig::categories`]: super::SessionConfig::categories
+    pub fn set_category(&self, info_hash: [u8; 20], category: Option<&str>) -> Result<()> {
+        if let Some(category) = category {
+            self.category_path(category)?;
+        }
+        self.update(info_hash, |added| {
+            added.category = category.map(str::to_owned);
+        })
+    }
+
+    pub fn category(&self, info_hash: [u8; 20]) -> Option<String> {
+        let torrents = self.shared.torrents.lock().unwrap();
+        torrents.get(&info_hash)?.category.clone()
+    }
+
+    /// Tags a torrent with `label`. Returns whether it didn't have it already.
+    pub fn add_label(&self, info_hash: [u8; 20], label: impl Into<String>) -> Result<bool> {
+        let label = label.into();
+        let mut inserted = false;
+        self.update(info_hash, |added| inserted = added.labels.insert(label))?;
+        Ok(inserted)
+    }
+
+    /// Returns whether the torrent had `label`.
+    pub fn remove_label(&self, info_hash: [u8; 20], label: &str) -> Result<bool> {
+        let mut removed = false;
+        self.update(info_hash, |added| removed = added.labels.remove(label))?;
+        Ok(removed)
+    }
+
+    pub fn set_labels(
+        &self,
+        info_hash: [u8; 20],
+        labels: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<()> {
+        let labels = labels.into_iter().map(Into::into).collect();
+        self.update(info_hash, |added| added.labels = labels)
+    }
+
+    /// A torrent's labels, empty if it has none or wasn't added.
+    pub fn labels(&self, info_hash: [u8; 20]) -> BTreeSet<String> {
+        let torrents = self.shared.torrents.lock().unwrap();
+        torrents
+            .get(&info_hash)
+            .map(|added| added.labels.clone())
+            .unwrap_or_default()
+    }
+
+    /// Every torrent `filter` matches, highest priority first, like [`torrents`](Self::torrents).
+    pub fn torrents_matching(&self, filter: &TorrentFilter) -> Vec<Arc<Download>> {
+        let mut torrents = self
+            .shared
+            .torrents
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|added| filter.matches(added))
+            .map(|added| added.download.clone())
+            .collect::<Vec<_>>();
+        torrents.sort_by_key(|download| Reverse(download.priority()));
+        torrents
+    }
+
+    /// Stats of every torrent `filter` matches, highest priority first.
+    pub fn stats(&self, filter: &TorrentFilter) -> Vec<TorrentStats> {
+        let matching = self
+            .shared
+            .torrents
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, added)| filter.matches(added))
+            .map(|(&info_hash, added)| {
+                (
+                    info_hash,
+                    added.category.clone(),
+                    added.labels.clone(),
+                    added.download.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // Stats take the download's own locks, so not under the session's.
+        let mut stats = matching
+            .into_iter()
+            .map(|(info_hash, category, labels, download)| {
+                let priority = download.priority();
+                let torrent = TorrentStats {
+                    info_hash,
+                    category,
+                    labels,
+                    stats: download.stats(),
+                };
+                (priority, torrent)
+            })
+            .collect::<Vec<_>>();
+        stats.sort_by_key(|(priority, _)| Reverse(*priority));
+        stats.into_iter().map(|(_, torrent)| torrent).collect()
+    }
+
+    fn category_path(&self, category: &str) -> Result<PathBuf> {
+        let config = self.shared.config.lock().unwrap();
+        match config.categories.get(category) {
+            Some(path) => Ok(path.clone()),
+            None => Err(UsageError::UnknownCategory(category.to_owned()).into()),
+        }
+    }
+
+    pub(super) fn update(&self, info_hash: [u8; 20], f: impl FnOnce(&mut Added)) -> Result<()> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        let added = torrents.get_mut(&info_hash).ok_or(UsageError::NotAdded)?;
+        f(added);
+        Ok(())
+    }
+}
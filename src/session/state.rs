@@ -0,0 +1,181 @@
+use std::{cmp::Reverse, collections::BTreeSet, io, path::Path, path::PathBuf, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    download::{Download, Priority},
+    error::{Result, StorageError},
+    info::Torrent,
+    listener::ListenConfig,
+};
+
+use super::Session;
+
+/// What [`Session::save_state`] writes, bencoded.
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionState {
+    torrents: Vec<TorrentState>,
+    /// The listen port picked under [`ListenConfig::random_port`], to keep it across restarts.
+    #[serde(
+        default,
+        with = "crate::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    port: Option<u16>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TorrentState {
+    metainfo: ByteBuf,
+    root: PathBuf,
+    #[serde(rename = "seed only")]
+    seed_only: bool,
+    priority: Priority,
+    uploaded: u64,
+    downloaded: u64,
+    /// Pieces that made it to the disk, as a bitfield.
+    verified: ByteBuf,
+    #[serde(
+        default,
+        with = "crate::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    category: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    labels: BTreeSet<String>,
+}
+
+impl Session {
+    /// Saves every torrent to `path`: its metainfo, where its files are, whether it only seeds,
+    /// its priority, category and labels, transfer totals, and which pieces are complete.
+    /// [`load_state`] adds them all back, e.g. after a restart. A random listen port is saved
+    /// too, so it stays the same.
+    ///
+    /// Pieces are synced first, as with [`Download::save_progress`]. The file is replaced
+    /// atomically, so a crash while saving leaves the previous state intact.
+    ///
+    /// [`load_state`]: Self::load_state
+    pub async fn save_state(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut added = self
+            .shared
+            .torrents
+            .lock()
+            .unwrap()
+            .values()
+            .map(|added| {
+                (
+                    added.download.clone(),
+                    added.metainfo.clone(),
+                    added.root.clone(),
+                    added.seed_only,
+                    added.category.clone(),
+                    added.labels.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        added.sort_by_key(|(download, ..)| Reverse(download.priority()));
+
+        let mut torrents = Vec::with_capacity(added.len());
+        for (download, metainfo, root, seed_only, category, labels) in added {
+            let verified = download.durable_progress().await?;
+            let transfer = download.transfer();
+            torrents.push(TorrentState {
+                metainfo: ByteBuf::from(metainfo),
+                root,
+                seed_only,
+                priority: download.priority(),
+                uploaded: transfer.uploaded,
+                downloaded: transfer.downloaded,
+                verified: ByteBuf::from(verified.as_bytes().to_vec()),
+                category,
+                labels,
+            });
+        }
+
+        let path = path.as_ref();
+        let write_error = |source| StorageError::Write {
+            path: path.to_owned(),
+            source,
+        };
+        let port = self.config().listen.random_port.then(|| self.port());
+        let bytes = bendy::serde::to_bytes(&SessionState { torrents, port })
+            .map_err(|e| write_error(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+
+        let temporary = path.with_extension("tmp");
+        let write = async {
+            let mut file = tokio::fs::File::create(&temporary).await?;
+            file.write_all(&bytes).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&temporary, path).await
+        };
+        write.await.map_err(write_error)?;
+
+        Ok(())
+    }
+
+    /// Adds back the torrents saved by [`save_state`](Self::save_state) without rechecking their
+    /// data.
+    ///
+    /// Under [`ListenConfig::random_port`], the session moves to the saved port unless it's taken
+    /// by now.
+    ///
+    /// Fails only if the state can't be read. Each torrent is added on its own, so one whose
+    /// files have gone missing doesn't keep the others from coming back; the results are in the
+    /// order the torrents were saved, highest priority first.
+    pub async fn load_state(&self, path: impl AsRef<Path>) -> Result<Vec<Result<Arc<Download>>>> {
+        let path = path.as_ref();
+        let read_error = |source| StorageError::Read {
+            path: path.to_owned(),
+            source,
+        };
+
+        let bytes = tokio::fs::read(path).await.map_err(read_error)?;
+        let state = bendy::serde::from_bytes::<SessionState>(&bytes)
+            .map_err(|e| read_error(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+
+        let listen = self.config().listen;
+        if let Some(port) = state
+            .port
+            .filter(|&port| listen.random_port && port != self.port())
+        {
+            let saved = ListenConfig {
+                port,
+                fallback: port..=port,
+                random_port: false,
+                ..listen
+            };
+            // Staying on the new random port beats failing the whole restore.
+            let _ = self.rebind(&saved).await;
+        }
+
+        let mut results = Vec::with_capacity(state.torrents.len());
+        for torrent in state.torrents {
+            results.push(self.restore(torrent).await);
+        }
+
+        Ok(results)
+    }
+
+    async fn restore(&self, state: TorrentState) -> Result<Arc<Download>> {
+        let torrent = Torrent::from_bytes(&state.metainfo)?;
+        let download = self
+            .insert(
+                &torrent,
+                &state.root,
+                state.seed_only,
+                Some(&state.verified),
+            )
+            .await?;
+        download.set_priority(state.priority);
+        download.restore_transfer(state.uploaded, state.downloaded);
+        // Kept even if the category is gone from the configuration by now.
+        self.update(torrent.info.calculate_info_hash()?, |added| {
+            added.category = state.category;
+            added.labels = state.labels;
+        })?;
+
+        Ok(download)
+    }
+}
@@ -0,0 +1,389 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+/// Caps on what all torrents of a session use together. `None` means unlimited.
+#[derive(Debug, Clone, Copy)]
+pub struct SlotLimits {
+    /// Open peer connections, incoming and outgoing.
+    pub max_connections: Option<usize>,
+    /// Peers unchoked at once.
+    pub max_uploads: Option<usize>,
+}
+
+impl Default for SlotLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: Some(200),
+            max_uploads: Some(20),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Connection = 0,
+    Upload = 1,
+}
+
+/// Connection and upload slots shared by the torrents registered with it.
+///
+/// When slots are scarce, every torrent is guaranteed a share of each limit in proportion to its
+/// weight. Connection shares are kept for every torrent, upload shares only for torrents with a
+/// peer waiting to be unchoked; the rest go to whoever asks first.
+///
+/// Clones share the same slots.
+#[derive(Debug, Clone)]
+pub struct SlotPool {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    pool: Mutex<Pool>,
+    /// Woken when a slot frees up or the shares change.
+    released: Notify,
+}
+
+#[derive(Debug)]
+struct Pool {
+    limits: SlotLimits,
+    torrents: HashMap<u64, Usage>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Usage {
+    weight: u32,
+    used: [usize; 2],
+    waiting: [usize; 2],
+}
+
+impl Default for SlotPool {
+    /// An unlimited pool.
+    fn default() -> Self {
+        Self::new(SlotLimits {
+            max_connections: None,
+            max_uploads: None,
+        })
+    }
+}
+
+impl SlotPool {
+    pub fn new(limits: SlotLimits) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                pool: Mutex::new(Pool {
+                    limits,
+                    torrents: HashMap::new(),
+                    next_id: 0,
+                }),
+                released: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn limits(&self) -> SlotLimits {
+        self.inner.pool.lock().unwrap().limits
+    }
+
+    /// Changes the limits. Slots already taken beyond new limits are kept until released.
+    pub fn set_limits(&self, limits: SlotLimits) {
+        self.inner.pool.lock().unwrap().limits = limits;
+        self.inner.released.notify_waiters();
+    }
+
+    /// Open connections across all torrents.
+    pub fn connections(&self) -> usize {
+        self.inner.pool.lock().unwrap().used(Kind::Connection)
+    }
+
+    /// Unchoked peers across all torrents.
+    pub fn uploads(&self) -> usize {
+        self.inner.pool.lock().unwrap().used(Kind::Upload)
+    }
+
+    /// Adds a torrent whose share of the slots is proportional to `weight`.
+    pub fn register(&self, weight: u32) -> TorrentSlots {
+        let mut pool = self.inner.pool.lock().unwrap();
+        let id = pool.next_id;
+        pool.next_id += 1;
+        pool.torrents.insert(
+            id,
+            Usage {
+                weight,
+                used: [0; 2],
+                waiting: [0; 2],
+            },
+        );
+
+        TorrentSlots {
+            pool: self.clone(),
+            id,
+        }
+    }
+
+    fn try_take(&self, id: u64, kind: Kind) -> Option<SlotPermit> {
+        let mut pool = self.inner.pool.lock().unwrap();
+        if !pool.may_take(id, kind) {
+            return None;
+        }
+        pool.torrents.get_mut(&id)?.used[kind as usize] += 1;
+
+        Some(SlotPermit {
+            pool: self.clone(),
+            id,
+            kind,
+        })
+    }
+
+    /// Changes a torrent's usage, waking waiters if `wake` because slots may have become
+    /// available to them.
+    fn update(&self, id: u64, wake: bool, f: impl FnOnce(&mut Usage)) {
+        if let Some(usage) = self.inner.pool.lock().unwrap().torrents.get_mut(&id) {
+            f(usage);
+        }
+        if wake {
+            self.inner.released.notify_waiters();
+        }
+    }
+}
+
+impl Pool {
+    fn limit(&self, kind: Kind) -> Option<usize> {
+        match kind {
+            Kind::Connection => self.limits.max_connections,
+            Kind::Upload => self.limits.max_uploads,
+        }
+    }
+
+    fn used(&self, kind: Kind) -> usize {
+        self.torrents
+            .values()
+            .map(|usage| usage.used[kind as usize])
+            .sum()
+    }
+
+    /// The torrent's guaranteed slots under `limit`.
+    fn share(&self, usage: &Usage, limit: usize) -> usize {
+        let total = self
+            .torrents
+            .values()
+            .map(|usage| u64::from(usage.weight))
+            .sum::<u64>()
+            .max(1);
+        (limit as u64 * u64::from(usage.weight) / total) as usize
+    }
+
+    /// Slots kept free for torrents other than `id` that haven't used up their share.
+    fn reserved(&self, id: u64, kind: Kind, limit: usize) -> usize {
+        self.torrents
+            .iter()
+            .filter(|&(&other, usage)| {
+                other != id
+                    && (matches!(kind, Kind::Connection) || usage.waiting[kind as usize] > 0)
+            })
+            .map(|(_, usage)| {
+                self.share(usage, limit)
+                    .saturating_sub(usage.used[kind as usize])
+            })
+            .sum()
+    }
+
+    fn may_take(&self, id: u64, kind: Kind) -> bool {
+        let Some(limit) = self.limit(kind) else {
+            return true;
+        };
+        let Some(usage) = self.torrents.get(&id) else {
+            return false;
+        };
+        let used = self.used(kind);
+        if used >= limit {
+            return false;
+        }
+
+        usage.used[kind as usize] < self.share(usage, limit)
+            || limit - used > self.reserved(id, kind, limit)
+    }
+
+    /// Whether `id` holds more than its share while another torrent waits for its own.
+    fn over_share(&self, id: u64, kind: Kind) -> bool {
+        let (Some(limit), Some(usage)) = (self.limit(kind), self.torrents.get(&id)) else {
+            return false;
+        };
+        let free = limit.saturating_sub(self.used(kind));
+        usage.used[kind as usize] > self.share(usage, limit)
+            && free < self.reserved(id, kind, limit)
+    }
+}
+
+/// A torrent's place in a [`SlotPool`], given up when dropped.
+#[derive(Debug)]
+pub struct TorrentSlots {
+    pool: SlotPool,
+    id: u64,
+}
+
+impl Default for TorrentSlots {
+    /// A torrent alone in an unlimited pool.
+    fn default() -> Self {
+        SlotPool::default().register(1)
+    }
+}
+
+impl TorrentSlots {
+    pub fn pool(&self) -> &SlotPool {
+        &self.pool
+    }
+
+    pub fn weight(&self) -> u32 {
+        let pool = self.pool.inner.pool.lock().unwrap();
+        pool.torrents.get(&self.id).map_or(0, |usage| usage.weight)
+    }
+
+    /// Changes the torrent's share of the slots relative to the other torrents.
+    pub fn set_weight(&self, weight: u32) {
+        self.pool
+            .update(self.id, true, |usage| usage.weight = weight);
+    }
+
+    /// Takes a connection slot, or returns `None` if the torrent may not open another connection.
+    pub fn try_connection(&self) -> Option<SlotPermit> {
+        self.pool.try_take(self.id, Kind::Connection)
+    }
+
+    /// Waits for an upload slot to unchoke a peer with.
+    pub async fn upload(&self) -> SlotPermit {
+        self.pool.update(self.id, false, |usage| {
+            usage.waiting[Kind::Upload as usize] += 1
+        });
+        let _waiting = Waiting(self);
+
+        loop {
+            let released = self.pool.inner.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            if let Some(permit) = self.pool.try_take(self.id, Kind::Upload) {
+                return permit;
+            }
+            released.await;
+        }
+    }
+}
+
+impl Drop for TorrentSlots {
+    fn drop(&mut self) {
+        self.pool
+            .inner
+            .pool
+            .lock()
+            .unwrap()
+            .torrents
+            .remove(&self.id);
+        self.pool.inner.released.notify_waiters();
+    }
+}
+
+/// Stops counting a torrent as waiting for an upload slot once it got one or gave up.
+struct Waiting<'a>(&'a TorrentSlots);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let slots = self.0;
+        slots.pool.update(slots.id, true, |usage| {
+            usage.waiting[Kind::Upload as usize] -= 1
+        });
+    }
+}
+
+/// A taken slot, returned to the pool when dropped.
+#[derive(Debug)]
+pub struct SlotPermit {
+    pool: SlotPool,
+    id: u64,
+    kind: Kind,
+}
+
+impl SlotPermit {
+    /// Whether the torrent should give this slot back because it holds more than its share while
+    /// another torrent waits for its own.
+    pub fn should_yield(&self) -> bool {
+        self.pool
+            .inner
+            .pool
+            .lock()
+            .unwrap()
+            .over_share(self.id, self.kind)
+    }
+}
+
+impl Drop for SlotPermit {
+    fn drop(&mut self) {
+        let kind = self.kind as usize;
+        self.pool.update(self.id, true, |usage| {
+            usage.used[kind] = usage.used[kind].saturating_sub(1)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use futures::{poll, FutureExt};
+
+    use super::*;
+
+    fn pool(max_connections: usize, max_uploads: usize) -> SlotPool {
+        SlotPool::new(SlotLimits {
+            max_connections: Some(max_connections),
+            max_uploads: Some(max_uploads),
+        })
+    }
+
+    #[test]
+    fn splits_connections_by_weight() {
+        let pool = pool(4, 4);
+        let light = pool.register(1);
+        let heavy = pool.register(3);
+
+        let light_permits = std::iter::from_fn(|| light.try_connection()).collect::<Vec<_>>();
+        assert_eq!(light_permits.len(), 1);
+        let heavy_permits = std::iter::from_fn(|| heavy.try_connection()).collect::<Vec<_>>();
+        assert_eq!(heavy_permits.len(), 3);
+        assert_eq!(pool.connections(), 4);
+    }
+
+    #[tokio::test]
+    async fn reserves_uploads_only_for_waiting_torrents() {
+        let pool = pool(4, 4);
+        let light = pool.register(1);
+        let heavy = pool.register(3);
+
+        // Nobody else wants an upload slot, so the light torrent may take them all.
+        let mut light_permits = Vec::new();
+        while let Some(permit) = light.upload().now_or_never() {
+            light_permits.push(permit);
+        }
+        assert_eq!(light_permits.len(), 4);
+        assert!(!light_permits[0].should_yield());
+
+        let mut heavy_wait = pin!(heavy.upload());
+        assert!(poll!(&mut heavy_wait).is_pending());
+        assert!(light_permits.iter().all(SlotPermit::should_yield));
+
+        light_permits.pop();
+        let heavy_permit = poll!(&mut heavy_wait);
+        assert!(heavy_permit.is_ready());
+        // The heavy torrent is still short of its share, so nothing is left for the light one.
+        let mut heavy_wait = pin!(heavy.upload());
+        assert!(poll!(&mut heavy_wait).is_pending());
+        light_permits.pop();
+        assert!(light.upload().now_or_never().is_none());
+        assert!(poll!(&mut heavy_wait).is_ready());
+    }
+}
@@ -0,0 +1,588 @@
+use std::{
+    collections::BTreeSet,
+    error, fmt,
+    io::{ErrorKind, SeekFrom},
+    mem,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{error::StorageError, info::Info};
+
+pub use self::paths::PathPolicy;
+
+mod paths;
+
+type Result<T, E = StorageError> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+struct StoreFile {
+    path: PathBuf,
+    /// Where the file is written until it's complete, if that's not `path`.
+    part: Mutex<Option<PathBuf>>,
+    offset: usize,
+    length: usize,
+    /// Whether the file was created along with the store, so may be replaced by
+    /// [`FileStore::adopt`].
+    fresh: AtomicBool,
+}
+
+impl StoreFile {
+    fn new(path: PathBuf, part: Option<PathBuf>, offset: usize, length: usize) -> Self {
+        Self {
+            path,
+            part: Mutex::new(part),
+            offset,
+            length,
+            fresh: AtomicBool::new(false),
+        }
+    }
+
+    /// Where the file is right now.
+    fn current(&self) -> PathBuf {
+        let part = self.part.lock().unwrap();
+        part.as_ref().unwrap_or(&self.path).clone()
+    }
+
+    /// Opens the file wherever it is, following it if it was just renamed to its final path.
+    async fn open(&self, options: &OpenOptions) -> std::io::Result<fs::File> {
+        let path = self.current();
+        match options.open(&path).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && self.current() != path => {
+                options.open(self.current()).await
+            }
+            result => result,
+        }
+    }
+}
+
+/// Maps the torrent's concatenated byte stream onto the files in a download directory.
+#[derive(Debug)]
+pub struct FileStore {
+    files: Vec<StoreFile>,
+    /// Files written to since the last [`sync`](Self::sync), by index.
+    dirty: Mutex<BTreeSet<usize>>,
+    read_only: bool,
+    rotational: bool,
+    /// Held while renaming complete files, so each is renamed once.
+    finalizing: tokio::sync::Mutex<()>,
+    _reservations: Vec<Reservation>,
+}
+
+impl FileStore {
+    /// Creates and preallocates the torrent's files under `root`.
+    ///
+    /// Paths are sanitized first so no file ends up outside `root`, with `policy` deciding what
+    /// happens to unsafe or colliding ones.
+    ///
+    /// With a `part_suffix`, files that don't exist yet are written under their name with the
+    /// suffix appended, e.g. `movie.mkv.part`, and only renamed to their real name by
+    /// [`finalize`](Self::finalize) once complete, so other programs never see partial files.
+    ///
+    /// Fails with [`DiskFull`] if the bytes still to be allocated exceed the free space on the
+    /// target filesystem or the remaining budget of any of `quotas`.
+    pub async fn create(
+        root: impl AsRef<Path>,
+        info: &Info,
+        quotas: &[DiskQuota],
+        policy: PathPolicy,
+        part_suffix: Option<&str>,
+    ) -> Result<Self> {
+        let root = root.as_ref();
+        let entries = info.files();
+        let paths = paths::sanitize(&entries, policy)?;
+        fs::create_dir_all(root).await?;
+
+        // Files already at their final path, e.g. from before suffixes were configured, stay
+        // there.
+        let mut parts = Vec::with_capacity(paths.len());
+        for path in &paths {
+            let path = root.join(path);
+            let part = match part_suffix {
+                Some(suffix) if fs::symlink_metadata(&path).await.is_err() => {
+                    let mut part = path.into_os_string();
+                    part.push(suffix);
+                    Some(PathBuf::from(part))
+                }
+                _ => None,
+            };
+            parts.push(part);
+        }
+
+        let mut size = 0;
+        let mut required = 0;
+        let mut fresh = Vec::with_capacity(paths.len());
+        for ((entry, path), part) in entries.iter().zip(&paths).zip(&parts) {
+            size += entry.length() as u64;
+            let path = part.clone().unwrap_or_else(|| root.join(path));
+            let existing = match fs::metadata(path).await {
+                Ok(metadata) => Some(metadata.len()),
+                Err(_) => None,
+            };
+            required += (entry.length() as u64).saturating_sub(existing.unwrap_or(0));
+            fresh.push(existing.is_none());
+        }
+
+        let available = fs2::available_space(root)?;
+        if required > available {
+            return Err(DiskFull {
+                required,
+                available,
+            }
+            .into());
+        }
+
+        let reservations = quotas
+            .iter()
+            .map(|quota| quota.reserve(size))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut files = Vec::new();
+        for (((entry, path), part), fresh) in entries.iter().zip(&paths).zip(parts).zip(fresh) {
+            let depth = path.components().count();
+            let path = root.join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+
+            if let Some(target) = entry.symlink_components() {
+                // The target is relative to the torrent root, which is the first path component.
+                let target = paths::sanitize_target(target, policy)?;
+                let target = std::iter::repeat_n(Path::new(".."), depth.saturating_sub(2))
+                    .chain([target.as_path()])
+                    .collect::<PathBuf>();
+                create_symlink(&target, &path).await?;
+                continue;
+            }
+
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(part.as_ref().unwrap_or(&path))
+                .await?;
+            file.set_len(entry.length() as u64).await?;
+
+            #[cfg(unix)]
+            if entry.is_executable() {
+                use std::os::unix::fs::PermissionsExt;
+
+                let mut permissions = file.metadata().await?.permissions();
+                permissions.set_mode(permissions.mode() | 0o111);
+                file.set_permissions(permissions).await?;
+            }
+
+            let file = StoreFile::new(path, part, entry.offset(), entry.length());
+            file.fresh.store(fresh, Ordering::Relaxed);
+            files.push(file);
+        }
+
+        Ok(Self {
+            files,
+            dirty: Mutex::default(),
+            read_only: false,
+            rotational: false,
+            finalizing: tokio::sync::Mutex::default(),
+            _reservations: reservations,
+        })
+    }
+
+    /// Opens existing files under `root` without ever opening them for writing, e.g. to seed a
+    /// torrent straight from the files it was created from.
+    ///
+    /// Fails if a file is missing or its length doesn't match the metainfo, rather than creating
+    /// or resizing it. Symlinks and permissions are left as they are.
+    pub async fn open_read_only(
+        root: impl AsRef<Path>,
+        info: &Info,
+        policy: PathPolicy,
+    ) -> Result<Self> {
+        let root = root.as_ref();
+        let entries = info.files();
+        let paths = paths::sanitize(&entries, policy)?;
+
+        let mut files = Vec::new();
+        for (entry, path) in entries.iter().zip(&paths) {
+            if entry.symlink_components().is_some() {
+                continue;
+            }
+
+            let path = root.join(path);
+            let metadata = fs::metadata(&path)
+                .await
+                .map_err(|source| StorageError::Read {
+                    path: path.clone(),
+                    source,
+                })?;
+            if metadata.len() != entry.length() as u64 {
+                return Err(StorageError::LengthMismatch {
+                    path,
+                    expected: entry.length() as u64,
+                    actual: metadata.len(),
+                });
+            }
+
+            files.push(StoreFile::new(path, None, entry.offset(), entry.length()));
+        }
+
+        Ok(Self {
+            files,
+            dirty: Mutex::default(),
+            read_only: true,
+            rotational: false,
+            finalizing: tokio::sync::Mutex::default(),
+            _reservations: Vec::new(),
+        })
+    }
+
+    /// Whether the store was opened with [`open_read_only`](Self::open_read_only).
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Marks the files as living on a spinning disk, where seeks are slow. Downloads using the
+    /// store then pick pieces near the ones they last wrote, see [`Picker::with_locality`].
+    ///
+    /// [`Picker::with_locality`]: crate::picker::Picker::with_locality
+    pub fn with_rotational(mut self, rotational: bool) -> Self {
+        self.rotational = rotational;
+        self
+    }
+
+    pub fn is_rotational(&self) -> bool {
+        self.rotational
+    }
+
+    /// Reads `length` bytes at `offset` in the torrent's byte stream.
+    ///
+    /// Bytes falling into padding gaps between files read as zeroes.
+    pub async fn read(&self, offset: usize, length: usize) -> Result<Vec<u8>> {
+        let end = offset + length;
+        let mut data = vec![0; length];
+
+        for file in &self.files {
+            let file_end = file.offset + file.length;
+            if file_end <= offset {
+                continue;
+            }
+            if file.offset >= end {
+                break;
+            }
+
+            let start = offset.max(file.offset);
+            let chunk = &mut data[start - offset..file_end.min(end) - offset];
+
+            let read = async {
+                let mut handle = file.open(OpenOptions::new().read(true)).await?;
+                handle
+                    .seek(SeekFrom::Start((start - file.offset) as u64))
+                    .await?;
+                handle.read_exact(chunk).await
+            };
+            read.await.map_err(|source| StorageError::Read {
+                path: file.current(),
+                source,
+            })?;
+        }
+
+        Ok(data)
+    }
+
+    /// Writes `data` at `offset` in the torrent's byte stream.
+    ///
+    /// Bytes falling into padding gaps between files are dropped. Fails with [`DiskFull`] if the
+    /// filesystem has run out of room for them since the files were created.
+    pub async fn write(&self, offset: usize, data: &[u8]) -> Result<()> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+
+        let end = offset + data.len();
+
+        for (index, file) in self.files.iter().enumerate() {
+            let file_end = file.offset + file.length;
+            if file_end <= offset {
+                continue;
+            }
+            if file.offset >= end {
+                break;
+            }
+
+            let start = offset.max(file.offset);
+            let chunk = &data[start - offset..file_end.min(end) - offset];
+
+            // Files are allocated sparsely, so the disk can fill up after they were created.
+            let path = file.current();
+            let available = fs2::available_space(&path).map_err(|source| StorageError::Write {
+                path: path.clone(),
+                source,
+            })?;
+            if (chunk.len() as u64) > available {
+                return Err(DiskFull {
+                    required: chunk.len() as u64,
+                    available,
+                }
+                .into());
+            }
+
+            let write = async {
+                let mut handle = file.open(OpenOptions::new().write(true)).await?;
+                handle
+                    .seek(SeekFrom::Start((start - file.offset) as u64))
+                    .await?;
+                handle.write_all(chunk).await?;
+                handle.flush().await
+            };
+            self.dirty.lock().unwrap().insert(index);
+            write.await.map_err(|source| match source.kind() {
+                ErrorKind::StorageFull | ErrorKind::QuotaExceeded => DiskFull {
+                    required: chunk.len() as u64,
+                    available: 0,
+                }
+                .into(),
+                _ => StorageError::Write { path, source },
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes every file written to since the last sync from the OS cache to the disk.
+    pub async fn sync(&self) -> Result<()> {
+        let dirty = mem::take(&mut *self.dirty.lock().unwrap());
+
+        for &index in &dirty {
+            let file = &self.files[index];
+            let sync = async {
+                let handle = file.open(OpenOptions::new().write(true)).await?;
+                handle.sync_data().await
+            };
+            if let Err(source) = sync.await {
+                // Whatever wasn't synced still needs to be.
+                self.dirty.lock().unwrap().extend(dirty.range(index..));
+                return Err(StorageError::Write {
+                    path: file.current(),
+                    source,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every file can still be opened for reading and, unless read-only, writing,
+    /// e.g. after the user fixed permissions following a failed write.
+    pub async fn check(&self) -> Result<()> {
+        for file in &self.files {
+            file.open(OpenOptions::new().read(true).write(!self.read_only))
+                .await
+                .map_err(|source| StorageError::Write {
+                    path: file.current(),
+                    source,
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Where the file starting at `offset` in the torrent's byte stream is right now.
+    pub fn file_path(&self, offset: usize) -> Option<PathBuf> {
+        self.files
+            .iter()
+            .find(|file| file.offset == offset && file.length > 0)
+            .map(StoreFile::current)
+    }
+
+    /// Replaces the file starting at `offset` with the one at `source`, which must hold the same
+    /// data, instead of downloading it. The torrent should be rechecked afterwards.
+    ///
+    /// Only files that didn't exist before the store was created are replaced. Returns whether
+    /// the file was.
+    pub async fn adopt(&self, offset: usize, source: &Path, dedup: Dedup) -> Result<bool> {
+        let Some((index, file)) = self
+            .files
+            .iter()
+            .enumerate()
+            .find(|(_, file)| file.offset == offset && file.length > 0)
+        else {
+            return Ok(false);
+        };
+        if self.read_only || !file.fresh.swap(false, Ordering::Relaxed) {
+            return Ok(false);
+        }
+
+        // Going through a temporary name leaves the empty file in place if linking fails.
+        let path = file.current();
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".adopt");
+        let adopt = async {
+            match dedup {
+                Dedup::HardLink => fs::hard_link(source, &temporary).await?,
+                Dedup::Copy => {
+                    fs::copy(source, &temporary).await?;
+                }
+            }
+            fs::rename(&temporary, &path).await
+        };
+        if let Err(source) = adopt.await {
+            let _ = fs::remove_file(&temporary).await;
+            return Err(StorageError::Write { path, source });
+        }
+
+        if dedup == Dedup::Copy {
+            self.dirty.lock().unwrap().insert(index);
+        }
+        Ok(true)
+    }
+
+    /// Renames every file still written under a part suffix whose bytes `is_complete` says are
+    /// all there to its real name, syncing it first so the rename never exposes missing data.
+    pub async fn finalize(&self, is_complete: impl Fn(Range<usize>) -> bool) -> Result<()> {
+        let _finalizing = self.finalizing.lock().await;
+
+        for (index, file) in self.files.iter().enumerate() {
+            let Some(part) = file.part.lock().unwrap().clone() else {
+                continue;
+            };
+            if !is_complete(file.offset..file.offset + file.length) {
+                continue;
+            }
+
+            let rename = async {
+                if self.dirty.lock().unwrap().remove(&index) {
+                    let handle = OpenOptions::new().write(true).open(&part).await?;
+                    handle.sync_data().await?;
+                }
+                fs::rename(&part, &file.path).await
+            };
+            rename
+                .await
+                .map_err(|source| StorageError::Write { path: part, source })?;
+            *file.part.lock().unwrap() = None;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+async fn create_symlink(target: &Path, link: &Path) -> Result<()> {
+    if fs::symlink_metadata(link).await.is_ok() {
+        fs::remove_file(link).await?;
+    }
+    fs::symlink(target, link).await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn create_symlink(_target: &Path, link: &Path) -> Result<()> {
+    // Creating symlinks needs elevated privileges elsewhere, so fall back to an empty file.
+    fs::File::create(link).await?;
+    Ok(())
+}
+
+/// When written data is flushed from the OS cache to the disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Only when explicitly asked to, leaving the rest to the OS.
+    #[default]
+    Never,
+    /// After every verified piece is written.
+    OnPiece,
+    /// When a piece is written at least this long after the last sync.
+    Interval(Duration),
+}
+
+/// How a file another torrent already has complete is reused, see [`FileStore::adopt`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dedup {
+    /// Both torrents share the file's data on disk. It must be on the same filesystem, and
+    /// writing to it through one torrent, e.g. with
+    /// [`Download::redownload_file`](crate::download::Download::redownload_file), changes it for
+    /// both.
+    HardLink,
+    /// The data is copied, which takes the space and time but leaves the torrents independent.
+    Copy,
+}
+
+/// A byte budget shared by every [`FileStore`] created against it.
+///
+/// Clone a quota to share it between torrents, or create one per torrent for individual limits.
+#[derive(Debug, Clone)]
+pub struct DiskQuota {
+    limit: u64,
+    used: Arc<AtomicU64>,
+}
+
+impl DiskQuota {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Acquire)
+    }
+
+    fn reserve(&self, bytes: u64) -> Result<Reservation, DiskFull> {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.limit)
+            })
+            .map_err(|used| DiskFull {
+                required: bytes,
+                available: self.limit.saturating_sub(used),
+            })?;
+
+        Ok(Reservation {
+            bytes,
+            used: self.used.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Reservation {
+    bytes: u64,
+    used: Arc<AtomicU64>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[derive(Debug)]
+pub struct DiskFull {
+    pub required: u64,
+    pub available: u64,
+}
+
+impl fmt::Display for DiskFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "not enough disk space: {} bytes required, {} available",
+            self.required, self.available
+        )
+    }
+}
+
+impl error::Error for DiskFull {}
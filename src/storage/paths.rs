@@ -0,0 +1,125 @@
+//! Turns metainfo paths into paths that are safe to create under a download directory.
+//!
+//! This happens here rather than in the parser because the metainfo has to stay untouched for
+//! its info hash to match.
+
+use std::{collections::HashSet, path::PathBuf};
+
+use crate::{error::StorageError, info::FileEntry};
+
+/// What to do with file paths that are unsafe or collide with each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathPolicy {
+    /// Replace unsafe parts and number colliding files, like `name (1).ext`.
+    #[default]
+    Rename,
+    /// Refuse to create the torrent's files.
+    Fail,
+}
+
+/// Windows device names, which can't be used as file names with any extension.
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Sanitized relative paths of `entries`, in the same order.
+///
+/// Paths are compared case-insensitively when checking for collisions, since the torrent may
+/// be moved to a case-insensitive filesystem later.
+pub(super) fn sanitize(
+    entries: &[FileEntry],
+    policy: PathPolicy,
+) -> Result<Vec<PathBuf>, StorageError> {
+    let mut taken = HashSet::new();
+    let mut paths = Vec::with_capacity(entries.len());
+
+    for entry in entries {
+        let mut components = sanitize_components(entry.components(), policy)?;
+
+        if !taken.insert(fold(&components)) {
+            if policy == PathPolicy::Fail {
+                return Err(StorageError::PathCollision(entry.path().to_owned()));
+            }
+
+            let last = components.len() - 1;
+            let original = components[last].clone();
+            for n in 1.. {
+                components[last] = numbered(&original, n);
+                if taken.insert(fold(&components)) {
+                    break;
+                }
+            }
+        }
+
+        paths.push(components.iter().collect());
+    }
+
+    Ok(paths)
+}
+
+/// Sanitizes a symlink target, which must stay inside the torrent.
+pub(super) fn sanitize_target(
+    components: &[String],
+    policy: PathPolicy,
+) -> Result<PathBuf, StorageError> {
+    Ok(sanitize_components(components, policy)?.iter().collect())
+}
+
+fn sanitize_components(
+    components: &[String],
+    policy: PathPolicy,
+) -> Result<Vec<String>, StorageError> {
+    components
+        .iter()
+        .map(|component| match sanitize_component(component) {
+            Some(clean) if policy == PathPolicy::Fail => Err(StorageError::UnsafePath(format!(
+                "{component:?} (would be {clean:?})"
+            ))),
+            Some(clean) => Ok(clean),
+            None => Ok(component.clone()),
+        })
+        .collect()
+}
+
+/// Returns the safe replacement for `component`, or `None` if it's fine as is.
+fn sanitize_component(component: &str) -> Option<String> {
+    if component.is_empty() || component == "." || component == ".." {
+        return Some("_".to_owned());
+    }
+
+    let mut clean = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    // Windows strips trailing dots and spaces, which would make distinct names collide.
+    let trimmed = clean.trim_end_matches(['.', ' ']).len();
+    if trimmed < clean.len() {
+        clean.truncate(trimmed);
+        clean.push('_');
+    }
+
+    let stem = clean.split('.').next().unwrap_or_default();
+    if RESERVED.iter().any(|name| name.eq_ignore_ascii_case(stem)) {
+        clean.insert(stem.len(), '_');
+    }
+
+    (clean != component).then_some(clean)
+}
+
+fn fold(components: &[String]) -> Vec<String> {
+    components.iter().map(|c| c.to_lowercase()).collect()
+}
+
+/// `name (n).ext`, keeping the extension so the file still opens with the right program.
+fn numbered(name: &str, n: usize) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{stem} ({n}).{ext}"),
+        _ => format!("{name} ({n})"),
+    }
+}
@@ -0,0 +1,163 @@
+//! Serves the files of a download over HTTP while it's still in progress.
+//!
+//! Each file is exposed at `/<index>`, in the order of [`Download::files`], with `Range` support
+//! so media players can seek. Requested ranges are prioritized and sent as their pieces verify.
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    task::JoinHandle,
+};
+
+use crate::download::Download;
+
+/// Longest request head accepted before the connection is dropped.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// An HTTP server for one download. Stops when dropped.
+#[derive(Debug)]
+pub struct StreamServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl StreamServer {
+    pub async fn bind(addr: impl ToSocketAddrs, download: Arc<Download>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let download = download.clone();
+                tokio::spawn(async move {
+                    let _ = serve(stream, &download).await;
+                });
+            }
+        });
+
+        Ok(Self { addr, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// URL of the file at `index`, e.g. to hand to a media player.
+    pub fn url(&self, index: usize) -> String {
+        format!("http://{}/{index}", self.addr)
+    }
+}
+
+impl Drop for StreamServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Answers a single request, closing the connection afterwards.
+async fn serve(mut stream: TcpStream, download: &Download) -> io::Result<()> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEAD || stream.read_buf(&mut head).await? == 0 {
+            return Ok(());
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let range = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("range").then(|| value.trim())
+    });
+
+    if method != "GET" && method != "HEAD" {
+        return respond(&mut stream, "405 Method Not Allowed", &[]).await;
+    }
+
+    let files = download.files();
+    let Some(file) = target
+        .strip_prefix('/')
+        .and_then(|index| index.parse::<usize>().ok())
+        .and_then(|index| files.get(index))
+    else {
+        return respond(&mut stream, "404 Not Found", &[]).await;
+    };
+
+    let length = file.length();
+    let (status, start, end) = match range {
+        Some(range) => match parse_range(range, length) {
+            Some((start, end)) => ("206 Partial Content", start, end),
+            None => {
+                let content_range = format!("bytes */{length}");
+                return respond(
+                    &mut stream,
+                    "416 Range Not Satisfiable",
+                    &[("Content-Range", &content_range)],
+                )
+                .await;
+            }
+        },
+        None => ("200 OK", 0, length),
+    };
+
+    let content_length = (end - start).to_string();
+    let content_range = format!("bytes {start}-{}/{length}", end.saturating_sub(1));
+    let mut headers = vec![
+        ("Accept-Ranges", "bytes"),
+        ("Content-Type", "application/octet-stream"),
+        ("Content-Length", content_length.as_str()),
+    ];
+    if range.is_some() {
+        headers.push(("Content-Range", &content_range));
+    }
+    respond(&mut stream, status, &headers).await?;
+
+    if method == "GET" {
+        download
+            .write_range(file.offset() + start, end - start, &mut stream)
+            .await
+            .map_err(io::Error::other)?;
+    }
+    stream.shutdown().await
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, headers: &[(&str, &str)]) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !headers.iter().any(|(name, _)| *name == "Content-Length") {
+        head.push_str("Content-Length: 0\r\n");
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await
+}
+
+/// Parses a single-range `Range` header into a half-open byte range of a `length`-byte file.
+///
+/// Only the first range of a multi-range request is served.
+fn parse_range(header: &str, length: usize) -> Option<(usize, usize)> {
+    let spec = header.strip_prefix("bytes=")?.split(',').next()?.trim();
+    let (start, end) = spec.split_once('-')?;
+
+    let (start, end) = if start.is_empty() {
+        // A suffix range: the last `end` bytes.
+        let suffix = end.parse::<usize>().ok()?;
+        (length.saturating_sub(suffix), length)
+    } else {
+        let start = start.parse::<usize>().ok()?;
+        let end = match end {
+            "" => length,
+            end => end.parse::<usize>().ok()?.saturating_add(1).min(length),
+        };
+        (start, end)
+    };
+
+    (start < end).then_some((start, end))
+}
@@ -0,0 +1,213 @@
+//! Fixtures for exercising the engine end to end inside `cargo test`.
+
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rand::{thread_rng, Rng, RngCore};
+
+use crate::{
+    download::Download,
+    error::{Result, StorageError},
+    info::{Builder, Torrent},
+    listener::ListenConfig,
+    session::{Session, SessionConfig},
+};
+
+pub use self::{
+    interop::Transmission,
+    tracker::{Announce, MockTracker},
+};
+
+mod interop;
+mod tracker;
+
+/// Two sessions on localhost, one seeding a generated torrent and one with nothing downloaded.
+///
+/// Everything lives in a temporary directory that's removed on drop.
+pub struct Loopback {
+    pub seeder: Session,
+    pub leecher: Session,
+    pub seed: Arc<Download>,
+    pub leech: Arc<Download>,
+    pub info_hash: [u8; 20],
+    dir: PathBuf,
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl Loopback {
+    /// Generates files of the given sizes filled with random bytes, and adds a torrent of them to
+    /// both sessions.
+    pub async fn new(sizes: &[usize], piece_length: usize) -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "torrant-loopback-{:016x}",
+            thread_rng().gen::<u64>()
+        ));
+        let content = dir.join("seed").join("payload");
+        fs::create_dir_all(&content).map_err(StorageError::from)?;
+
+        let mut files = Vec::new();
+        for (index, &size) in sizes.iter().enumerate() {
+            let mut data = vec![0; size];
+            thread_rng().fill_bytes(&mut data);
+
+            let path = PathBuf::from("payload").join(format!("{index}.bin"));
+            fs::write(dir.join("seed").join(&path), &data).map_err(StorageError::from)?;
+            files.push((path, data));
+        }
+
+        let bytes = Builder::new(&content)
+            .piece_length(piece_length)
+            // Nothing is ever announced; the sessions are connected directly.
+            .tier(vec!["http://127.0.0.1:1/announce".to_owned()])
+            .build(|_| {})?;
+        let torrent = Torrent::from_bytes(&bytes)?;
+        let info_hash = torrent.info.calculate_info_hash()?;
+
+        let config = SessionConfig {
+            listen: ListenConfig {
+                ip: Ipv4Addr::LOCALHOST.into(),
+                port: 0,
+                // Port 0 can't be in use, so no fallback is needed.
+                fallback: 0..=0,
+                additional: Vec::new(),
+                random_port: false,
+            },
+            ..SessionConfig::default()
+        };
+        let seeder = Session::new(config.clone()).await?;
+        let leecher = Session::new(config).await?;
+
+        let seed = seeder.seed(&torrent, dir.join("seed")).await?;
+        let leech = leecher.add(&torrent, dir.join("leech")).await?;
+
+        Ok(Self {
+            seeder,
+            leecher,
+            seed,
+            leech,
+            info_hash,
+            dir,
+            files,
+        })
+    }
+
+    /// Has the leecher dial the seeder.
+    pub async fn connect(&self) -> Result<()> {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, self.seeder.port()));
+        self.leecher.connect(self.info_hash, addr).await?;
+        Ok(())
+    }
+
+    /// Where the leecher stores the torrent.
+    pub fn leech_root(&self) -> PathBuf {
+        self.dir.join("leech")
+    }
+
+    /// Generated files, relative to a session's download root, with their contents.
+    pub fn files(&self) -> impl Iterator<Item = (&Path, &[u8])> {
+        self.files
+            .iter()
+            .map(|(path, data)| (path.as_path(), data.as_slice()))
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::peer_list::PeerSource;
+
+    #[tokio::test]
+    async fn leecher_downloads_from_seeder() {
+        let loopback = Loopback::new(&[100_000, 0, 40_000, 16_384], 32 * 1024)
+            .await
+            .unwrap();
+        assert!(loopback.seed.verified().is_complete());
+        assert_eq!(loopback.leech.verified().count_ones(), 0);
+
+        loopback.connect().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(30), loopback.leech.wait_complete())
+            .await
+            .unwrap()
+            .unwrap();
+
+        for (path, data) in loopback.files() {
+            assert_eq!(fs::read(loopback.leech_root().join(path)).unwrap(), data);
+        }
+
+        let total = 100_000 + 40_000 + 16_384;
+        assert_eq!(loopback.leech.transfer().downloaded, total);
+        assert_eq!(loopback.leech.transfer().left, 0);
+    }
+
+    #[tokio::test]
+    async fn seeder_counts_uploads() {
+        let loopback = Loopback::new(&[50_000], 16 * 1024).await.unwrap();
+
+        loopback.connect().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(30), loopback.leech.wait_complete())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(loopback.seed.transfer().uploaded, 50_000);
+        assert_eq!(loopback.seed.transfer().left, 0);
+    }
+
+    #[tokio::test]
+    async fn dials_candidates_in_canonical_order() {
+        let loopback = Loopback::new(&[1000], 16 * 1024).await.unwrap();
+        loopback
+            .leecher
+            .set_external_ip("123.213.32.10".parse().unwrap());
+        let near = "123.213.32.234:6881".parse().unwrap();
+        let far = "98.76.54.32:6881".parse().unwrap();
+        loopback.leech.add_peer(near, PeerSource::Tracker);
+        loopback.leech.add_peer(far, PeerSource::Tracker);
+
+        let candidates = loopback.leecher.candidates(loopback.info_hash).unwrap();
+
+        assert_eq!(candidates, [far, near]);
+    }
+
+    #[tokio::test]
+    async fn redownload_keeps_neighbouring_file() {
+        let loopback = Loopback::new(&[20_000, 30_000], 16 * 1024).await.unwrap();
+        for (path, data) in loopback.files() {
+            fs::write(loopback.leech_root().join(path), data).unwrap();
+        }
+        assert_eq!(loopback.leech.recheck().await.unwrap(), 4);
+
+        // The second file shares its first piece with the first one.
+        assert_eq!(loopback.leech.redownload_file(1).await.unwrap(), 3);
+
+        let files = loopback.files().collect::<Vec<_>>();
+        let root = loopback.leech_root();
+        assert_eq!(fs::read(root.join(files[0].0)).unwrap(), files[0].1);
+        assert_eq!(fs::read(root.join(files[1].0)).unwrap(), vec![0; 30_000]);
+        assert_eq!(loopback.leech.verified().count_ones(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_torrent() {
+        let loopback = Loopback::new(&[1000], 16 * 1024).await.unwrap();
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, loopback.seeder.port()));
+
+        assert!(
+            crate::peer::connect([0; 20], loopback.leecher.peer_id(), addr)
+                .await
+                .is_err()
+        );
+    }
+}
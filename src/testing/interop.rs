@@ -0,0 +1,269 @@
+//! Interoperability checks against a locally installed Transmission daemon.
+//!
+//! The tests are ignored because they need `transmission-daemon` and `transmission-remote` on the
+//! `PATH`, or wherever `TORRANT_TRANSMISSION_DAEMON` and `TORRANT_TRANSMISSION_REMOTE` point. Run
+//! them with `cargo test --features testing interop -- --ignored`.
+
+use std::{
+    env, fs, io,
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    time::{Duration, Instant},
+};
+
+use rand::{thread_rng, Rng};
+
+/// A `transmission-daemon` on localhost with its own configuration, killed on drop.
+pub struct Transmission {
+    child: Child,
+    config_dir: PathBuf,
+    rpc_port: u16,
+    peer_port: u16,
+}
+
+impl Transmission {
+    /// Starts a daemon storing torrents under `download_dir`, where it also finds data to seed.
+    ///
+    /// DHT, local peer discovery, and port mapping are off, so it only ever talks to peers handed
+    /// out by trackers or dialing in.
+    pub async fn start(download_dir: &Path) -> io::Result<Self> {
+        let config_dir = env::temp_dir().join(format!(
+            "torrant-transmission-{:016x}",
+            thread_rng().gen::<u64>()
+        ));
+        fs::create_dir_all(&config_dir)?;
+        let rpc_port = free_port()?;
+        let peer_port = free_port()?;
+
+        let child = Command::new(daemon_binary())
+            .arg("--foreground")
+            .arg("--config-dir")
+            .arg(&config_dir)
+            .arg("--download-dir")
+            .arg(download_dir)
+            .args(["--port", &rpc_port.to_string()])
+            .args(["--peerport", &peer_port.to_string()])
+            .args(["--no-auth", "--no-dht", "--no-lpd", "--no-portmap"])
+            .arg("--encryption-tolerated")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let transmission = Self {
+            child,
+            config_dir,
+            rpc_port,
+            peer_port,
+        };
+
+        // The RPC server takes a moment to come up.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while transmission.remote(&["--list"]).is_err() {
+            if Instant::now() > deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "transmission-daemon didn't start",
+                ));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        Ok(transmission)
+    }
+
+    /// Where peers reach the daemon.
+    pub fn peer_addr(&self) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, self.peer_port))
+    }
+
+    /// Adds a `.torrent` file and starts it, verifying any data already in the download directory.
+    pub fn add(&self, torrent: &Path) -> io::Result<()> {
+        self.remote(&["--add", &torrent.to_string_lossy()])?;
+        Ok(())
+    }
+
+    /// How much of the first torrent the daemon has verified, from 0 to 100.
+    pub fn percent_done(&self) -> io::Result<f64> {
+        let info = self.remote(&["--torrent", "1", "--info"])?;
+        info.lines()
+            .find_map(|line| line.trim().strip_prefix("Percent Done:"))
+            .and_then(|percent| percent.trim().trim_end_matches('%').parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, info))
+    }
+
+    /// Waits until the first torrent is complete on the daemon's side.
+    pub async fn wait_complete(&self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.percent_done()? < 100.0 {
+            if Instant::now() > deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "transmission didn't complete the torrent",
+                ));
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        Ok(())
+    }
+
+    fn remote(&self, args: &[&str]) -> io::Result<String> {
+        let Output { status, stdout, .. } = Command::new(remote_binary())
+            .arg(self.rpc_port.to_string())
+            .args(args)
+            .stderr(Stdio::null())
+            .output()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "transmission-remote {args:?} failed with {status}"
+            )));
+        }
+        Ok(String::from_utf8_lossy(&stdout).into_owned())
+    }
+}
+
+impl Drop for Transmission {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.config_dir);
+    }
+}
+
+fn daemon_binary() -> String {
+    env::var("TORRANT_TRANSMISSION_DAEMON").unwrap_or_else(|_| "transmission-daemon".into())
+}
+
+fn remote_binary() -> String {
+    env::var("TORRANT_TRANSMISSION_REMOTE").unwrap_or_else(|_| "transmission-remote".into())
+}
+
+/// A port nothing listens on right now.
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddrV4, time::Duration};
+
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        info::{Builder, Torrent},
+        listener::ListenConfig,
+        session::{Session, SessionConfig},
+        testing::MockTracker,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Random files in `dir/seed/payload` and a torrent of them announcing to `tracker`.
+    fn fixture(dir: &Path, tracker: &MockTracker) -> (PathBuf, Torrent, Vec<(PathBuf, Vec<u8>)>) {
+        let content = dir.join("seed").join("payload");
+        fs::create_dir_all(&content).unwrap();
+
+        // Odd sizes so the last piece and a piece spanning files both come up.
+        let files = [300_000, 5, 70_001]
+            .iter()
+            .enumerate()
+            .map(|(index, &size)| {
+                let mut data = vec![0; size];
+                thread_rng().fill_bytes(&mut data);
+                let path = PathBuf::from("payload").join(format!("{index}.bin"));
+                fs::write(dir.join("seed").join(&path), &data).unwrap();
+                (path, data)
+            })
+            .collect();
+
+        let bytes = Builder::new(&content)
+            .piece_length(32 * 1024)
+            .tier(vec![tracker.url()])
+            .build(|_| {})
+            .unwrap();
+        let path = dir.join("fixture.torrent");
+        fs::write(&path, &bytes).unwrap();
+
+        (path, Torrent::from_bytes(&bytes).unwrap(), files)
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "torrant-interop-{:016x}",
+            thread_rng().gen::<u64>()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn session() -> Session {
+        Session::new(SessionConfig {
+            listen: ListenConfig {
+                ip: Ipv4Addr::LOCALHOST.into(),
+                port: 0,
+                fallback: 0..=0,
+                additional: Vec::new(),
+                random_port: false,
+            },
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs transmission-daemon"]
+    async fn downloads_from_transmission() {
+        let dir = temp_dir();
+        let tracker = MockTracker::start().await.unwrap();
+        let (path, torrent, files) = fixture(&dir, &tracker);
+
+        let transmission = Transmission::start(&dir.join("seed")).await.unwrap();
+        transmission.add(&path).unwrap();
+        transmission.wait_complete(TIMEOUT).await.unwrap();
+
+        let session = session().await;
+        let download = session.add(&torrent, dir.join("leech")).await.unwrap();
+        let info_hash = torrent.info.calculate_info_hash().unwrap();
+        session
+            .connect(info_hash, transmission.peer_addr())
+            .await
+            .unwrap();
+        tokio::time::timeout(TIMEOUT, download.wait_complete())
+            .await
+            .expect("download timed out")
+            .unwrap();
+
+        for (path, data) in &files {
+            assert_eq!(&fs::read(dir.join("leech").join(path)).unwrap(), data);
+        }
+        drop(transmission);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs transmission-daemon"]
+    async fn seeds_to_transmission() {
+        let dir = temp_dir();
+        let tracker = MockTracker::start().await.unwrap();
+        let (path, torrent, files) = fixture(&dir, &tracker);
+
+        let session = session().await;
+        session.seed(&torrent, dir.join("seed")).await.unwrap();
+        // Transmission only learns about us from the tracker.
+        tracker.set_peers(vec![SocketAddrV4::new(Ipv4Addr::LOCALHOST, session.port())]);
+
+        let transmission = Transmission::start(&dir.join("leech")).await.unwrap();
+        transmission.add(&path).unwrap();
+        transmission.wait_complete(TIMEOUT).await.unwrap();
+
+        for (path, data) in &files {
+            assert_eq!(&fs::read(dir.join("leech").join(path)).unwrap(), data);
+        }
+        drop(transmission);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
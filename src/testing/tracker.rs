@@ -0,0 +1,484 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+/// An announce as received by [`MockTracker`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub path: String,
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+    pub port: Option<u16>,
+    pub uploaded: Option<u64>,
+    pub downloaded: Option<u64>,
+    pub left: Option<u64>,
+    pub event: Option<String>,
+    pub ip: Option<String>,
+    /// Every query parameter in order, percent-decoded.
+    pub params: Vec<(String, Vec<u8>)>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    announces: Vec<Announce>,
+    peers: Vec<SocketAddrV4>,
+    interval: u64,
+    seeders: Option<u64>,
+    leechers: Option<u64>,
+    external_ip: Option<IpAddr>,
+    failure: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    #[serde(
+        rename = "failure reason",
+        serialize_with = "crate::optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    failure_reason: Option<String>,
+    interval: u64,
+    #[serde(
+        serialize_with = "crate::optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    complete: Option<u64>,
+    #[serde(
+        serialize_with = "crate::optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    incomplete: Option<u64>,
+    peers: ByteBuf,
+    #[serde(
+        rename = "external ip",
+        serialize_with = "crate::optional::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    external_ip: Option<ByteBuf>,
+}
+
+/// An HTTP tracker on localhost that records every announce and answers with a configurable
+/// compact peer list.
+pub struct MockTracker {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+impl MockTracker {
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State {
+            interval: 1800,
+            ..State::default()
+        }));
+
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        let _ = serve(stream, &state).await;
+                    });
+                }
+            }
+        });
+
+        Ok(Self { addr, state, task })
+    }
+
+    /// The announce URL.
+    pub fn url(&self) -> String {
+        format!("http://{}/announce", self.addr)
+    }
+
+    pub fn set_peers(&self, peers: Vec<SocketAddrV4>) {
+        self.state.lock().unwrap().peers = peers;
+    }
+
+    pub fn set_interval(&self, interval: u64) {
+        self.state.lock().unwrap().interval = interval;
+    }
+
+    pub fn set_counts(&self, seeders: Option<u64>, leechers: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.seeders = seeders;
+        state.leechers = leechers;
+    }
+
+    pub fn set_external_ip(&self, ip: Option<IpAddr>) {
+        self.state.lock().unwrap().external_ip = ip;
+    }
+
+    /// Makes every following announce fail with `reason`, or succeed again for `None`.
+    pub fn fail_with(&self, reason: Option<&str>) {
+        self.state.lock().unwrap().failure = reason.map(str::to_owned);
+    }
+
+    /// Announces received so far, oldest first.
+    pub fn announces(&self) -> Vec<Announce> {
+        self.state.lock().unwrap().announces.clone()
+    }
+}
+
+impl Drop for MockTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(mut stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        if stream.read_buf(&mut request).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+
+    let announce = std::str::from_utf8(&request)
+        .ok()
+        .and_then(|request| request.lines().next())
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(parse_announce)
+        .ok_or(io::ErrorKind::InvalidData)?;
+
+    let body = {
+        let mut state = state.lock().unwrap();
+        state.announces.push(announce);
+
+        let response = Response {
+            failure_reason: state.failure.clone(),
+            interval: state.interval,
+            complete: state.seeders,
+            incomplete: state.leechers,
+            peers: ByteBuf::from(
+                state
+                    .peers
+                    .iter()
+                    .flat_map(|peer| {
+                        let mut entry = peer.ip().octets().to_vec();
+                        entry.extend_from_slice(&peer.port().to_be_bytes());
+                        entry
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            external_ip: state.external_ip.map(|ip| {
+                ByteBuf::from(match ip {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                })
+            }),
+        };
+        bendy::serde::to_bytes(&response).map_err(io::Error::other)?
+    };
+
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+fn parse_announce(target: &str) -> Option<Announce> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let params = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((
+                String::from_utf8(percent_decode(key)?).ok()?,
+                percent_decode(value)?,
+            ))
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    let get = |key: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.clone())
+    };
+    let text = |key: &str| get(key).and_then(|value| String::from_utf8(value).ok());
+
+    Some(Announce {
+        path: path.to_owned(),
+        info_hash: get("info_hash").unwrap_or_default(),
+        peer_id: get("peer_id").unwrap_or_default(),
+        port: text("port").and_then(|v| v.parse().ok()),
+        uploaded: text("uploaded").and_then(|v| v.parse().ok()),
+        downloaded: text("downloaded").and_then(|v| v.parse().ok()),
+        left: text("left").and_then(|v| v.parse().ok()),
+        event: text("event"),
+        ip: text("ip"),
+        params,
+    })
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+    }
+    Some(out)
+}
+
+#[cfg(all(test, feature = "http"))]
+mod tests {
+    use super::*;
+    use crate::{
+        download::TransferStats,
+        info::Torrent,
+        peer_id::PeerId,
+        tracker::{AnnounceMode, Tracker, TrackerTiers},
+    };
+
+    /// A single-file torrent with one tier per tracker.
+    fn torrent(tiers: &[&MockTracker]) -> Torrent {
+        let string = |s: &str| format!("{}:{s}", s.len());
+        let list = tiers
+            .iter()
+            .map(|tracker| format!("l{}e", string(&tracker.url())))
+            .collect::<String>();
+        let bytes = format!(
+            "d8:announce{}13:announce-listl{list}e4:infod6:lengthi1e4:name1:x12:piece lengthi16384e6:pieces20:{}ee",
+            string(&tiers[0].url()),
+            "0".repeat(20),
+        );
+
+        bendy::serde::from_bytes(bytes.as_bytes()).unwrap()
+    }
+
+    const INFO_HASH: [u8; 20] = *b"\x00\x01 &?=+%\xff\xfeabcdefghij";
+
+    fn stats(left: u64) -> TransferStats {
+        TransferStats {
+            uploaded: 1,
+            downloaded: 2,
+            left,
+        }
+    }
+
+    #[tokio::test]
+    async fn records_announce_parameters() {
+        let mock = MockTracker::start().await.unwrap();
+        let peer_id = PeerId::generate();
+
+        let mut tracker = Tracker::new(&mock.url()).unwrap();
+        tracker
+            .announce(INFO_HASH, peer_id, 6881, stats(100))
+            .await
+            .unwrap();
+
+        let announces = mock.announces();
+        assert_eq!(announces.len(), 1);
+        assert_eq!(announces[0].path, "/announce");
+        assert_eq!(announces[0].info_hash, INFO_HASH);
+        assert_eq!(announces[0].peer_id, peer_id.as_bytes());
+        assert_eq!(announces[0].port, Some(6881));
+        assert_eq!(announces[0].uploaded, Some(1));
+        assert_eq!(announces[0].downloaded, Some(2));
+        assert_eq!(announces[0].left, Some(100));
+        assert_eq!(announces[0].event.as_deref(), Some("started"));
+    }
+
+    #[tokio::test]
+    async fn serves_peers_and_counts() {
+        let mock = MockTracker::start().await.unwrap();
+        let peers = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "10.0.0.2:51413".parse().unwrap(),
+        ];
+        mock.set_peers(peers.clone());
+        mock.set_counts(Some(3), Some(7));
+        mock.set_interval(900);
+
+        let mut tracker = Tracker::new(&mock.url()).unwrap();
+        let response = tracker
+            .announce(INFO_HASH, PeerId::generate(), 6881, stats(100))
+            .await
+            .unwrap();
+
+        let expected = peers.into_iter().map(SocketAddr::V4).collect::<Vec<_>>();
+        assert_eq!(response.peers(), expected);
+        assert_eq!(response.seeders(), Some(3));
+        assert_eq!(response.leechers(), Some(7));
+        assert_eq!(response.interval().as_secs(), 900);
+    }
+
+    #[tokio::test]
+    async fn sends_lifecycle_events_in_order() {
+        let mock = MockTracker::start().await.unwrap();
+        let peer_id = PeerId::generate();
+        let mut tracker = Tracker::new(&mock.url()).unwrap();
+
+        for left in [100, 50, 0, 0] {
+            tracker
+                .announce(INFO_HASH, peer_id, 6881, stats(left))
+                .await
+                .unwrap();
+        }
+        tracker
+            .stop(INFO_HASH, peer_id, 6881, stats(0))
+            .await
+            .unwrap();
+
+        let events = mock
+            .announces()
+            .into_iter()
+            .map(|announce| announce.event)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                Some("started".to_owned()),
+                None,
+                Some("completed".to_owned()),
+                None,
+                Some("stopped".to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn announces_complete_torrent_as_seeder() {
+        let mock = MockTracker::start().await.unwrap();
+        let peer_id = PeerId::generate();
+        let mut tracker = Tracker::new(&mock.url()).unwrap();
+
+        for _ in 0..2 {
+            tracker
+                .announce(INFO_HASH, peer_id, 6881, stats(0))
+                .await
+                .unwrap();
+        }
+        tracker
+            .stop(INFO_HASH, peer_id, 6881, stats(0))
+            .await
+            .unwrap();
+
+        let announces = mock.announces();
+        let events = announces
+            .iter()
+            .map(|announce| announce.event.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(events, [Some("started"), None, Some("stopped")]);
+        assert!(announces.iter().all(|announce| announce.left == Some(0)));
+        assert!(!tracker.session().is_completed());
+    }
+
+    #[tokio::test]
+    async fn retries_started_after_failure() {
+        let mock = MockTracker::start().await.unwrap();
+        let peer_id = PeerId::generate();
+        let mut tracker = Tracker::new(&mock.url()).unwrap();
+
+        mock.fail_with(Some("overloaded"));
+        let error = tracker
+            .announce(INFO_HASH, peer_id, 6881, stats(100))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("overloaded"));
+
+        mock.fail_with(None);
+        tracker
+            .announce(INFO_HASH, peer_id, 6881, stats(100))
+            .await
+            .unwrap();
+
+        let announces = mock.announces();
+        assert_eq!(announces[0].event.as_deref(), Some("started"));
+        assert_eq!(announces[1].event.as_deref(), Some("started"));
+    }
+
+    #[tokio::test]
+    async fn echoes_external_ip() {
+        let mock = MockTracker::start().await.unwrap();
+        mock.set_external_ip(Some("203.0.113.7".parse().unwrap()));
+        let peer_id = PeerId::generate();
+        let mut tracker = Tracker::new(&mock.url()).unwrap();
+
+        for _ in 0..2 {
+            tracker
+                .announce(INFO_HASH, peer_id, 6881, stats(100))
+                .await
+                .unwrap();
+        }
+
+        let announces = mock.announces();
+        assert_eq!(announces[0].ip, None);
+        assert_eq!(announces[1].ip.as_deref(), Some("203.0.113.7"));
+    }
+
+    #[tokio::test]
+    async fn keeps_passkey_query() {
+        let mock = MockTracker::start().await.unwrap();
+        let mut tracker = Tracker::new(&format!("{}?passkey=secret", mock.url())).unwrap();
+
+        tracker
+            .announce(INFO_HASH, PeerId::generate(), 6881, stats(100))
+            .await
+            .unwrap();
+
+        let announce = &mock.announces()[0];
+        assert_eq!(
+            announce.params[0],
+            ("passkey".to_owned(), b"secret".to_vec())
+        );
+        assert_eq!(announce.info_hash, INFO_HASH);
+    }
+
+    #[tokio::test]
+    async fn tiers_fall_back_and_merge() {
+        let failing = MockTracker::start().await.unwrap();
+        failing.fail_with(Some("unregistered torrent"));
+        let working = MockTracker::start().await.unwrap();
+        working.set_peers(vec!["10.0.0.1:6881".parse().unwrap()]);
+        let other = MockTracker::start().await.unwrap();
+        other.set_peers(vec!["10.0.0.2:6881".parse().unwrap()]);
+
+        let torrent = torrent(&[&failing, &working, &other]);
+
+        let mut tiers = TrackerTiers::new(&torrent);
+        let responses = tiers
+            .announce(INFO_HASH, PeerId::generate(), 6881, stats(100))
+            .await
+            .unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].0, working.url());
+        assert!(other.announces().is_empty());
+
+        tiers.set_mode(AnnounceMode::ConcurrentAll);
+        let responses = tiers
+            .announce(INFO_HASH, PeerId::generate(), 6881, stats(100))
+            .await
+            .unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(other.announces().len(), 1);
+    }
+}
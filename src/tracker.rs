@@ -1 +1,432 @@
+#[cfg(any(feature = "http", feature = "net"))]
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
 
+use serde::{de, Deserialize, Deserializer};
+use serde_bytes::ByteBuf;
+
+#[cfg(any(feature = "http", feature = "net"))]
+use crate::download::Overhead;
+use crate::error::TrackerError;
+
+#[cfg(feature = "http")]
+pub use self::http::{HttpConfig, TlsConfig, Tracker, TrackerTiers};
+pub use self::schedule::{AnnouncePolicy, AnnounceSchedule};
+#[cfg(feature = "net")]
+pub use self::udp::UdpTracker;
+
+#[cfg(feature = "http")]
+mod http;
+mod schedule;
+#[cfg(feature = "net")]
+mod udp;
+
+type Result<T, E = TrackerError> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    /// The value of the `event` announce parameter.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+}
+
+/// The lifecycle events already reported to one tracker, deciding which to send next.
+///
+/// Every `Tracker` keeps one; use it directly when announcing through another HTTP client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrackerSession {
+    started: bool,
+    completed: bool,
+    seen_incomplete: bool,
+}
+
+impl TrackerSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The event the next announce carries, given the bytes `left` to download.
+    ///
+    /// The first announce carries `started`. `completed` is sent exactly once, on the first
+    /// announce with nothing left after one where data was still missing, so torrents that were
+    /// already complete when added announce as seeders with `left=0` and never report it.
+    pub fn next_event(&mut self, left: u64) -> Option<Event> {
+        if left > 0 {
+            self.seen_incomplete = true;
+        }
+
+        if !self.started {
+            Some(Event::Started)
+        } else if left == 0 && self.seen_incomplete && !self.completed {
+            Some(Event::Completed)
+        } else {
+            None
+        }
+    }
+
+    /// Records that the tracker accepted an announce carrying `event`.
+    pub fn announced(&mut self, event: Option<Event>) {
+        match event {
+            Some(Event::Started) => self.started = true,
+            Some(Event::Completed) => self.completed = true,
+            Some(Event::Stopped) => self.started = false,
+            None => {}
+        }
+    }
+
+    /// Whether the tracker was told we joined and hasn't been told we left since.
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.completed
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CompactTrackerResponse {
+    #[serde(rename = "failure reason", default, with = "crate::optional")]
+    failure_reason: Option<String>,
+    #[serde(rename = "retry in", default, with = "crate::optional")]
+    retry_in: Option<RetryIn>,
+    #[serde(default)]
+    interval: u64,
+    #[serde(rename = "min interval", default, with = "crate::optional")]
+    min_interval: Option<u64>,
+    #[serde(default, with = "crate::optional")]
+    complete: Option<u64>,
+    #[serde(default, with = "crate::optional")]
+    incomplete: Option<u64>,
+    #[serde(default)]
+    peers: ByteBuf,
+    #[serde(rename = "external ip", default, with = "crate::optional")]
+    external_ip: Option<ByteBuf>,
+}
+
+#[derive(Debug)]
+pub struct TrackerResponse {
+    interval: Duration,
+    min_interval: Option<Duration>,
+    peers: Vec<SocketAddr>,
+    seeders: Option<u64>,
+    leechers: Option<u64>,
+    external_ip: Option<IpAddr>,
+}
+
+impl TrackerResponse {
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// How long the tracker wants us to wait at least before announcing again, even for events.
+    /// Only HTTP trackers send one.
+    pub fn min_interval(&self) -> Option<Duration> {
+        self.min_interval
+    }
+
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    pub fn seeders(&self) -> Option<u64> {
+        self.seeders
+    }
+
+    pub fn leechers(&self) -> Option<u64> {
+        self.leechers
+    }
+
+    /// Our address as seen by the tracker (BEP 24), if it reported one.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    /// Parses a bencoded announce response, failing if the tracker reported an error.
+    ///
+    /// Useful with any HTTP client when the built-in one is disabled.
+    pub fn from_bytes(body: &[u8]) -> Result<Self> {
+        let response = bendy::serde::from_bytes::<CompactTrackerResponse>(body)?;
+
+        if let Some(reason) = response.failure_reason {
+            let retry = match response.retry_in {
+                Some(RetryIn(retry)) => retry,
+                None => Retry::from_reason(&reason),
+            };
+            return Err(TrackerError::Failure { reason, retry });
+        }
+
+        if response.peers.len() % 6 != 0 {
+            return Err(TrackerError::Malformed(format!(
+                "compact peer list length {} is not a multiple of 6",
+                response.peers.len()
+            )));
+        }
+
+        let peers = response
+            .peers
+            .chunks_exact(6)
+            .map(|x| {
+                let ip = Ipv4Addr::new(x[0], x[1], x[2], x[3]);
+
+                let mut port = [0; 2];
+                port.copy_from_slice(&x[4..]);
+                let port = u16::from_be_bytes(port);
+
+                SocketAddr::new(ip.into(), port)
+            })
+            .collect();
+
+        let external_ip = response.external_ip.and_then(|ip| match ip.len() {
+            4 => <[u8; 4]>::try_from(ip.as_slice()).ok().map(IpAddr::from),
+            16 => <[u8; 16]>::try_from(ip.as_slice()).ok().map(IpAddr::from),
+            _ => None,
+        });
+
+        Ok(Self {
+            interval: Duration::from_secs(response.interval),
+            min_interval: response.min_interval.map(Duration::from_secs),
+            peers,
+            seeders: response.complete,
+            leechers: response.incomplete,
+            external_ip,
+        })
+    }
+}
+
+/// When a tracker that refused an announce may be asked again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retry {
+    /// At the next regular announce.
+    Default,
+    /// Not before this much time has passed.
+    After(Duration),
+    /// Never, e.g. because the tracker doesn't know the torrent.
+    Never,
+}
+
+impl Retry {
+    /// Backoff for trackers that complain about announcing too often without saying how long to
+    /// wait.
+    const FLOOD_DELAY: Duration = Duration::from_secs(5 * 60);
+
+    /// Guesses from a `failure reason` alone, for trackers that don't send `retry in` (BEP 31).
+    pub fn from_reason(reason: &str) -> Self {
+        let reason = reason.to_ascii_lowercase();
+        let permanent = [
+            "not registered",
+            "unregistered",
+            "not authorized",
+            "unknown torrent",
+            "torrent not found",
+            "banned",
+        ];
+        let flood = ["flood", "too many", "too often", "slow down", "rate limit"];
+
+        if permanent.iter().any(|x| reason.contains(x)) {
+            Retry::Never
+        } else if flood.iter().any(|x| reason.contains(x)) {
+            Retry::After(Self::FLOOD_DELAY)
+        } else {
+            Retry::Default
+        }
+    }
+}
+
+/// The `retry in` key of a failure response: minutes, or `never`.
+#[derive(Debug)]
+struct RetryIn(Retry);
+
+impl<'de> Deserialize<'de> for RetryIn {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = RetryIn;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number of minutes or \"never\"")
+            }
+
+            fn visit_i64<E: de::Error>(self, minutes: i64) -> Result<RetryIn, E> {
+                self.visit_u64(u64::try_from(minutes).map_err(E::custom)?)
+            }
+
+            fn visit_u64<E: de::Error>(self, minutes: u64) -> Result<RetryIn, E> {
+                Ok(RetryIn(Retry::After(Duration::from_secs(
+                    minutes.saturating_mul(60),
+                ))))
+            }
+
+            fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<RetryIn, E> {
+                match value {
+                    b"never" => Ok(RetryIn(Retry::Never)),
+                    _ => Err(E::invalid_value(de::Unexpected::Bytes(value), &self)),
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<RetryIn, E> {
+                self.visit_bytes(value.as_bytes())
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// How the trackers of an announce list are contacted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AnnounceMode {
+    /// One tracker at a time in BEP 12 order, stopping at the first that answers.
+    #[default]
+    Sequential,
+    /// Every tracker of a tier at once, moving to the next tier only if all of them failed.
+    ConcurrentTier,
+    /// Every tracker of every tier at once.
+    ConcurrentAll,
+}
+
+/// A tracker exchange as it went over the wire, for debugging trackers that reject announces.
+#[derive(Debug, Clone, Copy)]
+pub enum Trace<'a> {
+    /// The full URL of an HTTP announce, passkey included.
+    Request(&'a str),
+    /// A UDP packet sent to the tracker, once per retransmission.
+    Sent(&'a [u8]),
+    /// A raw HTTP response body or UDP packet, before it's parsed.
+    Received(&'a [u8]),
+}
+
+#[cfg(any(feature = "http", feature = "net"))]
+type TraceHook = dyn Fn(Trace<'_>) + Send + Sync;
+
+#[cfg(any(feature = "http", feature = "net"))]
+/// An optional trace hook, shared by the trackers it was set on.
+#[derive(Clone, Default)]
+struct Tracer(Option<Arc<TraceHook>>);
+
+#[cfg(any(feature = "http", feature = "net"))]
+impl Tracer {
+    fn new(hook: impl Fn(Trace<'_>) + Send + Sync + 'static) -> Self {
+        Self(Some(Arc::new(hook)))
+    }
+
+    fn trace(&self, trace: Trace<'_>) {
+        if let Some(hook) = &self.0 {
+            hook(trace);
+        }
+    }
+}
+
+/// Bytes exchanged with a tracker since they were last taken.
+#[cfg(any(feature = "http", feature = "net"))]
+#[derive(Debug, Default)]
+struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+#[cfg(any(feature = "http", feature = "net"))]
+impl Traffic {
+    fn sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> Overhead {
+        Overhead {
+            uploaded: self.sent.swap(0, Ordering::Relaxed),
+            downloaded: self.received.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(any(feature = "http", feature = "net"))]
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Tracer").field(&self.0.is_some()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_sends_completed_once_across_restarts() {
+        let mut session = TrackerSession::new();
+        let mut announce = |left| {
+            let event = session.next_event(left);
+            session.announced(event);
+            event
+        };
+
+        assert_eq!(announce(100), Some(Event::Started));
+        assert_eq!(announce(0), Some(Event::Completed));
+        assert_eq!(announce(0), None);
+
+        session.announced(Some(Event::Stopped));
+        assert_eq!(session.next_event(0), Some(Event::Started));
+        session.announced(Some(Event::Started));
+        assert_eq!(session.next_event(0), None);
+    }
+
+    #[test]
+    fn session_completes_torrent_that_lost_data() {
+        let mut session = TrackerSession::new();
+        assert_eq!(session.next_event(0), Some(Event::Started));
+        session.announced(Some(Event::Started));
+
+        // A recheck found pieces missing, which were then downloaded again.
+        assert_eq!(session.next_event(10), None);
+        assert_eq!(session.next_event(0), Some(Event::Completed));
+    }
+
+    #[test]
+    fn session_repeats_events_that_failed() {
+        let mut session = TrackerSession::new();
+        assert_eq!(session.next_event(100), Some(Event::Started));
+        assert_eq!(session.next_event(100), Some(Event::Started));
+        session.announced(Some(Event::Started));
+
+        assert_eq!(session.next_event(0), Some(Event::Completed));
+        assert_eq!(session.next_event(0), Some(Event::Completed));
+        session.announced(Some(Event::Completed));
+        assert_eq!(session.next_event(0), None);
+    }
+
+    #[test]
+    fn session_never_completes_torrent_added_complete() {
+        let mut session = TrackerSession::new();
+        assert_eq!(session.next_event(0), Some(Event::Started));
+        session.announced(Some(Event::Started));
+
+        for _ in 0..3 {
+            let event = session.next_event(0);
+            assert_eq!(event, None);
+            session.announced(event);
+        }
+        assert!(!session.is_completed());
+    }
+}
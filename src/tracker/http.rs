@@ -0,0 +1,667 @@
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use futures::future;
+use rand::seq::SliceRandom;
+use reqwest::{Certificate, Client, ClientBuilder, Identity, Proxy, Url};
+#[cfg(feature = "net")]
+use url::Host;
+
+#[cfg(feature = "net")]
+use crate::net::udp::UdpMux;
+use crate::{
+    download::{Overhead, TransferStats},
+    error::TrackerError,
+    info::Torrent,
+    peer_id::PeerId,
+};
+
+#[cfg(feature = "net")]
+use super::UdpTracker;
+use super::{
+    AnnounceMode, Event, Result, Retry, Trace, Tracer, TrackerResponse, TrackerSession, Traffic,
+};
+
+fn form_encode(b: &[u8]) -> String {
+    url::form_urlencoded::byte_serialize(b)
+        .map(|x| if x == "+" { "%20" } else { x })
+        .collect()
+}
+
+/// TLS settings for HTTPS trackers.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// Extra trusted root certificates, PEM encoded.
+    pub root_certificates: Vec<Vec<u8>>,
+    /// A PEM client certificate chain and its PEM PKCS #8 key, for trackers gated on mutual TLS.
+    pub identity: Option<(Vec<u8>, Vec<u8>)>,
+    /// Skips certificate verification. Prefer adding a self-signed certificate as a root.
+    pub accept_invalid_certs: bool,
+}
+
+impl TlsConfig {
+    fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder> {
+        builder = builder.danger_accept_invalid_certs(self.accept_invalid_certs);
+        for pem in &self.root_certificates {
+            builder = builder.add_root_certificate(Certificate::from_pem(pem)?);
+        }
+        if let Some((certificate, key)) = &self.identity {
+            builder = builder.identity(Identity::from_pkcs8_pem(certificate, key)?);
+        }
+
+        Ok(builder)
+    }
+}
+
+/// Settings of the HTTP client announces go through. Build one [`client`](Self::client) and
+/// hand it to every tracker, so they share its connection pool and TLS sessions.
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub user_agent: Option<String>,
+    /// Limit on a whole announce, from connecting to reading the response.
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    /// Proxy URL for every announce, e.g. `http://proxy:3128`.
+    pub proxy: Option<String>,
+    pub tls: TlsConfig,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            user_agent: Some(concat!("torrant/", env!("CARGO_PKG_VERSION")).to_owned()),
+            timeout: Some(Duration::from_secs(30)),
+            connect_timeout: Some(Duration::from_secs(10)),
+            proxy: None,
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Fails if the proxy URL, a certificate or a key doesn't parse.
+    pub fn client(&self) -> Result<Client> {
+        let mut builder = self.tls.apply(Client::builder())?;
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+fn tls_client(tls: &TlsConfig) -> Result<Client> {
+    HttpConfig {
+        tls: tls.clone(),
+        ..HttpConfig::default()
+    }
+    .client()
+}
+
+/// The client of the default [`HttpConfig`], shared by every tracker not given one of its own.
+fn default_client() -> Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT
+        .get_or_init(|| HttpConfig::default().client().unwrap_or_default())
+        .clone()
+}
+
+#[derive(Debug)]
+enum Transport {
+    Http(Client),
+    #[cfg(feature = "net")]
+    Udp(Box<UdpTracker>),
+}
+
+impl Transport {
+    fn set_client(&mut self, client: Client) {
+        match self {
+            Transport::Http(existing) => *existing = client,
+            #[cfg(feature = "net")]
+            Transport::Udp(_) => {}
+        }
+    }
+}
+
+/// Parses a tracker URL into the form that tells whether two URLs name the same tracker.
+///
+/// Parsing already lowercases the scheme and the host of HTTP URLs and drops their default port.
+/// UDP URLs get their host lowercased too, and fragments, which never reach the tracker, go.
+///
+/// Common mistakes of torrent makers are repaired: whitespace around the URL is ignored, and an
+/// HTTP URL without a path announces to `/announce`.
+fn parse_url(url: &str) -> Result<Url> {
+    let mut url = Url::parse(url.trim())?;
+    url.set_fragment(None);
+    if matches!(url.scheme(), "http" | "https") && url.path() == "/" && url.query().is_none() {
+        url.set_path("/announce");
+    }
+    if let Some(url::Host::Domain(host)) = url.host() {
+        let host = host.to_lowercase();
+        if host != url.host_str().unwrap_or_default() {
+            url.set_host(Some(&host))?;
+        }
+    }
+
+    Ok(url)
+}
+
+/// An HTTP or UDP tracker along with the lifecycle events already reported to it.
+#[derive(Debug)]
+pub struct Tracker {
+    url: Url,
+    transport: Transport,
+    tracer: Tracer,
+    traffic: Traffic,
+    ip: Option<IpAddr>,
+    external_ip: Option<IpAddr>,
+    num_want: Option<u32>,
+    session: TrackerSession,
+    /// Set once the tracker said it will never accept this torrent.
+    disabled: bool,
+    retry_at: Option<Instant>,
+}
+
+impl Tracker {
+    /// Fails if `url` doesn't parse or has a scheme other than `http`, `https`, or `udp`.
+    ///
+    /// Whitespace around `url` is ignored, and an HTTP URL without a path announces to
+    /// `/announce`, as torrents in the wild often get these wrong.
+    pub fn new(url: &str) -> Result<Self> {
+        Self::with_client(url, default_client())
+    }
+
+    /// Like [`new`](Self::new), announcing over HTTP through `client`, e.g. one from
+    /// [`HttpConfig::client`] shared with other trackers.
+    pub fn with_client(url: &str, client: Client) -> Result<Self> {
+        let url = parse_url(url)?;
+        let transport = match url.scheme() {
+            "http" | "https" => Transport::Http(client),
+            #[cfg(feature = "net")]
+            "udp" => {
+                let host = match url.host() {
+                    // Unlike `host_str`, this leaves IPv6 literals without brackets.
+                    Some(Host::Ipv6(ip)) => ip.to_string(),
+                    Some(host) => host.to_string(),
+                    None => return Err(url::ParseError::EmptyHost.into()),
+                };
+                let port = url.port().ok_or(url::ParseError::InvalidPort)?;
+                Transport::Udp(Box::new(UdpTracker::new(host, port)))
+            }
+            scheme => return Err(TrackerError::UnsupportedScheme(scheme.to_owned())),
+        };
+
+        Ok(Self {
+            url,
+            transport,
+            tracer: Tracer::default(),
+            traffic: Traffic::default(),
+            ip: None,
+            external_ip: None,
+            num_want: None,
+            session: TrackerSession::new(),
+            disabled: false,
+            retry_at: None,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        self.url.as_str()
+    }
+
+    /// Overrides the address sent in the `ip` announce parameter.
+    ///
+    /// Without an override, the external address the tracker last reported is sent instead, which
+    /// keeps multi-homed hosts announcing the address peers can actually reach.
+    pub fn set_ip(&mut self, ip: Option<IpAddr>) {
+        self.ip = ip;
+    }
+
+    /// Asks for up to `num_want` peers in later announces, or lets the tracker pick its default
+    /// with `None`.
+    pub fn set_num_want(&mut self, num_want: Option<u32>) {
+        self.num_want = num_want;
+        #[cfg(feature = "net")]
+        if let Transport::Udp(tracker) = &mut self.transport {
+            tracker.set_num_want(num_want);
+        }
+    }
+
+    /// Calls `hook` with the raw request and response of every announce, or every packet for UDP
+    /// trackers.
+    pub fn set_trace(&mut self, hook: impl Fn(Trace<'_>) + Send + Sync + 'static) {
+        self.set_tracer(Tracer::new(hook));
+    }
+
+    /// Announces through `client` from now on. Has no effect on UDP trackers.
+    pub fn set_client(&mut self, client: Client) {
+        self.transport.set_client(client);
+    }
+
+    /// Switches to a client of its own with the default [`HttpConfig`] but these TLS settings.
+    /// Has no effect on UDP trackers.
+    ///
+    /// Fails if a certificate or key doesn't parse.
+    pub fn set_tls(&mut self, tls: &TlsConfig) -> Result<()> {
+        let client = tls_client(tls)?;
+        self.transport.set_client(client);
+
+        Ok(())
+    }
+
+    /// Sends UDP announces from a shared socket, see [`UdpTracker::set_socket`]. Has no effect on
+    /// HTTP trackers.
+    #[cfg(feature = "net")]
+    pub fn set_udp_socket(&mut self, socket: UdpMux) {
+        if let Transport::Udp(udp) = &mut self.transport {
+            udp.set_socket(socket);
+        }
+    }
+
+    /// Whether the tracker is skipped because a failure response asked us to back off (BEP 31),
+    /// and for how long.
+    ///
+    /// Announces fail with [`TrackerError::BackingOff`] meanwhile.
+    pub fn backoff(&self) -> Option<Retry> {
+        if self.disabled {
+            return Some(Retry::Never);
+        }
+
+        let remaining = self.retry_at?.checked_duration_since(Instant::now())?;
+        Some(Retry::After(remaining))
+    }
+
+    /// Bytes exchanged with the tracker since the last call, to add to
+    /// [`Download::record_overhead`](crate::download::Download::record_overhead).
+    ///
+    /// HTTP announces count the URL and the response body, but not headers or TLS.
+    pub fn take_traffic(&self) -> Overhead {
+        #[cfg(feature = "net")]
+        if let Transport::Udp(tracker) = &self.transport {
+            return tracker.take_traffic();
+        }
+        self.traffic.take()
+    }
+
+    fn set_tracer(&mut self, tracer: Tracer) {
+        #[cfg(feature = "net")]
+        if let Transport::Udp(tracker) = &mut self.transport {
+            tracker.set_tracer(tracer.clone());
+        }
+        self.tracer = tracer;
+    }
+
+    /// Announces the current transfer stats, with the event [`TrackerSession::next_event`]
+    /// picks.
+    pub async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        peer_id: PeerId,
+        port: u16,
+        stats: TransferStats,
+    ) -> Result<TrackerResponse> {
+        let event = self.session.next_event(stats.left);
+        let response = self.send(info_hash, peer_id, port, stats, event).await?;
+
+        if let Some(external_ip) = response.external_ip {
+            self.external_ip = Some(external_ip);
+        }
+        self.session.announced(event);
+
+        Ok(response)
+    }
+
+    /// The lifecycle events reported to the tracker so far.
+    pub fn session(&self) -> TrackerSession {
+        self.session
+    }
+
+    /// Whether the tracker was told we joined and hasn't been told we left since.
+    pub fn is_started(&self) -> bool {
+        self.session.is_started()
+    }
+
+    /// Tells the tracker we're leaving the swarm. A later announce starts a new session.
+    pub async fn stop(
+        &mut self,
+        info_hash: [u8; 20],
+        peer_id: PeerId,
+        port: u16,
+        stats: TransferStats,
+    ) -> Result<()> {
+        self.send(info_hash, peer_id, port, stats, Some(Event::Stopped))
+            .await?;
+        self.session.announced(Some(Event::Stopped));
+
+        Ok(())
+    }
+
+    async fn send(
+        &mut self,
+        info_hash: [u8; 20],
+        peer_id: PeerId,
+        port: u16,
+        stats: TransferStats,
+        event: Option<Event>,
+    ) -> Result<TrackerResponse> {
+        if let Some(retry) = self.backoff() {
+            return Err(TrackerError::BackingOff(retry));
+        }
+
+        let result = self.exchange(info_hash, peer_id, port, stats, event).await;
+        match &result {
+            Err(TrackerError::Failure {
+                retry: Retry::Never,
+                ..
+            }) => self.disabled = true,
+            Err(TrackerError::Failure {
+                retry: Retry::After(delay),
+                ..
+            }) => self.retry_at = Some(Instant::now() + *delay),
+            _ => {}
+        }
+
+        result
+    }
+
+    async fn exchange(
+        &mut self,
+        info_hash: [u8; 20],
+        peer_id: PeerId,
+        port: u16,
+        stats: TransferStats,
+        event: Option<Event>,
+    ) -> Result<TrackerResponse> {
+        let ip = self.ip.or(self.external_ip);
+        #[cfg_attr(not(feature = "net"), allow(clippy::infallible_destructuring_match))]
+        let client = match &mut self.transport {
+            Transport::Http(client) => client,
+            #[cfg(feature = "net")]
+            Transport::Udp(tracker) => {
+                return tracker
+                    .announce(info_hash, peer_id, port, ip, stats, event)
+                    .await
+            }
+        };
+
+        let info_hash = form_encode(&info_hash);
+        let peer_id = form_encode(peer_id.as_bytes());
+        let TransferStats {
+            uploaded,
+            downloaded,
+            left,
+        } = stats;
+
+        let mut query = format!(
+            "info_hash={info_hash}&peer_id={peer_id}&port={port}&uploaded={uploaded}&downloaded={downloaded}&left={left}&compact=1"
+        );
+        if let Some(event) = event {
+            query.push_str("&event=");
+            query.push_str(event.as_str());
+        }
+        if let Some(ip) = ip {
+            query.push_str("&ip=");
+            query.push_str(&ip.to_string());
+        }
+        if let Some(num_want) = self.num_want {
+            query.push_str("&numwant=");
+            query.push_str(&num_want.to_string());
+        }
+
+        let mut url = self.url.clone();
+        // Private trackers often carry a passkey in the announce URL's own query.
+        match self.url.query() {
+            Some(existing) => url.set_query(Some(&format!("{existing}&{query}"))),
+            None => url.set_query(Some(&query)),
+        }
+
+        self.tracer.trace(Trace::Request(url.as_str()));
+        self.traffic.sent(url.as_str().len());
+        let body = client.get(url).send().await?.bytes().await?;
+        self.tracer.trace(Trace::Received(&body));
+        self.traffic.received(body.len());
+        TrackerResponse::from_bytes(&body)
+    }
+}
+
+/// A torrent's trackers grouped into the tiers of its announce list (BEP 12).
+#[derive(Debug)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<Tracker>>,
+    mode: AnnounceMode,
+    private: bool,
+}
+
+impl TrackerTiers {
+    /// Builds the tiers from [`Torrent::trackers`].
+    ///
+    /// Trackers within a tier are shuffled. Malformed URLs are repaired where possible, see
+    /// [`Tracker::new`], and skipped otherwise rather than failing the whole torrent. A tracker
+    /// listed more than once, even spelled differently, is only kept in the first tier naming it.
+    /// The result is [empty](Self::is_empty) for trackerless torrents.
+    pub fn new(torrent: &Torrent) -> Self {
+        Self::with_client(torrent, default_client())
+    }
+
+    /// Like [`new`](Self::new), with every HTTP tracker announcing through `client`.
+    pub fn with_client(torrent: &Torrent, client: Client) -> Self {
+        let urls = torrent.trackers();
+
+        let mut rng = rand::thread_rng();
+        let mut seen = HashSet::new();
+        let tiers = urls
+            .iter()
+            .map(|tier| {
+                let mut trackers = tier
+                    .iter()
+                    .filter_map(|url| Tracker::with_client(url, client.clone()).ok())
+                    .filter(|tracker| seen.insert(tracker.url.clone()))
+                    .collect::<Vec<_>>();
+                trackers.shuffle(&mut rng);
+                trackers
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+
+        Self {
+            tiers,
+            mode: AnnounceMode::default(),
+            private: torrent.info.is_private(),
+        }
+    }
+
+    pub fn mode(&self) -> AnnounceMode {
+        self.mode
+    }
+
+    /// Sets how trackers are contacted. Private torrents always announce sequentially, since
+    /// their trackers expect a client to hold a single session.
+    pub fn set_mode(&mut self, mode: AnnounceMode) {
+        self.mode = mode;
+    }
+
+    /// Sets the same peer count on every tracker, see [`Tracker::set_num_want`].
+    pub fn set_num_want(&mut self, num_want: Option<u32>) {
+        for tracker in self.tiers.iter_mut().flatten() {
+            tracker.set_num_want(num_want);
+        }
+    }
+
+    /// Sets the same trace hook on every tracker, see [`Tracker::set_trace`].
+    pub fn set_trace(&mut self, hook: impl Fn(Trace<'_>) + Send + Sync + 'static) {
+        let tracer = Tracer::new(hook);
+        for tracker in self.tiers.iter_mut().flatten() {
+            tracker.set_tracer(tracer.clone());
+        }
+    }
+
+    /// Has every HTTP tracker announce through `client`.
+    pub fn set_client(&mut self, client: &Client) {
+        for tracker in self.tiers.iter_mut().flatten() {
+            tracker.set_client(client.clone());
+        }
+    }
+
+    /// Sets the same TLS settings on every tracker, see [`Tracker::set_tls`].
+    pub fn set_tls(&mut self, tls: &TlsConfig) -> Result<()> {
+        let client = tls_client(tls)?;
+        for tracker in self.tiers.iter_mut().flatten() {
+            tracker.transport.set_client(client.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Sends every UDP tracker's announces from `socket`, see [`Tracker::set_udp_socket`].
+    #[cfg(feature = "net")]
+    pub fn set_udp_socket(&mut self, socket: &UdpMux) {
+        for tracker in self.tiers.iter_mut().flatten() {
+            tracker.set_udp_socket(socket.clone());
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<Tracker>] {
+        &self.tiers
+    }
+
+    /// Bytes exchanged with all of the trackers since the last call, see
+    /// [`Tracker::take_traffic`].
+    pub fn take_traffic(&self) -> Overhead {
+        self.tiers.iter().flatten().map(Tracker::take_traffic).fold(
+            Overhead::default(),
+            |total, traffic| Overhead {
+                uploaded: total.uploaded + traffic.uploaded,
+                downloaded: total.downloaded + traffic.downloaded,
+            },
+        )
+    }
+
+    /// The tracker announcing to `url`, e.g. to give it settings of its own.
+    pub fn tracker_mut(&mut self, url: &str) -> Option<&mut Tracker> {
+        let url = parse_url(url).ok()?;
+        self.tiers
+            .iter_mut()
+            .flatten()
+            .find(|tracker| tracker.url == url)
+    }
+
+    /// Whether there's no tracker to announce to, in which case [`announce`](Self::announce)
+    /// always fails with [`TrackerError::NoTrackers`].
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Announces according to the current mode, returning the response of every tracker that
+    /// answered along with its URL.
+    ///
+    /// Fails only if no tracker answered.
+    pub async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        peer_id: PeerId,
+        port: u16,
+        stats: TransferStats,
+    ) -> Result<Vec<(String, TrackerResponse)>> {
+        let mode = if self.private {
+            AnnounceMode::Sequential
+        } else {
+            self.mode
+        };
+
+        let mut last_error = None;
+
+        match mode {
+            AnnounceMode::Sequential => {
+                for tier in &mut self.tiers {
+                    for index in 0..tier.len() {
+                        match tier[index].announce(info_hash, peer_id, port, stats).await {
+                            Ok(response) => {
+                                // BEP 12: a tracker that answers moves to the front of its tier.
+                                let tracker = tier.remove(index);
+                                let url = tracker.url().to_owned();
+                                tier.insert(0, tracker);
+                                return Ok(vec![(url, response)]);
+                            }
+                            Err(e) => last_error = Some(e),
+                        }
+                    }
+                }
+            }
+            AnnounceMode::ConcurrentTier => {
+                for tier in &mut self.tiers {
+                    match announce_all(tier.iter_mut(), info_hash, peer_id, port, stats).await {
+                        Ok(responses) => return Ok(responses),
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+            }
+            AnnounceMode::ConcurrentAll => {
+                let trackers = self.tiers.iter_mut().flatten();
+                return announce_all(trackers, info_hash, peer_id, port, stats).await;
+            }
+        }
+
+        Err(last_error.unwrap_or(TrackerError::NoTrackers))
+    }
+
+    /// Tells every tracker that knows about us that we're leaving the swarm, all at once, e.g.
+    /// when the torrent is removed. Failures are ignored, as the trackers time us out eventually.
+    pub async fn stop(
+        &mut self,
+        info_hash: [u8; 20],
+        peer_id: PeerId,
+        port: u16,
+        stats: TransferStats,
+    ) {
+        let trackers = self
+            .tiers
+            .iter_mut()
+            .flatten()
+            .filter(|tracker| tracker.is_started());
+        future::join_all(trackers.map(|tracker| tracker.stop(info_hash, peer_id, port, stats)))
+            .await;
+    }
+}
+
+/// Announces to `trackers` concurrently, succeeding if any of them answered.
+async fn announce_all(
+    trackers: impl Iterator<Item = &mut Tracker>,
+    info_hash: [u8; 20],
+    peer_id: PeerId,
+    port: u16,
+    stats: TransferStats,
+) -> Result<Vec<(String, TrackerResponse)>> {
+    let results = future::join_all(trackers.map(|tracker| async move {
+        let result = tracker.announce(info_hash, peer_id, port, stats).await;
+        (tracker.url().to_owned(), result)
+    }))
+    .await;
+
+    let mut responses = Vec::new();
+    let mut last_error = None;
+    for (url, result) in results {
+        match result {
+            Ok(response) => responses.push((url, response)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    if responses.is_empty() {
+        return Err(last_error.unwrap_or(TrackerError::NoTrackers));
+    }
+
+    Ok(responses)
+}
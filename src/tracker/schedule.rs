@@ -0,0 +1,249 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::info::Torrent;
+
+use super::TrackerResponse;
+
+/// How closely announces follow what trackers ask for. Private trackers track ratios from the
+/// announces, so they get [`AnnouncePolicy::private`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnnouncePolicy {
+    /// Never announce before the tracker's `min interval` has passed, not even early for an
+    /// event.
+    pub respect_min_interval: bool,
+    /// Announce again as soon as allowed when our external address changes, so the tracker
+    /// doesn't hand out a stale one and the torrent doesn't silently drop out of its swarms.
+    pub reannounce_on_ip_change: bool,
+    /// Interval used until a tracker tells us one, or if it says `0`.
+    pub default_interval: Duration,
+    /// Wait before retrying a failed announce, doubled for each failure in a row up to
+    /// `default_interval`.
+    pub retry: Duration,
+}
+
+impl Default for AnnouncePolicy {
+    fn default() -> Self {
+        Self {
+            respect_min_interval: false,
+            reannounce_on_ip_change: true,
+            default_interval: Duration::from_secs(30 * 60),
+            retry: Duration::from_secs(60),
+        }
+    }
+}
+
+impl AnnouncePolicy {
+    /// The strict policy for private torrents.
+    pub fn private() -> Self {
+        Self {
+            respect_min_interval: true,
+            ..Self::default()
+        }
+    }
+
+    /// [`private`](Self::private) for private torrents, the default otherwise.
+    pub fn for_torrent(torrent: &Torrent) -> Self {
+        if torrent.info.is_private() {
+            Self::private()
+        } else {
+            Self::default()
+        }
+    }
+}
+
+/// When a torrent should announce next, for callers driving
+/// [`TrackerTiers::announce`](super::TrackerTiers::announce) themselves.
+///
+/// A new schedule is due right away.
+#[derive(Debug, Clone)]
+pub struct AnnounceSchedule {
+    policy: AnnouncePolicy,
+    next: Option<Instant>,
+    /// Not before then, per the tracker's `min interval`.
+    earliest: Option<Instant>,
+    external_ip: Option<IpAddr>,
+    failures: u32,
+}
+
+impl AnnounceSchedule {
+    pub fn new(policy: AnnouncePolicy) -> Self {
+        Self {
+            policy,
+            next: None,
+            earliest: None,
+            external_ip: None,
+            failures: 0,
+        }
+    }
+
+    pub fn policy(&self) -> AnnouncePolicy {
+        self.policy
+    }
+
+    /// When the next announce is due, `None` if it already is.
+    pub fn next_announce(&self) -> Option<Instant> {
+        self.next
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.next.is_none_or(|next| next <= now)
+    }
+
+    /// Schedules the next regular announce after the trackers in `responses` answered. With
+    /// several, the shortest interval and the longest `min interval` apply.
+    pub fn announced<'a>(
+        &mut self,
+        now: Instant,
+        responses: impl IntoIterator<Item = &'a TrackerResponse>,
+    ) {
+        let mut interval = None::<Duration>;
+        let mut min_interval = None::<Duration>;
+        for response in responses {
+            let ours = Some(response.interval())
+                .filter(|interval| !interval.is_zero())
+                .unwrap_or(self.policy.default_interval);
+            interval = Some(interval.map_or(ours, |interval| interval.min(ours)));
+            min_interval = min_interval.max(response.min_interval());
+        }
+
+        let interval = interval.unwrap_or(self.policy.default_interval);
+        // A tracker may ask for a `min interval` longer than its interval.
+        let interval = min_interval.map_or(interval, |min| interval.max(min));
+        self.next = Some(now + interval);
+        self.earliest = min_interval.map(|min| now + min);
+        self.failures = 0;
+    }
+
+    /// Schedules a retry after no tracker answered.
+    pub fn failed(&mut self, now: Instant) {
+        let backoff = self
+            .policy
+            .retry
+            .saturating_mul(1 << self.failures.min(16))
+            .min(self.policy.default_interval);
+        self.failures = self.failures.saturating_add(1);
+        self.next = Some(self.not_before(now + backoff));
+    }
+
+    /// Makes an announce due as soon as the policy allows, e.g. to report completion. Returns
+    /// when that is.
+    pub fn announce_early(&mut self, now: Instant) -> Instant {
+        let at = self.not_before(now);
+        if self.next.is_some_and(|next| next > at) {
+            self.next = Some(at);
+        }
+        self.next.unwrap_or(now)
+    }
+
+    /// Records our external address, e.g. from [`TrackerResponse::external_ip`]. Returns whether
+    /// it changed from a previously known one, in which case an announce is made due if the
+    /// policy asks for it.
+    pub fn set_external_ip(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let changed = self.external_ip.is_some_and(|known| known != ip);
+        self.external_ip = Some(ip);
+        if changed && self.policy.reannounce_on_ip_change {
+            self.announce_early(now);
+        }
+
+        changed
+    }
+
+    fn not_before(&self, at: Instant) -> Instant {
+        match self.earliest {
+            Some(earliest) if self.policy.respect_min_interval => at.max(earliest),
+            _ => at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(interval: u64, min_interval: Option<u64>) -> TrackerResponse {
+        TrackerResponse {
+            interval: Duration::from_secs(interval),
+            min_interval: min_interval.map(Duration::from_secs),
+            peers: Vec::new(),
+            seeders: None,
+            leechers: None,
+            external_ip: None,
+        }
+    }
+
+    #[test]
+    fn early_announces_wait_for_min_interval() {
+        let now = Instant::now();
+        let responses = [response(1800, Some(600))];
+
+        let mut schedule = AnnounceSchedule::new(AnnouncePolicy::private());
+        schedule.announced(now, &responses);
+        assert_eq!(
+            schedule.next_announce(),
+            Some(now + Duration::from_secs(1800))
+        );
+        let early = now + Duration::from_secs(10);
+        assert_eq!(
+            schedule.announce_early(early),
+            now + Duration::from_secs(600)
+        );
+
+        let mut schedule = AnnounceSchedule::new(AnnouncePolicy::default());
+        schedule.announced(now, &responses);
+        assert_eq!(schedule.announce_early(early), early);
+    }
+
+    #[test]
+    fn backs_off_after_failures() {
+        let now = Instant::now();
+        let policy = AnnouncePolicy {
+            retry: Duration::from_secs(60),
+            default_interval: Duration::from_secs(300),
+            ..AnnouncePolicy::default()
+        };
+        let mut schedule = AnnounceSchedule::new(policy);
+        assert!(schedule.is_due(now));
+
+        for delay in [60, 120, 240, 300, 300] {
+            schedule.failed(now);
+            assert_eq!(
+                schedule.next_announce(),
+                Some(now + Duration::from_secs(delay))
+            );
+        }
+
+        schedule.announced(now, &[response(900, None)]);
+        schedule.failed(now);
+        assert_eq!(
+            schedule.next_announce(),
+            Some(now + Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn reannounces_when_external_ip_changes() {
+        let now = Instant::now();
+        let mut schedule = AnnounceSchedule::new(AnnouncePolicy::default());
+        schedule.announced(now, &[response(1800, None)]);
+
+        assert!(!schedule.set_external_ip([1, 2, 3, 4].into(), now));
+        assert!(!schedule.is_due(now));
+        assert!(!schedule.set_external_ip([1, 2, 3, 4].into(), now));
+
+        let later = now + Duration::from_secs(5);
+        assert!(schedule.set_external_ip([5, 6, 7, 8].into(), later));
+        assert!(schedule.is_due(later));
+
+        let mut schedule = AnnounceSchedule::new(AnnouncePolicy {
+            reannounce_on_ip_change: false,
+            ..AnnouncePolicy::default()
+        });
+        schedule.announced(now, &[response(1800, None)]);
+        schedule.set_external_ip([1, 2, 3, 4].into(), now);
+        assert!(schedule.set_external_ip([5, 6, 7, 8].into(), later));
+        assert!(!schedule.is_due(later));
+    }
+}
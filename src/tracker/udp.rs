@@ -0,0 +1,437 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use rand::Rng;
+use tokio::net::{lookup_host, UdpSocket};
+
+use crate::{
+    download::{Overhead, TransferStats},
+    error::TrackerError,
+    net::udp::{Transaction, UdpMux},
+    peer_id::PeerId,
+};
+
+use super::{Event, Result, Retry, Trace, Tracer, TrackerResponse, Traffic};
+
+/// Magic constant identifying the connect request (BEP 15).
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+
+/// How long a connection id may be used after it was handed out.
+const CONNECTION_LIFETIME: Duration = Duration::from_secs(60);
+
+/// How long to wait for an answer from one address before also trying the next (RFC 8305).
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// A UDP tracker (BEP 15).
+///
+/// The socket family follows the tracker's resolved address, so IPv6 trackers are announced to
+/// over IPv6 and answer with 18-byte peer entries. With [`set_socket`](Self::set_socket), a
+/// shared socket is used instead of one per tracker.
+#[derive(Debug)]
+pub struct UdpTracker {
+    host: String,
+    port: u16,
+    /// Initial retransmission timeout, doubled on every retry.
+    timeout: Duration,
+    max_retries: u32,
+    num_want: Option<u32>,
+    tracer: Tracer,
+    traffic: Traffic,
+    socket: Option<UdpMux>,
+    /// The address that last answered, reused until an announce to it fails.
+    resolved: Option<SocketAddr>,
+    connection: Option<Connection>,
+}
+
+#[derive(Debug)]
+struct Connection {
+    addr: SocketAddr,
+    endpoint: Endpoint,
+    id: u64,
+    since: Instant,
+}
+
+/// Where requests to one tracker address go out from.
+#[derive(Debug)]
+enum Endpoint {
+    /// A socket of our own, connected to the tracker.
+    Socket(UdpSocket),
+    Shared(UdpMux, SocketAddr),
+}
+
+impl Endpoint {
+    async fn send(&self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Endpoint::Socket(socket) => socket.send(data).await?,
+            Endpoint::Shared(mux, addr) => mux.send_to(data, *addr).await?,
+        };
+        Ok(())
+    }
+
+    fn responses(&self, transaction_id: u32) -> Responses<'_> {
+        match self {
+            Endpoint::Socket(socket) => Responses::Socket(socket, vec![0; 64 * 1024]),
+            Endpoint::Shared(mux, addr) => {
+                Responses::Shared(mux.transaction(transaction_id, *addr))
+            }
+        }
+    }
+}
+
+enum Responses<'a> {
+    Socket(&'a UdpSocket, Vec<u8>),
+    /// Only gets responses with the right transaction id.
+    Shared(Transaction),
+}
+
+impl Responses<'_> {
+    async fn recv(&mut self) -> io::Result<Vec<u8>> {
+        match self {
+            Responses::Socket(socket, buffer) => {
+                let len = socket.recv(buffer).await?;
+                Ok(buffer[..len].to_vec())
+            }
+            Responses::Shared(transaction) => transaction.recv().await,
+        }
+    }
+}
+
+impl UdpTracker {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            timeout: Duration::from_secs(15),
+            max_retries: 8,
+            num_want: None,
+            tracer: Tracer::default(),
+            traffic: Traffic::default(),
+            socket: None,
+            resolved: None,
+            connection: None,
+        }
+    }
+
+    /// Overrides the retransmission schedule, which defaults to BEP 15's 15 seconds doubled up to
+    /// 8 times.
+    pub fn set_timeout(&mut self, timeout: Duration, max_retries: u32) {
+        self.timeout = timeout;
+        self.max_retries = max_retries;
+    }
+
+    /// Asks for up to `num_want` peers per announce, or as many as the tracker likes with `None`.
+    pub fn set_num_want(&mut self, num_want: Option<u32>) {
+        self.num_want = num_want;
+    }
+
+    /// Calls `hook` with every packet sent to and received from the tracker.
+    pub fn set_trace(&mut self, hook: impl Fn(Trace<'_>) + Send + Sync + 'static) {
+        self.tracer = Tracer::new(hook);
+    }
+
+    /// Sends requests from `socket`, e.g. the one a DHT node uses, instead of a new socket per
+    /// announce address.
+    pub fn set_socket(&mut self, socket: UdpMux) {
+        self.socket = Some(socket);
+        self.connection = None;
+    }
+
+    /// Bytes sent to and received from the tracker since the last call, every retransmission
+    /// included, to add to [`Download::record_overhead`].
+    ///
+    /// [`Download::record_overhead`]: crate::download::Download::record_overhead
+    pub fn take_traffic(&self) -> Overhead {
+        self.traffic.take()
+    }
+
+    #[cfg(feature = "http")]
+    pub(super) fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = tracer;
+    }
+
+    pub async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        peer_id: PeerId,
+        port: u16,
+        ip: Option<IpAddr>,
+        stats: TransferStats,
+        event: Option<Event>,
+    ) -> Result<TrackerResponse> {
+        self.connect().await?;
+        let connection = self.connection.as_ref().expect("connected above");
+
+        let transaction_id = rand::thread_rng().gen::<u32>();
+        let mut request = Vec::with_capacity(98);
+        request.extend_from_slice(&connection.id.to_be_bytes());
+        request.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        request.extend_from_slice(&transaction_id.to_be_bytes());
+        request.extend_from_slice(&info_hash);
+        request.extend_from_slice(peer_id.as_bytes());
+        request.extend_from_slice(&stats.downloaded.to_be_bytes());
+        request.extend_from_slice(&stats.left.to_be_bytes());
+        request.extend_from_slice(&stats.uploaded.to_be_bytes());
+        let event = match event {
+            None => 0u32,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        };
+        request.extend_from_slice(&event.to_be_bytes());
+        // Only IPv4 addresses fit. 0 means the sender's.
+        let ip = match ip {
+            Some(IpAddr::V4(ip)) => ip,
+            _ => Ipv4Addr::UNSPECIFIED,
+        };
+        request.extend_from_slice(&ip.octets());
+        request.extend_from_slice(&rand::thread_rng().gen::<u32>().to_be_bytes());
+        // -1 lets the tracker decide.
+        let num_want = self
+            .num_want
+            .map_or(-1, |num_want| num_want.min(i32::MAX as u32) as i32);
+        request.extend_from_slice(&num_want.to_be_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+
+        let ipv6 = connection.addr.is_ipv6();
+        let response = self
+            .exchange(
+                &connection.endpoint,
+                &request,
+                ACTION_ANNOUNCE,
+                transaction_id,
+            )
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                // The connection id may have expired on the tracker's side, or the address may
+                // have gone away.
+                self.connection = None;
+                self.resolved = None;
+                return Err(e);
+            }
+        };
+
+        parse_announce(&response, ipv6)
+    }
+
+    /// Returns a connection with a fresh id, connecting again if needed.
+    async fn connect(&mut self) -> Result<&Connection> {
+        if let Some(connection) = &self.connection {
+            if connection.since.elapsed() < CONNECTION_LIFETIME {
+                return Ok(self.connection.as_ref().expect("checked above"));
+            }
+        }
+
+        let result = match self.resolved {
+            Some(addr) => self.connect_to(addr).await,
+            None => {
+                let addrs = self.resolve().await?;
+                self.race(addrs).await
+            }
+        };
+
+        match result {
+            Ok(connection) => {
+                self.resolved = Some(connection.addr);
+                Ok(self.connection.insert(connection))
+            }
+            Err(e) => {
+                self.resolved = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Resolves the tracker's host, alternating address families starting with IPv6.
+    async fn resolve(&self) -> Result<Vec<SocketAddr>> {
+        let resolve_error = |source| TrackerError::Resolve {
+            host: self.host.clone(),
+            source,
+        };
+
+        let (v6, v4): (Vec<_>, Vec<_>) = lookup_host((self.host.as_str(), self.port))
+            .await
+            .map_err(resolve_error)?
+            .partition(SocketAddr::is_ipv6);
+        if v6.is_empty() && v4.is_empty() {
+            return Err(resolve_error(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no addresses",
+            )));
+        }
+
+        let mut addrs = Vec::with_capacity(v6.len() + v4.len());
+        let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+        loop {
+            match (v6.next(), v4.next()) {
+                (None, None) => break,
+                (a, b) => addrs.extend(a.into_iter().chain(b)),
+            }
+        }
+
+        Ok(addrs)
+    }
+
+    /// Connects to whichever of `addrs` answers first, starting an attempt on the next address
+    /// every [`ATTEMPT_DELAY`] while earlier ones are still waiting.
+    async fn race(&self, addrs: Vec<SocketAddr>) -> Result<Connection> {
+        let mut attempts = addrs
+            .into_iter()
+            .enumerate()
+            .map(|(index, addr)| async move {
+                tokio::time::sleep(ATTEMPT_DELAY * index as u32).await;
+                self.connect_to(addr).await
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut last_error = None;
+        while let Some(result) = attempts.next().await {
+            match result {
+                Ok(connection) => return Ok(connection),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.expect("resolve returns at least one address"))
+    }
+
+    async fn connect_to(&self, addr: SocketAddr) -> Result<Connection> {
+        let endpoint = match &self.socket {
+            Some(mux) => Endpoint::Shared(mux.clone(), addr),
+            None => {
+                let local: IpAddr = match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+                };
+                let socket = UdpSocket::bind((local, 0)).await?;
+                socket.connect(addr).await?;
+                Endpoint::Socket(socket)
+            }
+        };
+
+        let transaction_id = rand::thread_rng().gen::<u32>();
+        let mut request = Vec::with_capacity(16);
+        request.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        request.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        request.extend_from_slice(&transaction_id.to_be_bytes());
+
+        let response = self
+            .exchange(&endpoint, &request, ACTION_CONNECT, transaction_id)
+            .await?;
+        let id = response
+            .get(8..16)
+            .and_then(|id| id.try_into().ok())
+            .map(u64::from_be_bytes)
+            .ok_or_else(|| TrackerError::Malformed("short connect response".into()))?;
+
+        Ok(Connection {
+            addr,
+            endpoint,
+            id,
+            since: Instant::now(),
+        })
+    }
+
+    /// Sends `request` until a response with a matching transaction id arrives, waiting
+    /// `timeout * 2^n` before the nth retransmission.
+    async fn exchange(
+        &self,
+        endpoint: &Endpoint,
+        request: &[u8],
+        action: u32,
+        transaction_id: u32,
+    ) -> Result<Vec<u8>> {
+        let mut responses = endpoint.responses(transaction_id);
+
+        for attempt in 0..=self.max_retries {
+            self.tracer.trace(Trace::Sent(request));
+            endpoint.send(request).await?;
+            self.traffic.sent(request.len());
+            let deadline = tokio::time::Instant::now() + self.timeout * 2u32.pow(attempt);
+
+            loop {
+                let response = match tokio::time::timeout_at(deadline, responses.recv()).await {
+                    Ok(response) => response?,
+                    Err(_) => break,
+                };
+                let response = response.as_slice();
+                self.tracer.trace(Trace::Received(response));
+                self.traffic.received(response.len());
+                if response.len() < 8 || response[4..8] != transaction_id.to_be_bytes() {
+                    continue;
+                }
+
+                let received =
+                    u32::from_be_bytes(response[..4].try_into().expect("length checked"));
+                if received == ACTION_ERROR {
+                    let message = String::from_utf8_lossy(&response[8..]).into_owned();
+                    return Err(TrackerError::Failure {
+                        retry: Retry::from_reason(&message),
+                        reason: message,
+                    });
+                }
+                if received != action {
+                    return Err(TrackerError::Malformed(format!(
+                        "expected action {action}, got {received}"
+                    )));
+                }
+
+                return Ok(response.to_vec());
+            }
+        }
+
+        Err(TrackerError::Timeout)
+    }
+}
+
+fn parse_announce(response: &[u8], ipv6: bool) -> Result<TrackerResponse> {
+    if response.len() < 20 {
+        return Err(TrackerError::Malformed("short announce response".into()));
+    }
+    let field = |offset: usize| {
+        u32::from_be_bytes(
+            response[offset..offset + 4]
+                .try_into()
+                .expect("length checked"),
+        )
+    };
+
+    let entry = if ipv6 { 18 } else { 6 };
+    let entries = &response[20..];
+    if !entries.len().is_multiple_of(entry) {
+        return Err(TrackerError::Malformed(format!(
+            "peer list length {} is not a multiple of {entry}",
+            entries.len()
+        )));
+    }
+
+    let peers = entries
+        .chunks_exact(entry)
+        .map(|x| {
+            let (ip, port) = x.split_at(entry - 2);
+            let ip = match <[u8; 16]>::try_from(ip) {
+                Ok(ip) => IpAddr::from(ip),
+                Err(_) => IpAddr::from(<[u8; 4]>::try_from(ip).expect("6-byte entry")),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+        })
+        .collect();
+
+    Ok(TrackerResponse {
+        interval: Duration::from_secs(field(8).into()),
+        min_interval: None,
+        peers,
+        seeders: Some(field(16).into()),
+        leechers: Some(field(12).into()),
+        external_ip: None,
+    })
+}
@@ -0,0 +1,128 @@
+//! The peer wire protocol's layout (BEP 3 and the Fast extension of BEP 6), for reuse without the
+//! rest of the engine.
+//!
+//! The `put_*` functions append one message, length prefix included, to a caller's [`BytesMut`]
+//! and never allocate beyond growing it. [`PeerMessage`] and [`Handshake`] are built on them and
+//! parse what they write.
+//!
+//! [`PeerMessage`]: crate::peer::PeerMessage
+//! [`Handshake`]: crate::peer::Handshake
+
+use bytes::{BufMut, BytesMut};
+
+/// The protocol string every handshake starts with, after its length.
+pub const PROTOCOL_NAME: &[u8; 19] = b"BitTorrent protocol";
+
+/// Size of a handshake: name length, name, reserved bytes, info hash, and peer id.
+pub const HANDSHAKE_LEN: usize = 1 + PROTOCOL_NAME.len() + 8 + 20 + 20;
+
+/// Where the reserved bytes start in a handshake.
+pub const RESERVED_OFFSET: usize = 1 + PROTOCOL_NAME.len();
+
+/// Where the info hash starts in a handshake.
+pub const INFO_HASH_OFFSET: usize = RESERVED_OFFSET + 8;
+
+/// Where the peer id starts in a handshake.
+pub const PEER_ID_OFFSET: usize = INFO_HASH_OFFSET + 20;
+
+/// Bit of the last reserved byte advertising the Fast extension.
+pub const FAST_EXTENSION: u8 = 0x04;
+
+/// Size of the big-endian length that prefixes every message after the handshake.
+pub const LENGTH_PREFIX: usize = 4;
+
+/// Size of a piece message before its block: prefix, id, index, and begin.
+pub const PIECE_HEADER_LEN: usize = LENGTH_PREFIX + 1 + 8;
+
+/// Message ids, the byte after the length prefix.
+pub mod id {
+    pub const CHOKE: u8 = 0;
+    pub const UNCHOKE: u8 = 1;
+    pub const INTERESTED: u8 = 2;
+    pub const NOT_INTERESTED: u8 = 3;
+    pub const HAVE: u8 = 4;
+    pub const BITFIELD: u8 = 5;
+    pub const REQUEST: u8 = 6;
+    pub const PIECE: u8 = 7;
+    pub const CANCEL: u8 = 8;
+    pub const HAVE_ALL: u8 = 0x0e;
+    pub const HAVE_NONE: u8 = 0x0f;
+    pub const REJECT: u8 = 0x10;
+}
+
+pub fn put_handshake(
+    dst: &mut BytesMut,
+    reserved: [u8; 8],
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) {
+    dst.reserve(HANDSHAKE_LEN);
+    dst.put_u8(PROTOCOL_NAME.len() as u8);
+    dst.put_slice(PROTOCOL_NAME);
+    dst.put_slice(&reserved);
+    dst.put_slice(&info_hash);
+    dst.put_slice(&peer_id);
+}
+
+pub fn put_keep_alive(dst: &mut BytesMut) {
+    dst.put_u32(0);
+}
+
+/// Appends a message that's only an id, like [`id::CHOKE`] or [`id::HAVE_ALL`].
+pub fn put_empty(dst: &mut BytesMut, id: u8) {
+    dst.reserve(LENGTH_PREFIX + 1);
+    dst.put_u32(1);
+    dst.put_u8(id);
+}
+
+pub fn put_have(dst: &mut BytesMut, piece: u32) {
+    dst.reserve(LENGTH_PREFIX + 5);
+    dst.put_u32(5);
+    dst.put_u8(id::HAVE);
+    dst.put_u32(piece);
+}
+
+pub fn put_bitfield(dst: &mut BytesMut, bitfield: &[u8]) {
+    dst.reserve(LENGTH_PREFIX + 1 + bitfield.len());
+    dst.put_u32(1 + bitfield.len() as u32);
+    dst.put_u8(id::BITFIELD);
+    dst.put_slice(bitfield);
+}
+
+/// Appends a request, cancel, or reject, which share their layout.
+pub fn put_block_message(dst: &mut BytesMut, id: u8, piece: u32, begin: u32, length: u32) {
+    dst.reserve(LENGTH_PREFIX + 13);
+    dst.put_u32(13);
+    dst.put_u8(id);
+    dst.put_u32(piece);
+    dst.put_u32(begin);
+    dst.put_u32(length);
+}
+
+pub fn put_request(dst: &mut BytesMut, piece: u32, begin: u32, length: u32) {
+    put_block_message(dst, id::REQUEST, piece, begin, length);
+}
+
+pub fn put_cancel(dst: &mut BytesMut, piece: u32, begin: u32, length: u32) {
+    put_block_message(dst, id::CANCEL, piece, begin, length);
+}
+
+pub fn put_reject(dst: &mut BytesMut, piece: u32, begin: u32, length: u32) {
+    put_block_message(dst, id::REJECT, piece, begin, length);
+}
+
+/// Appends everything of a piece message but the block itself, for callers that write the
+/// `length` bytes of the block straight after, e.g. from disk.
+pub fn put_piece_header(dst: &mut BytesMut, piece: u32, begin: u32, length: u32) {
+    dst.reserve(PIECE_HEADER_LEN);
+    dst.put_u32(9 + length);
+    dst.put_u8(id::PIECE);
+    dst.put_u32(piece);
+    dst.put_u32(begin);
+}
+
+pub fn put_piece(dst: &mut BytesMut, piece: u32, begin: u32, block: &[u8]) {
+    dst.reserve(PIECE_HEADER_LEN + block.len());
+    put_piece_header(dst, piece, begin, block.len() as u32);
+    dst.put_slice(block);
+}